@@ -6,6 +6,29 @@ import 'package:file_picker/file_picker.dart';
 class TranscoderState {
   final Mp3Config _mp3config =
       Mp3Config(bitrate: Mp3Bitrate.Kbps320, quality: Mp3Quality.Best);
+  final OpusConfig _opusConfig = OpusConfig(
+      bitrate: 160, complexity: 10, application: OpusApplication.Audio);
+  final VorbisConfig _vorbisConfig =
+      VorbisConfig(mode: VorbisBitrateMode.QualityVbr, quality: 6, bitrate: 192);
+  final FlacConfig _flacConfig = FlacConfig(compressionLevel: 5);
+  final SyncConfig _syncConfig =
+      SyncConfig(incremental: false, compareContentHash: false);
+  final MirrorConfig _mirrorConfig =
+      MirrorConfig(mode: MirrorMode.MirrorOff, dryRun: true);
+  final FilterConfig _filterConfig =
+      FilterConfig(includeHidden: false, maxDepth: 0);
+  final ArtworkConfig _artworkConfig = ArtworkConfig(
+      selection: ArtworkSelection.ArtworkAll,
+      recompress: false,
+      maxDimension: 1000,
+      jpegQuality: 90,
+      progressive: false,
+      stripExif: true,
+      maxBytes: 0);
+  final List<SourceFormat> _convertFormats = [];
+  ConflictPolicy _conflictPolicy = ConflictPolicy.ConflictOverwrite;
+  String _namingTemplate = "";
+  PassthroughMode _passthroughMode = PassthroughMode.PassthroughCopy;
   TargetFormat _targetFormat = TargetFormat.Mp3;
   int _noOfThreads = Platform.numberOfProcessors;
   bool _copyUnrecognisedFiles = true;
@@ -33,6 +56,177 @@ class TranscoderState {
     _mp3config.quality = quality;
   }
 
+  /// Set the version of the ID3v2 tag written to mp3 files
+  void setMp3Id3Version(Id3Version id3Version) {
+    _mp3config.id3Version = id3Version;
+  }
+
+  /// Set whether mp3 files also get an ID3v1 tag at the end
+  void setMp3WriteId3v1(bool writeId3v1) {
+    _mp3config.writeId3v1 = writeId3v1;
+  }
+
+  /// Set opus output bitrate in kbps
+  void setOpusBitrate(int bitrate) {
+    _opusConfig.bitrate = bitrate;
+  }
+
+  /// Set opus encoder complexity
+  void setOpusComplexity(int complexity) {
+    _opusConfig.complexity = complexity;
+  }
+
+  /// Set opus encoder application
+  void setOpusApplication(OpusApplication application) {
+    _opusConfig.application = application;
+  }
+
+  /// Set vorbis bitrate management mode
+  void setVorbisMode(VorbisBitrateMode mode) {
+    _vorbisConfig.mode = mode;
+  }
+
+  /// Set vorbis quality, from -1 to 10
+  void setVorbisQuality(double quality) {
+    _vorbisConfig.quality = quality;
+  }
+
+  /// Set vorbis nominal bitrate in kbps
+  void setVorbisBitrate(int bitrate) {
+    _vorbisConfig.bitrate = bitrate;
+  }
+
+  /// Set flac compression level, from 0 to 8
+  void setFlacCompressionLevel(int compressionLevel) {
+    _flacConfig.compressionLevel = compressionLevel;
+  }
+
+  /// Sets incremental mode
+  /// If true, files whose output in the destination is up to date are skipped
+  void setIncremental(bool incremental) {
+    _syncConfig.incremental = incremental;
+  }
+
+  /// Sets whether the content of the source files is hashed in incremental mode
+  void setCompareContentHash(bool compareContentHash) {
+    _syncConfig.compareContentHash = compareContentHash;
+  }
+
+  /// Sets what happens to destination files without a source once the conversion finishes
+  void setMirrorMode(MirrorMode mode) {
+    _mirrorConfig.mode = mode;
+  }
+
+  /// Sets mirror dry run
+  /// If true, destination files without a source are only listed
+  void setMirrorDryRun(bool dryRun) {
+    _mirrorConfig.dryRun = dryRun;
+  }
+
+  /// Sets what happens when an output already exists or several files would be written to the same output
+  void setConflictPolicy(ConflictPolicy conflictPolicy) {
+    _conflictPolicy = conflictPolicy;
+  }
+
+  /// Sets the template converted files are named by, like {albumartist}/{album}/{track:02} {title}
+  /// An empty template keeps the paths of the source
+  void setNamingTemplate(String namingTemplate) {
+    _namingTemplate = namingTemplate;
+  }
+
+  /// Sets the glob patterns of the files that are included, like **/*.flac
+  /// Without any pattern every file is included
+  void setIncludeGlobs(List<String> includeGlobs) {
+    _filterConfig.includeGlobs
+      ..clear()
+      ..addAll(includeGlobs);
+  }
+
+  /// Sets the glob patterns of the files that are left out, like **/Scans/** or *.log
+  void setExcludeGlobs(List<String> excludeGlobs) {
+    _filterConfig.excludeGlobs
+      ..clear()
+      ..addAll(excludeGlobs);
+  }
+
+  /// Sets whether hidden files and folders are included
+  void setIncludeHidden(bool includeHidden) {
+    _filterConfig.includeHidden = includeHidden;
+  }
+
+  /// Sets whether linked files and folders in the source are followed
+  void setFollowSymlinks(bool followSymlinks) {
+    _filterConfig.followSymlinks = followSymlinks;
+  }
+
+  /// Sets how many folder levels deep files are taken from, 0 for no limit
+  void setMaxDepth(int maxDepth) {
+    if (maxDepth < 0) {
+      return;
+    }
+    _filterConfig.maxDepth = maxDepth;
+  }
+
+  /// Sets the source formats that are converted, files of every other
+  /// format are treated as unrecognised files
+  /// An empty list converts FLAC, WAV, AIFF and ALAC and copies lossy files
+  void setConvertFormats(List<SourceFormat> convertFormats) {
+    _convertFormats
+      ..clear()
+      ..addAll(convertFormats);
+  }
+
+  /// Sets how unrecognised files are put in the destination
+  /// Links that can not be made fall back to a copy
+  void setPassthroughMode(PassthroughMode passthroughMode) {
+    _passthroughMode = passthroughMode;
+  }
+
+  /// Sets which pictures of the source are embedded in the converted files
+  void setArtworkSelection(ArtworkSelection selection) {
+    _artworkConfig.selection = selection;
+  }
+
+  /// Sets whether pictures are re-encoded as JPEG to fit the limits below
+  void setArtworkRecompress(bool recompress) {
+    _artworkConfig.recompress = recompress;
+  }
+
+  /// Sets the longest side of embedded pictures in pixels, 0 for no limit
+  void setArtworkMaxDimension(int maxDimension) {
+    if (maxDimension < 0) {
+      return;
+    }
+    _artworkConfig.maxDimension = maxDimension;
+  }
+
+  /// Sets the JPEG quality of re-encoded pictures, from 1 to 100
+  void setArtworkJpegQuality(int jpegQuality) {
+    if (jpegQuality < 1 || jpegQuality > 100) {
+      return;
+    }
+    _artworkConfig.jpegQuality = jpegQuality;
+  }
+
+  /// Sets whether re-encoded pictures are progressive instead of baseline
+  /// JPEGs, some car stereos only show baseline ones
+  void setArtworkProgressive(bool progressive) {
+    _artworkConfig.progressive = progressive;
+  }
+
+  /// Sets whether the Exif data of embedded pictures is removed
+  void setArtworkStripExif(bool stripExif) {
+    _artworkConfig.stripExif = stripExif;
+  }
+
+  /// Sets the largest size of an embedded picture in bytes, 0 for no limit
+  void setArtworkMaxBytes(int maxBytes) {
+    if (maxBytes < 0) {
+      return;
+    }
+    _artworkConfig.maxBytes = maxBytes;
+  }
+
   /// Sets the number of threads to be used
   /// Does not allow setting more threads than available in the system hardware
   void setNoOfThreads(int noOfThreads) {
@@ -119,6 +313,62 @@ class TranscoderState {
     return _mp3config;
   }
 
+  /// Returns the current state of the opus configuration
+  OpusConfig getOpusConfig() {
+    return _opusConfig;
+  }
+
+  /// Returns the current state of the vorbis configuration
+  VorbisConfig getVorbisConfig() {
+    return _vorbisConfig;
+  }
+
+  /// Returns the current state of the flac configuration
+  FlacConfig getFlacConfig() {
+    return _flacConfig;
+  }
+
+  /// Returns the current state of the incremental sync configuration
+  SyncConfig getSyncConfig() {
+    return _syncConfig;
+  }
+
+  /// Returns the current state of the mirror configuration
+  MirrorConfig getMirrorConfig() {
+    return _mirrorConfig;
+  }
+
+  /// Returns the current state of the file filter configuration
+  FilterConfig getFilterConfig() {
+    return _filterConfig;
+  }
+
+  /// Returns the current conflict policy
+  ConflictPolicy getConflictPolicy() {
+    return _conflictPolicy;
+  }
+
+  /// Returns the source formats that are converted, empty for the lossless
+  /// formats
+  List<SourceFormat> getConvertFormats() {
+    return List.unmodifiable(_convertFormats);
+  }
+
+  /// Returns the current passthrough mode
+  PassthroughMode getPassthroughMode() {
+    return _passthroughMode;
+  }
+
+  /// Returns the current state of the artwork configuration
+  ArtworkConfig getArtworkConfig() {
+    return _artworkConfig;
+  }
+
+  /// Returns the current naming template
+  String getNamingTemplate() {
+    return _namingTemplate;
+  }
+
   /// Returns the currently selected target format
   TargetFormat getTargetFormat() {
     return _targetFormat;
@@ -126,6 +376,37 @@ class TranscoderState {
 
   // methods
   Future<bool> startConversion() async {
+    await _checkPaths();
+    _getConvertMessage().sendSignalToRust();
+    return true;
+  }
+
+  /// Asks for a plan of the conversion with the current configuration
+  ///
+  /// The plan is answered with a [ConversionPlan] signal, nothing is written
+  Future<bool> planConversion() async {
+    await _checkPaths();
+    PlanConversion(convert: _getConvertMessage()).sendSignalToRust();
+    return true;
+  }
+
+  /// Parks the running conversion, the remaining files are kept
+  void pauseConversion() {
+    Pause().sendSignalToRust();
+  }
+
+  /// Continues a paused conversion where it stopped
+  void resumeConversion() {
+    Resume().sendSignalToRust();
+  }
+
+  /// Continues the unfinished job recorded in the destination, after the app
+  /// was closed or the conversion cancelled
+  void resumeJob() {
+    ResumeJob(destPath: _destPath).sendSignalToRust();
+  }
+
+  Future<void> _checkPaths() async {
     if (_srcPath.isEmpty) {
       throw const FormatException("Source path not set.");
     } else if (!await Directory(_srcPath).exists()) {
@@ -135,14 +416,27 @@ class TranscoderState {
     } else if (!await Directory(_destPath).exists()) {
       throw const FormatException("Destination path is invalid.");
     }
-    Convert(
+  }
+
+  Convert _getConvertMessage() {
+    return Convert(
       copyUnrecognisedFiles: _copyUnrecognisedFiles,
       destPath: _destPath,
       srcPath: _srcPath,
       mp3Config: _mp3config,
+      opusConfig: _opusConfig,
+      vorbisConfig: _vorbisConfig,
+      flacConfig: _flacConfig,
+      syncConfig: _syncConfig,
+      mirrorConfig: _mirrorConfig,
+      conflictPolicy: _conflictPolicy,
+      namingTemplate: _namingTemplate,
+      filterConfig: _filterConfig,
+      passthroughMode: _passthroughMode,
+      convertFormats: _convertFormats,
+      artworkConfig: _artworkConfig,
       targetFormat: _targetFormat,
       noOfThreads: _noOfThreads,
-    ).sendSignalToRust();
-    return true;
+    );
   }
 }
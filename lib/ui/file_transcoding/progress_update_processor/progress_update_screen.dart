@@ -115,8 +115,10 @@ class _ProgressUpdateProcessorState extends State<ProgressUpdateProcessor> {
                   stream: ProgressUpdate.rustSignalStream,
                   builder: (context, snapshot) {
                     if (snapshot.hasData &&
-                        snapshot.data!.message.messageType ==
-                            MessageType.FileFinish) {
+                        (snapshot.data!.message.messageType ==
+                                MessageType.FileFinish ||
+                            snapshot.data!.message.messageType ==
+                                MessageType.Skipped)) {
                       _numberOfFilesFinished++;
                     } else if (snapshot.hasData &&
                         snapshot.data!.message.messageType ==
@@ -27,7 +27,8 @@ class _TranscoderCombinedUpdateProcessorState
             child: Text(
               progressUpdate.msg,
               style: TextStyle(
-                color: (progressUpdate.messageType == MessageType.Fail)
+                color: (progressUpdate.messageType == MessageType.Fail ||
+                        progressUpdate.messageType == MessageType.Warning)
                     ? Theme.of(context).colorScheme.error
                     : Theme.of(context).colorScheme.onTertiaryContainer,
               ),
@@ -86,7 +86,11 @@ class _TranscoderPageState extends State<TranscoderPage> {
             ),
             onChanged: (value) {
               TranscoderState.getInstance().setSource(value);
-              CheckDirectory(src: value).sendSignalToRust();
+              CheckDirectory(
+                      src: value,
+                      filterConfig:
+                          TranscoderState.getInstance().getFilterConfig())
+                  .sendSignalToRust();
             },
           ),
         ),
@@ -99,7 +103,10 @@ class _TranscoderPageState extends State<TranscoderPage> {
                 await TranscoderState.getInstance().setSourcePathViaOS() ??
                     _srcPathController.text;
             print(_srcPathController.text);
-            CheckDirectory(src: _srcPathController.text).sendSignalToRust();
+            CheckDirectory(
+                    src: _srcPathController.text,
+                    filterConfig: TranscoderState.getInstance().getFilterConfig())
+                .sendSignalToRust();
           },
         ),
       ],
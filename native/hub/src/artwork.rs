@@ -0,0 +1,501 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io::Cursor,
+    num::NonZeroU32,
+    ops::Range,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use image::{imageops::FilterType, DynamicImage, ImageReader};
+use jpeg_encoder::{ColorType, Encoder};
+use md5::{Digest, Md5};
+use symphonia::core::meta::{ColorMode, Size, StandardVisualKey, Visual};
+
+use crate::messages::dart_signal::{ArtworkConfig, ArtworkSelection};
+
+// Lowest quality and longest side a picture is squeezed down to while it is
+// above the byte limit.
+const MIN_JPEG_QUALITY: u8 = 40;
+const MIN_DIMENSION: u32 = 200;
+// Quality of the re-encoded pictures when none is set.
+const DEFAULT_JPEG_QUALITY: u8 = 90;
+// Covers kept at once, the files of an album are converted together so only
+// the last few are used again.
+const MAX_CACHED_PICTURES: usize = 32;
+
+/// Pictures processed during a job, by the hash of their content. The tracks
+/// of an album usually carry the same cover, so it is only processed once.
+/// Only the most recently added pictures are kept, so a large job does not
+/// hold every cover it has seen in memory.
+#[derive(Default)]
+pub struct ArtworkCache {
+    pictures: Mutex<CachedPictures>,
+}
+
+#[derive(Default)]
+struct CachedPictures {
+    by_hash: HashMap<[u8; 16], CachedPicture>,
+    // hashes in the order they were added, the oldest is dropped first
+    order: VecDeque<[u8; 16]>,
+}
+
+// Filled in by the first worker that needs the picture, `None` if it is
+// embedded as it is.
+type CachedPicture = Arc<OnceLock<Option<Visual>>>;
+
+impl ArtworkCache {
+    fn get_or_process(&self, visual: &Visual, config: &ArtworkConfig) -> Option<Visual> {
+        let key: [u8; 16] = Md5::digest(&visual.data).into();
+        let picture = {
+            let mut pictures = self
+                .pictures
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            match pictures.by_hash.get(&key) {
+                Some(picture) => Arc::clone(picture),
+                None => {
+                    // workers still processing a dropped picture keep it alive
+                    // through their own handle
+                    if pictures.order.len() >= MAX_CACHED_PICTURES {
+                        if let Some(oldest) = pictures.order.pop_front() {
+                            pictures.by_hash.remove(&oldest);
+                        }
+                    }
+                    let picture = CachedPicture::default();
+                    pictures.by_hash.insert(key, Arc::clone(&picture));
+                    pictures.order.push_back(key);
+                    picture
+                }
+            }
+        };
+        // other pictures are processed meanwhile, only the workers waiting
+        // for this one are held up
+        picture
+            .get_or_init(|| process_picture(visual, config))
+            .clone()
+    }
+}
+
+/// Picks the pictures of a source file that are embedded in its output and
+/// shrinks them the way the config asks for.
+pub fn get_artwork(
+    visuals: &[Visual],
+    config: &ArtworkConfig,
+    cache: &ArtworkCache,
+) -> Vec<Visual> {
+    let mut artwork = select_artwork(visuals, config.selection());
+    if config.recompress {
+        for visual in artwork.iter_mut() {
+            if let Some(processed) = cache.get_or_process(visual, config) {
+                // the same picture may be used differently by another file
+                *visual = Visual {
+                    usage: visual.usage,
+                    tags: visual.tags.clone(),
+                    ..processed
+                };
+            }
+        }
+    }
+    artwork
+}
+
+/// The artwork options that change the output, `None` while every picture
+/// is embedded as it is. Every option is listed by hand, so adding one to
+/// the message does not change the settings of existing outputs.
+pub fn get_artwork_settings(config: &ArtworkConfig) -> Option<String> {
+    match (config.selection(), config.recompress) {
+        (ArtworkSelection::ArtworkAll, false) => None,
+        (_, false) => Some(format!("Artwork selection={}", config.selection)),
+        (_, true) => Some(format!(
+            "Artwork selection={} recompress max_dimension={} jpeg_quality={} \
+             progressive={} strip_exif={} max_bytes={}",
+            config.selection,
+            config.max_dimension,
+            get_jpeg_quality(config),
+            config.progressive,
+            config.strip_exif,
+            config.max_bytes
+        )),
+    }
+}
+
+fn select_artwork(visuals: &[Visual], selection: ArtworkSelection) -> Vec<Visual> {
+    match selection {
+        ArtworkSelection::ArtworkAll => visuals.to_vec(),
+        ArtworkSelection::ArtworkFrontCover => {
+            // containers like MP4 do not say what a picture shows, their
+            // first picture is taken as the cover
+            let front_cover = visuals
+                .iter()
+                .find(|visual| visual.usage == Some(StandardVisualKey::FrontCover))
+                .or_else(|| visuals.iter().find(|visual| visual.usage.is_none()));
+            front_cover.cloned().into_iter().collect()
+        }
+        ArtworkSelection::ArtworkNone => Vec::new(),
+    }
+}
+
+// Returns `None` if the picture is embedded as it is, which is also the case
+// for pictures that can not be read.
+fn process_picture(visual: &Visual, config: &ArtworkConfig) -> Option<Visual> {
+    let segments = get_jpeg_segments(&visual.data);
+    let (width, height) = ImageReader::new(Cursor::new(&visual.data))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()?;
+    let max_dimension = config.max_dimension;
+    let max_bytes = config.max_bytes as usize;
+    let too_large = max_dimension != 0 && width.max(height) > max_dimension;
+    let too_heavy = max_bytes != 0 && visual.data.len() > max_bytes;
+    // a lot of car stereos show nothing but baseline JPEGs
+    let unsupported = match &segments {
+        Some(segments) => {
+            !config.progressive
+                && segments
+                    .iter()
+                    .any(|(marker, _)| matches!(marker, 0xC2 | 0xC6 | 0xCA | 0xCE))
+        }
+        None => true,
+    };
+    if !too_large && !too_heavy && !unsupported {
+        if !config.strip_exif {
+            return None;
+        }
+        let data = strip_exif(&visual.data, &segments?)?;
+        return Some(Visual {
+            data: data.into_boxed_slice(),
+            ..visual.clone()
+        });
+    }
+
+    let mut image = image::load_from_memory(&visual.data).ok()?;
+    if too_large {
+        image = image.resize(max_dimension, max_dimension, FilterType::Lanczos3);
+    }
+    let jpeg_quality = get_jpeg_quality(config);
+    let mut quality = jpeg_quality;
+    loop {
+        let data = encode_jpeg(&image, quality, config.progressive)?;
+        let dimension = image.width().max(image.height());
+        if max_bytes == 0
+            || data.len() <= max_bytes
+            || (quality <= MIN_JPEG_QUALITY && dimension <= MIN_DIMENSION)
+        {
+            return Some(Visual {
+                media_type: "image/jpeg".to_string(),
+                dimensions: Some(Size {
+                    width: image.width(),
+                    height: image.height(),
+                }),
+                bits_per_pixel: NonZeroU32::new(24),
+                color_mode: Some(ColorMode::Discrete),
+                usage: visual.usage,
+                tags: visual.tags.clone(),
+                data: data.into_boxed_slice(),
+            });
+        }
+        // the quality is lowered first, pixels are only given up after that
+        if quality > MIN_JPEG_QUALITY {
+            quality = quality.saturating_sub(10).max(MIN_JPEG_QUALITY);
+        } else {
+            let dimension = (dimension * 3 / 4).max(MIN_DIMENSION);
+            image = image.resize(dimension, dimension, FilterType::Lanczos3);
+            quality = jpeg_quality;
+        }
+    }
+}
+
+// An unset quality is 0 in the message, which is no quality to encode with.
+fn get_jpeg_quality(config: &ArtworkConfig) -> u8 {
+    match config.jpeg_quality {
+        0 => DEFAULT_JPEG_QUALITY,
+        jpeg_quality => jpeg_quality.min(100) as u8,
+    }
+}
+
+fn encode_jpeg(image: &DynamicImage, quality: u8, progressive: bool) -> Option<Vec<u8>> {
+    let rgb = image.to_rgb8();
+    let width = u16::try_from(rgb.width()).ok()?;
+    let height = u16::try_from(rgb.height()).ok()?;
+    let mut data = Vec::new();
+    let mut encoder = Encoder::new(&mut data, quality);
+    encoder.set_progressive(progressive);
+    encoder
+        .encode(rgb.as_raw(), width, height, ColorType::Rgb)
+        .ok()?;
+    Some(data)
+}
+
+// Markers and byte ranges of the segments of a JPEG up to the start of scan,
+// `None` if the data is not a JPEG that can be followed.
+fn get_jpeg_segments(data: &[u8]) -> Option<Vec<(u8, Range<usize>)>> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut segments = Vec::new();
+    let mut position = 2;
+    loop {
+        if *data.get(position)? != 0xFF {
+            return None;
+        }
+        let marker = *data.get(position + 1)?;
+        // fill bytes in front of a marker
+        if marker == 0xFF {
+            position += 1;
+            continue;
+        }
+        let length = u16::from_be_bytes([*data.get(position + 2)?, *data.get(position + 3)?]);
+        let end = position + 2 + length as usize;
+        if length < 2 || end > data.len() {
+            return None;
+        }
+        segments.push((marker, position..end));
+        // the image data follows the start of scan
+        if marker == 0xDA {
+            return Some(segments);
+        }
+        position = end;
+    }
+}
+
+// Drops the APP1 segments with Exif data, `None` if there are none.
+fn strip_exif(data: &[u8], segments: &[(u8, Range<usize>)]) -> Option<Vec<u8>> {
+    if !segments
+        .iter()
+        .any(|segment| is_exif_segment(data, segment))
+    {
+        return None;
+    }
+    let mut stripped = data[..2].to_vec();
+    for segment in segments {
+        if !is_exif_segment(data, segment) {
+            stripped.extend_from_slice(&data[segment.1.clone()]);
+        }
+    }
+    stripped.extend_from_slice(&data[segments.last()?.1.end..]);
+    Some(stripped)
+}
+
+fn is_exif_segment(data: &[u8], (marker, range): &(u8, Range<usize>)) -> bool {
+    *marker == 0xE1 && data[range.start + 4..range.end].starts_with(b"Exif\0\0")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{DynamicImage, ImageFormat, RgbImage};
+    use md5::{Digest, Md5};
+    use symphonia::core::meta::{StandardVisualKey, Visual};
+
+    use super::{
+        encode_jpeg, get_jpeg_segments, process_picture, ArtworkCache, MAX_CACHED_PICTURES,
+        MIN_DIMENSION,
+    };
+    use crate::messages::dart_signal::ArtworkConfig;
+
+    fn get_png_visual(size: u32) -> Visual {
+        let image = RgbImage::from_fn(size, size, |x, y| image::Rgb([x as u8, y as u8, 128]));
+        let mut data = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut data), ImageFormat::Png)
+            .unwrap();
+        Visual {
+            media_type: "image/png".to_string(),
+            dimensions: None,
+            bits_per_pixel: None,
+            color_mode: None,
+            usage: Some(StandardVisualKey::FrontCover),
+            tags: Vec::new(),
+            data: data.into_boxed_slice(),
+        }
+    }
+
+    // Noise does not compress, so the byte limit can only be reached by
+    // giving up quality and pixels.
+    fn get_noisy_jpeg(size: u32, progressive: bool) -> Vec<u8> {
+        let image = RgbImage::from_fn(size, size, |x, y| {
+            let noise = (x.wrapping_mul(2_654_435_761) ^ y.wrapping_mul(2_246_822_519)) >> 8;
+            image::Rgb([noise as u8, (noise >> 8) as u8, (noise >> 16) as u8])
+        });
+        encode_jpeg(&DynamicImage::ImageRgb8(image), 90, progressive).unwrap()
+    }
+
+    fn get_jpeg_visual(data: Vec<u8>) -> Visual {
+        Visual {
+            media_type: "image/jpeg".to_string(),
+            data: data.into_boxed_slice(),
+            ..get_png_visual(1)
+        }
+    }
+
+    fn get_dimensions(visual: &Visual) -> (u32, u32) {
+        let image = image::load_from_memory(&visual.data).unwrap();
+        (image.width(), image.height())
+    }
+
+    fn get_markers(data: &[u8]) -> Vec<u8> {
+        get_jpeg_segments(data)
+            .unwrap()
+            .into_iter()
+            .map(|(marker, _)| marker)
+            .collect()
+    }
+
+    #[test]
+    fn takes_an_unset_quality_as_the_default() {
+        let visual = get_png_visual(64);
+        let config = |jpeg_quality| ArtworkConfig {
+            recompress: true,
+            jpeg_quality,
+            ..Default::default()
+        };
+        let unset = process_picture(&visual, &config(0)).unwrap();
+        let default = process_picture(&visual, &config(90)).unwrap();
+        let lowest = process_picture(&visual, &config(1)).unwrap();
+        assert_eq!(unset.media_type, "image/jpeg");
+        assert_eq!(unset.data, default.data);
+        assert!(unset.data.len() > lowest.data.len());
+    }
+
+    #[test]
+    fn resizes_to_the_max_dimension() {
+        let visual = get_png_visual(256);
+        let config = ArtworkConfig {
+            recompress: true,
+            max_dimension: 100,
+            ..Default::default()
+        };
+        let resized = process_picture(&visual, &config).unwrap();
+        let dimensions = resized.dimensions.unwrap();
+        assert_eq!((dimensions.width, dimensions.height), (100, 100));
+        assert_eq!(get_dimensions(&resized), (100, 100));
+
+        // baseline JPEGs within the limit are embedded as they are
+        let visual = get_jpeg_visual(get_noisy_jpeg(100, false));
+        assert!(process_picture(&visual, &config).is_none());
+    }
+
+    #[test]
+    fn lowers_quality_and_size_until_under_max_bytes() {
+        let data = get_noisy_jpeg(400, false);
+        let max_bytes = data.len() / 4;
+        let visual = get_jpeg_visual(data);
+        let config = ArtworkConfig {
+            recompress: true,
+            max_bytes: max_bytes as u32,
+            ..Default::default()
+        };
+        let processed = process_picture(&visual, &config).unwrap();
+        assert!(processed.data.len() <= max_bytes);
+        assert_eq!(processed.media_type, "image/jpeg");
+    }
+
+    #[test]
+    fn stops_at_the_smallest_picture_when_max_bytes_is_out_of_reach() {
+        let visual = get_jpeg_visual(get_noisy_jpeg(400, false));
+        let config = ArtworkConfig {
+            recompress: true,
+            max_bytes: 1,
+            ..Default::default()
+        };
+        let processed = process_picture(&visual, &config).unwrap();
+        let (width, height) = get_dimensions(&processed);
+        assert_eq!(width.max(height), MIN_DIMENSION);
+        assert!(processed.data.len() > 1);
+    }
+
+    #[test]
+    fn strips_only_exif_segments() {
+        let jpeg = get_noisy_jpeg(16, false);
+        let exif = [
+            &[0xFF, 0xE1, 0x00, 0x0C][..],
+            b"Exif\0\0",
+            &[0x4D, 0x4D, 0x00, 0x2A],
+        ]
+        .concat();
+        let xmp_payload = b"http://ns.adobe.com/xap/1.0/\0<x/>";
+        let xmp = [
+            &[0xFF, 0xE1][..],
+            &(xmp_payload.len() as u16 + 2).to_be_bytes(),
+            xmp_payload,
+        ]
+        .concat();
+        let with_exif = [&jpeg[..2], &exif, &xmp, &jpeg[2..]].concat();
+        let config = ArtworkConfig {
+            recompress: true,
+            strip_exif: true,
+            ..Default::default()
+        };
+
+        let stripped = process_picture(&get_jpeg_visual(with_exif), &config).unwrap();
+        assert_eq!(
+            &stripped.data[..],
+            &[&jpeg[..2], &xmp, &jpeg[2..]].concat()[..]
+        );
+        // nothing to strip, so it is embedded as it is
+        assert!(process_picture(&get_jpeg_visual(jpeg), &config).is_none());
+    }
+
+    #[test]
+    fn re_encodes_progressive_jpegs_as_baseline() {
+        let progressive = get_noisy_jpeg(32, true);
+        assert!(get_markers(&progressive).contains(&0xC2));
+        let visual = get_jpeg_visual(progressive);
+        let config = ArtworkConfig {
+            recompress: true,
+            ..Default::default()
+        };
+
+        let baseline = process_picture(&visual, &config).unwrap();
+        let markers = get_markers(&baseline.data);
+        assert!(markers.contains(&0xC0));
+        assert!(!markers.contains(&0xC2));
+        // progressive pictures are kept when they are allowed
+        let config = ArtworkConfig {
+            progressive: true,
+            ..config
+        };
+        assert!(process_picture(&visual, &config).is_none());
+    }
+
+    #[test]
+    fn processes_a_repeated_cover_once() {
+        let cache = ArtworkCache::default();
+        let visual = get_png_visual(64);
+        let config = |max_dimension| ArtworkConfig {
+            recompress: true,
+            max_dimension,
+            ..Default::default()
+        };
+        let first = cache.get_or_process(&visual, &config(32)).unwrap();
+        // a second processing would resize to the new limit
+        let second = cache.get_or_process(&visual, &config(16)).unwrap();
+        assert_eq!(first.data, second.data);
+        assert_eq!(get_dimensions(&second), (32, 32));
+        assert_eq!(cache.pictures.lock().unwrap().by_hash.len(), 1);
+    }
+
+    #[test]
+    fn keeps_only_the_latest_pictures() {
+        let cache = ArtworkCache::default();
+        let config = ArtworkConfig {
+            recompress: true,
+            ..Default::default()
+        };
+        // pictures that can not be read are cached as embedded as they are
+        for i in 0..=MAX_CACHED_PICTURES {
+            let visual = Visual {
+                data: (i as u32).to_be_bytes().into(),
+                ..get_png_visual(1)
+            };
+            assert!(cache.get_or_process(&visual, &config).is_none());
+        }
+        let pictures = cache.pictures.lock().unwrap();
+        assert_eq!(pictures.by_hash.len(), MAX_CACHED_PICTURES);
+        assert_eq!(pictures.order.len(), MAX_CACHED_PICTURES);
+        let first_key: [u8; 16] = Md5::digest(0u32.to_be_bytes()).into();
+        assert!(!pictures.by_hash.contains_key(&first_key));
+    }
+}
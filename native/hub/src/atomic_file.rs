@@ -0,0 +1,190 @@
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+const TEMP_FILE_SUFFIX: &str = ".diraudio-tmp";
+
+/// File that is written next to its final path and only moved into place
+/// once all of it is on disk, so a crash or a full disk never leaves a
+/// truncated output behind. A file that is dropped without being committed
+/// is removed.
+pub struct AtomicFile {
+    path: PathBuf,
+    // closed before the temporary file is removed, which Windows needs
+    writer: BufWriter<File>,
+    temp_file: TempFile,
+}
+
+// Removes the temporary file when dropped, unless it was moved into place.
+struct TempFile {
+    path: PathBuf,
+    committed: bool,
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+impl AtomicFile {
+    pub fn create(path: &Path) -> Result<AtomicFile, String> {
+        if let Some(parent) = path.parent() {
+            if let Err(err) = fs::create_dir_all(parent) {
+                return Err(err.to_string());
+            }
+        }
+        let temp_path = get_temp_path(path);
+        let file = match File::create(&temp_path) {
+            Ok(file) => file,
+            Err(err) => return Err(err.to_string()),
+        };
+        Ok(AtomicFile {
+            path: path.to_path_buf(),
+            writer: BufWriter::new(file),
+            temp_file: TempFile {
+                path: temp_path,
+                committed: false,
+            },
+        })
+    }
+
+    /// Flushes the file to disk and renames it to its final path.
+    pub fn commit(mut self) -> Result<(), String> {
+        let file = match self.writer.into_inner() {
+            Ok(file) => file,
+            Err(err) => return Err(err.error().to_string()),
+        };
+        if let Err(err) = file.sync_all() {
+            return Err(err.to_string());
+        }
+        drop(file);
+        if let Err(err) = fs::rename(&self.temp_file.path, &self.path) {
+            return Err(err.to_string());
+        }
+        self.temp_file.committed = true;
+        sync_parent_dir(&self.path);
+        Ok(())
+    }
+
+    /// Removes everything written so far, the final path is left untouched.
+    pub fn discard(self) {
+        drop(self);
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// Formats with their sizes in the header have them filled in once the end of
+// the audio is written.
+impl Seek for AtomicFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.writer.seek(pos)
+    }
+}
+
+/// Writes `contents` to `path` through a temporary sibling file.
+pub fn write_atomically(path: &Path, contents: &[u8]) -> Result<(), String> {
+    let mut file = AtomicFile::create(path)?;
+    if let Err(err) = file.write_all(contents) {
+        file.discard();
+        return Err(err.to_string());
+    }
+    file.commit()
+}
+
+/// Copies the file at `src_path` to `path` through a temporary sibling file.
+pub fn copy_atomically(src_path: &Path, path: &Path) -> Result<(), String> {
+    let mut src_file = match File::open(src_path) {
+        Ok(file) => file,
+        Err(err) => return Err(err.to_string()),
+    };
+    let mut file = AtomicFile::create(path)?;
+    if let Err(err) = io::copy(&mut src_file, &mut file) {
+        file.discard();
+        return Err(err.to_string());
+    }
+    file.commit()
+}
+
+/// Flushes the directory entry of a file that was just renamed into place,
+/// without it the rename can still be lost when the power goes out.
+#[cfg(unix)]
+pub fn sync_parent_dir(path: &Path) {
+    // not every file system can sync a directory, the file itself is safe
+    // either way
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        if let Ok(dir) = File::open(parent) {
+            let _ = dir.sync_all();
+        }
+    }
+}
+
+// Windows can not open a directory as a file, renames there are flushed with
+// the file system journal.
+#[cfg(not(unix))]
+pub fn sync_parent_dir(_path: &Path) {}
+
+/// Hidden sibling of `path` that its contents are written to first.
+pub fn get_temp_path(path: &Path) -> PathBuf {
+    let mut file_name = OsString::from(".");
+    file_name.push(path.file_name().unwrap_or_default());
+    file_name.push(TEMP_FILE_SUFFIX);
+    path.with_file_name(file_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Write, path::PathBuf};
+
+    use super::{get_temp_path, AtomicFile};
+
+    fn get_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("diraudio-atomic-file-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("output.mp3")
+    }
+
+    #[test]
+    fn moves_a_committed_file_into_place() {
+        let path = get_path("commit");
+        let mut file = AtomicFile::create(&path).unwrap();
+        file.write_all(b"audio").unwrap();
+        assert!(!path.exists());
+        file.commit().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"audio");
+        assert!(!get_temp_path(&path).exists());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn removes_a_file_that_is_dropped() {
+        let path = get_path("drop");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, b"old").unwrap();
+        {
+            let mut file = AtomicFile::create(&path).unwrap();
+            file.write_all(b"half").unwrap();
+            assert!(get_temp_path(&path).exists());
+        }
+        assert!(!get_temp_path(&path).exists());
+        assert_eq!(fs::read(&path).unwrap(), b"old");
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
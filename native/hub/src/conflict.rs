@@ -0,0 +1,277 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::{
+    conversion_handler::{get_expected_output, ConversionInstructions},
+    messages::dart_signal::ConflictPolicy,
+};
+
+/// What happens to a file of the job whose output is the same as the
+/// output of another file of the job.
+#[derive(Clone)]
+pub enum Collision {
+    /// Written to another path so both outputs are kept.
+    Renamed(PathBuf),
+    /// Not written, the output of another file takes its place.
+    LeftOut(String),
+    /// Not written and reported as failed.
+    Failed(String),
+}
+
+/// Finds the files of the job that would be written to the same output,
+/// like `song.flac` and `song.wav` both turning into `song.mp3`, and settles
+/// them according to the conflict policy before anything is written.
+pub fn find_collisions(
+    instruction: &ConversionInstructions,
+    files: &[PathBuf],
+) -> HashMap<PathBuf, Collision> {
+    // outputs are grouped by their case folded path, FAT32 and exFAT do not
+    // tell `Song.mp3` and `song.mp3` apart
+    let mut outputs: HashMap<PathBuf, Vec<(PathBuf, PathBuf)>> = HashMap::new();
+    for file_path in files {
+        if let Some(output_path) = get_expected_output(instruction, file_path) {
+            outputs
+                .entry(get_folded_path(&output_path))
+                .or_default()
+                .push((file_path.clone(), output_path));
+        }
+    }
+    let mut taken: HashSet<PathBuf> = outputs.keys().cloned().collect();
+    let mut collided: Vec<(PathBuf, Vec<(PathBuf, PathBuf)>)> = outputs
+        .into_iter()
+        .filter(|(_, file_paths)| file_paths.len() > 1)
+        .collect();
+    // sorted so that a resumed job settles them the same way
+    collided.sort();
+
+    let policy = instruction.conflict_policy;
+    let mut collisions = HashMap::new();
+    for (_, mut file_paths) in collided {
+        file_paths.sort();
+        let kept = match policy {
+            // the last file is written over the others
+            ConflictPolicy::ConflictOverwrite => file_paths.len() - 1,
+            ConflictPolicy::ConflictKeepNewer => {
+                let mut newest = 0;
+                for (index, (file_path, _)) in file_paths.iter().enumerate() {
+                    let src_path = Path::new(&instruction.src_path);
+                    if get_modified(&src_path.join(file_path))
+                        > get_modified(&src_path.join(&file_paths[newest].0))
+                    {
+                        newest = index;
+                    }
+                }
+                newest
+            }
+            _ => 0,
+        };
+        let (kept_file_path, kept_output_path) = &file_paths[kept];
+        for (index, (file_path, output_path)) in file_paths.iter().enumerate() {
+            let collision = match policy {
+                ConflictPolicy::ConflictFail => Collision::Failed(format!(
+                    "{} can not be written, {} files would be written to {}",
+                    file_path.display(),
+                    file_paths.len(),
+                    output_path.display()
+                )),
+                _ if index == kept => continue,
+                ConflictPolicy::ConflictRename => {
+                    // files already in the destination are left to the
+                    // workers, so a resumed job picks the same names
+                    let renamed_path =
+                        get_free_path(output_path, |path| taken.contains(&get_folded_path(path)));
+                    taken.insert(get_folded_path(&renamed_path));
+                    Collision::Renamed(renamed_path)
+                }
+                _ => Collision::LeftOut(format!(
+                    "Skipped {}, {} is written to {} instead",
+                    file_path.display(),
+                    kept_file_path.display(),
+                    kept_output_path.display()
+                )),
+            };
+            collisions.insert(file_path.clone(), collision);
+        }
+    }
+    collisions
+}
+
+/// Applies the conflict policy to an output that is already in the
+/// destination. Returns the path to write to, `None` if the file is skipped.
+pub fn resolve_existing_output(
+    policy: ConflictPolicy,
+    src_path: &Path,
+    output_path: PathBuf,
+) -> Result<Option<PathBuf>, String> {
+    if !output_path.exists() {
+        return Ok(Some(output_path));
+    }
+    match policy {
+        ConflictPolicy::ConflictOverwrite => Ok(Some(output_path)),
+        ConflictPolicy::ConflictSkip => Ok(None),
+        ConflictPolicy::ConflictKeepNewer => {
+            match (get_modified(src_path), get_modified(&output_path)) {
+                (Some(src_modified), Some(output_modified)) if output_modified >= src_modified => {
+                    Ok(None)
+                }
+                _ => Ok(Some(output_path)),
+            }
+        }
+        ConflictPolicy::ConflictRename => {
+            Ok(Some(get_free_path(&output_path, |path| path.exists())))
+        }
+        ConflictPolicy::ConflictFail => Err(format!("{} already exists", output_path.display())),
+    }
+}
+
+// `song.mp3` becomes `song (1).mp3`, `song (2).mp3` and so on.
+fn get_free_path(path: &Path, is_taken: impl Fn(&Path) -> bool) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default();
+    let mut number = 1;
+    loop {
+        let mut file_name = OsString::from(stem);
+        file_name.push(format!(" ({})", number));
+        if let Some(extension) = path.extension() {
+            file_name.push(".");
+            file_name.push(extension);
+        }
+        let free_path = path.with_file_name(file_name);
+        if !is_taken(&free_path) {
+            return free_path;
+        }
+        number += 1;
+    }
+}
+
+fn get_folded_path(path: &Path) -> PathBuf {
+    PathBuf::from(path.to_string_lossy().to_lowercase())
+}
+
+fn get_modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::dart_signal::{Convert, TargetFormat};
+
+    fn get_instructions(policy: ConflictPolicy) -> ConversionInstructions {
+        let mut message = Convert {
+            src_path: "/src".to_string(),
+            dest_path: "/dest".to_string(),
+            ..Default::default()
+        };
+        message.set_target_format(TargetFormat::Mp3);
+        message.set_conflict_policy(policy);
+        crate::get_conversion_instructions(&message)
+    }
+
+    fn get_files() -> Vec<PathBuf> {
+        vec![
+            PathBuf::from("a/song.flac"),
+            PathBuf::from("a/song.aiff"),
+            PathBuf::from("a/other.flac"),
+        ]
+    }
+
+    #[test]
+    fn numbers_the_first_free_path() {
+        let taken = [
+            PathBuf::from("/dest/song (1).mp3"),
+            PathBuf::from("/dest/song (2).mp3"),
+        ];
+        let free_path = get_free_path(Path::new("/dest/song.mp3"), |path| {
+            taken.iter().any(|taken_path| taken_path == path)
+        });
+        assert_eq!(free_path, PathBuf::from("/dest/song (3).mp3"));
+        let free_path = get_free_path(Path::new("/dest/cover"), |_| false);
+        assert_eq!(free_path, PathBuf::from("/dest/cover (1)"));
+    }
+
+    #[test]
+    fn renames_the_files_written_to_the_same_output() {
+        let mut files = get_files();
+        files.push(PathBuf::from("a/song (1).flac"));
+        let collisions = find_collisions(&get_instructions(ConflictPolicy::ConflictRename), &files);
+        assert_eq!(collisions.len(), 1);
+        match collisions.get(Path::new("a/song.flac")) {
+            // `song (1).mp3` is the output of `song (1).flac`
+            Some(Collision::Renamed(path)) => {
+                assert_eq!(path, &PathBuf::from("/dest/a/song (2).mp3"))
+            }
+            _ => panic!("song.flac is not renamed"),
+        }
+    }
+
+    #[test]
+    fn keeps_one_file_when_skipping_or_overwriting() {
+        let collisions = find_collisions(
+            &get_instructions(ConflictPolicy::ConflictSkip),
+            &get_files(),
+        );
+        assert_eq!(collisions.len(), 1);
+        assert!(matches!(
+            collisions.get(Path::new("a/song.flac")),
+            Some(Collision::LeftOut(_))
+        ));
+
+        let collisions = find_collisions(
+            &get_instructions(ConflictPolicy::ConflictOverwrite),
+            &get_files(),
+        );
+        assert_eq!(collisions.len(), 1);
+        assert!(matches!(
+            collisions.get(Path::new("a/song.aiff")),
+            Some(Collision::LeftOut(_))
+        ));
+    }
+
+    #[test]
+    fn fails_every_file_written_to_the_same_output() {
+        let collisions = find_collisions(
+            &get_instructions(ConflictPolicy::ConflictFail),
+            &get_files(),
+        );
+        assert_eq!(collisions.len(), 2);
+        for file_path in ["a/song.flac", "a/song.aiff"] {
+            assert!(matches!(
+                collisions.get(Path::new(file_path)),
+                Some(Collision::Failed(_))
+            ));
+        }
+        assert!(!collisions.contains_key(Path::new("a/other.flac")));
+    }
+
+    #[test]
+    fn treats_outputs_differing_in_case_as_the_same() {
+        let files = vec![
+            PathBuf::from("Beatles/Song.flac"),
+            PathBuf::from("beatles/song.flac"),
+            PathBuf::from("beatles/song (1).flac"),
+        ];
+        let collisions = find_collisions(&get_instructions(ConflictPolicy::ConflictSkip), &files);
+        assert_eq!(collisions.len(), 1);
+        assert!(matches!(
+            collisions.get(Path::new("beatles/song.flac")),
+            Some(Collision::LeftOut(_))
+        ));
+
+        let collisions = find_collisions(&get_instructions(ConflictPolicy::ConflictRename), &files);
+        assert_eq!(collisions.len(), 1);
+        match collisions.get(Path::new("beatles/song.flac")) {
+            // `song (1).mp3` is taken whatever its case
+            Some(Collision::Renamed(path)) => {
+                assert_eq!(path, &PathBuf::from("/dest/beatles/song (2).mp3"))
+            }
+            _ => panic!("song.flac is not renamed"),
+        }
+    }
+}
@@ -1,18 +1,47 @@
 use crate::{
+    artwork::{get_artwork, get_artwork_settings, ArtworkCache},
+    atomic_file::{get_temp_path, AtomicFile},
+    cancellation::CancellationToken,
+    conflict::{find_collisions, resolve_existing_output, Collision},
     encoder_decoder::{
-        encoders::mp3::mp3::Mp3Encoder, raw_audio_data::raw_audio_data::RawAudioData,
+        audio_stream::audio_stream::AudioStream,
+        encoders::{
+            flac::flac::FlacStreamEncoder,
+            mp3::mp3::Mp3StreamEncoder,
+            opus::opus::OpusStreamEncoder,
+            pcm::{aiff::AiffStreamEncoder, wav::WavStreamEncoder},
+            vorbis::vorbis::VorbisStreamEncoder,
+        },
+        source_format::source_format::get_source_format,
     },
+    journal::{FileState, Journal, JournalEntry},
     messages::{
-        dart_signal::{Mp3Config, TargetFormat},
+        dart_signal::{
+            ArtworkConfig, ConflictPolicy, Convert, FilterConfig, FlacConfig, MirrorConfig,
+            Mp3Config, OpusConfig, PassthroughMode, SourceFormat, SyncConfig, TargetFormat,
+            VorbisConfig,
+        },
         rust_signal::{MessageType, ProgressUpdate, TotalNumberOfFilesFound},
     },
+    mirror::prune_destination,
+    naming::get_templated_paths,
+    passthrough::{get_passthrough_name, place_passthrough_file},
     progress_report_buffer::{handle_buffer, ProgressBuffer},
+    sync_state::{get_content_hash, SourceState, SyncState},
+    walk_filter::WalkFilter,
     AppState,
 };
 
 use rinf::debug_print;
-use std::{fs, path::Path, sync::Arc, usize};
-use tokio::sync::Mutex;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, ReadDir},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+    usize,
+};
+use tokio::{sync::Mutex, time::sleep};
 
 #[derive(Clone)]
 pub struct ConversionInstructions {
@@ -22,6 +51,64 @@ pub struct ConversionInstructions {
     pub(crate) no_of_threads: i32,
     pub(crate) target_format: TargetFormat,
     pub(crate) mp3_config: Mp3Config,
+    pub(crate) opus_config: OpusConfig,
+    pub(crate) vorbis_config: VorbisConfig,
+    pub(crate) flac_config: FlacConfig,
+    pub(crate) convert_formats: Vec<SourceFormat>,
+    pub(crate) sync_config: SyncConfig,
+    pub(crate) mirror_config: MirrorConfig,
+    pub(crate) conflict_policy: ConflictPolicy,
+    pub(crate) naming_template: String,
+    pub(crate) filter_config: FilterConfig,
+    pub(crate) passthrough_mode: PassthroughMode,
+    pub(crate) artwork_config: ArtworkConfig,
+    // filled in once the whole job is known
+    pub(crate) file_actions: Arc<HashMap<PathBuf, FileAction>>,
+    pub(crate) collisions: Arc<HashMap<PathBuf, Collision>>,
+    pub(crate) templated_paths: Arc<HashMap<PathBuf, PathBuf>>,
+    pub(crate) artwork_cache: Arc<ArtworkCache>,
+    pub(crate) cancel_token: CancellationToken,
+}
+
+impl ConversionInstructions {
+    /// Whether `file_path` is converted or copied, only probed when the job
+    /// is not prepared yet.
+    pub(crate) fn get_file_action(&self, file_path: &Path) -> FileAction {
+        match self.file_actions.get(file_path) {
+            Some(file_action) => *file_action,
+            None => decide_file_action(
+                &Path::new(&self.src_path).join(file_path),
+                &self.convert_formats,
+            ),
+        }
+    }
+
+    /// The signal these instructions were made from, as kept in the journal.
+    pub(crate) fn to_convert_message(&self) -> Convert {
+        Convert {
+            src_path: self.src_path.clone(),
+            dest_path: self.dest_path.clone(),
+            no_of_threads: self.no_of_threads,
+            copy_unrecognised_files: self.copy_unrecognised_files,
+            target_format: self.target_format.into(),
+            mp3_config: Some(self.mp3_config.clone()),
+            opus_config: Some(self.opus_config.clone()),
+            vorbis_config: Some(self.vorbis_config.clone()),
+            flac_config: Some(self.flac_config.clone()),
+            convert_formats: self
+                .convert_formats
+                .iter()
+                .map(|source_format| (*source_format).into())
+                .collect(),
+            sync_config: Some(self.sync_config.clone()),
+            mirror_config: Some(self.mirror_config.clone()),
+            conflict_policy: self.conflict_policy.into(),
+            naming_template: self.naming_template.clone(),
+            filter_config: Some(self.filter_config.clone()),
+            passthrough_mode: self.passthrough_mode.into(),
+            artwork_config: Some(self.artwork_config.clone()),
+        }
+    }
 }
 
 pub(crate) async fn handle_conversion(
@@ -30,20 +117,110 @@ pub(crate) async fn handle_conversion(
 ) {
     // Traverse the source directory and make a list of all the files
     // Get all the files in the source directory
-    let mut files: Vec<String> = Vec::new();
-    let src_path = conversion_details.src_path.clone();
-    let files = traverse_directory(&src_path, &mut files, src_path.len()).unwrap();
+    let walk = match WalkFilter::new_from_config(&conversion_details.filter_config)
+        .and_then(|filter| traverse_directory(Path::new(&conversion_details.src_path), &filter))
+    {
+        Ok(walk) => walk,
+        Err(err) => {
+            debug_print!("Failed to read the source: {}", err);
+            TotalNumberOfFilesFound {
+                files_found: false,
+                number: 0,
+            }
+            .send_signal_to_dart();
+            return;
+        }
+    };
+    let entries = walk
+        .files
+        .into_iter()
+        .map(|file_path| JournalEntry {
+            file_path,
+            state: FileState::Pending,
+            checksum: None,
+        })
+        .collect();
+    run_conversion(conversion_details, entries, walk.warnings, app_state).await;
+}
+
+/// Continues a job from the files of its journal.
+pub(crate) async fn resume_conversion(
+    conversion_details: ConversionInstructions,
+    entries: Vec<JournalEntry>,
+    app_state: Arc<Mutex<AppState>>,
+) {
+    run_conversion(conversion_details, entries, Vec::new(), app_state).await;
+}
+
+async fn run_conversion(
+    conversion_details: ConversionInstructions,
+    entries: Vec<JournalEntry>,
+    mut warnings: Vec<String>,
+    app_state: Arc<Mutex<AppState>>,
+) {
+    // files that were being written when a resumed job stopped are
+    // converted again
+    let no_of_files = entries
+        .iter()
+        .filter(|entry| matches!(entry.state, FileState::Pending | FileState::InProgress))
+        .count();
     TotalNumberOfFilesFound {
-        number: files.len() as i32,
+        number: no_of_files as i32,
         files_found: true,
     }
     .send_signal_to_dart();
+    // files that would overwrite each other are settled before any worker
+    // starts
+    let (conversion_details, entries) =
+        match prepare_job_in_background(conversion_details, entries).await {
+            Some(prepared) => prepared,
+            None => return,
+        };
+    let files: Vec<PathBuf> = entries
+        .iter()
+        .filter(|entry| entry.state == FileState::Pending)
+        .map(|entry| entry.file_path.clone())
+        .collect();
+    // mirroring and collisions need every file of the job, also the ones
+    // finished before it was resumed
+    let source_files: Vec<PathBuf> = entries
+        .iter()
+        .map(|entry| entry.file_path.clone())
+        .collect();
+    warnings.append(&mut get_sync_warnings(&conversion_details, &files));
     let files = Arc::new(Mutex::new(files));
+    let written_outputs = Arc::new(Mutex::new(HashSet::new()));
 
     let update_buffer = ProgressBuffer::new();
     let update_buffer = Arc::new(Mutex::new(update_buffer));
     let update_buffer_clone = Arc::clone(&update_buffer);
     tokio::spawn(handle_buffer(update_buffer_clone));
+    for warning in warnings {
+        let mut update_buffer_lock = update_buffer.lock().await;
+        update_buffer_lock.add(ProgressUpdate {
+            msg: warning,
+            handling_thread: 0,
+            message_type: MessageType::Warning.into(),
+        });
+    }
+
+    let sync_state = SyncState::load(&conversion_details.dest_path);
+    let sync_state = Arc::new(Mutex::new(sync_state));
+
+    // the job still runs without a journal, it just can not be resumed
+    let journal = match Journal::create(&conversion_details.to_convert_message(), &entries) {
+        Ok(journal) => Some(journal),
+        Err(err) => {
+            let mut update_buffer_lock = update_buffer.lock().await;
+            update_buffer_lock.add(ProgressUpdate {
+                msg: format!("Failed to create the job journal: {}", err),
+                handling_thread: 0,
+                message_type: MessageType::Fail.into(),
+            });
+            None
+        }
+    };
+    let journal = Arc::new(Mutex::new(journal));
 
     let mut handles = Vec::new();
 
@@ -52,6 +229,9 @@ pub(crate) async fn handle_conversion(
         let files_clone = Arc::clone(&files);
         let app_state_clone = Arc::clone(&app_state);
         let update_buffer_clone = Arc::clone(&update_buffer);
+        let sync_state_clone = Arc::clone(&sync_state);
+        let journal_clone = Arc::clone(&journal);
+        let written_outputs_clone = Arc::clone(&written_outputs);
         let handle = tokio::task::spawn_blocking(move || {
             tokio::runtime::Handle::current().block_on(async {
                 process_files_till_empty(
@@ -60,6 +240,9 @@ pub(crate) async fn handle_conversion(
                     files_clone,
                     app_state_clone,
                     update_buffer_clone,
+                    sync_state_clone,
+                    journal_clone,
+                    written_outputs_clone,
                 )
                 .await
             });
@@ -74,7 +257,60 @@ pub(crate) async fn handle_conversion(
         }
     }
 
+    // a cancelled conversion leaves the destination as it is
+    let cancelled = match *app_state.lock().await {
+        AppState::DoNothing => true,
+        _ => conversion_details.cancel_token.is_cancelled(),
+    };
+    if !cancelled {
+        prune_destination(
+            &conversion_details,
+            &source_files,
+            &*written_outputs.lock().await,
+            Arc::clone(&update_buffer),
+        )
+        .await;
+    }
+
     let mut update_buffer_lock = update_buffer.lock().await;
+    // a cancelled job keeps its journal so it can be resumed
+    if !cancelled {
+        if let Some(journal) = journal.lock().await.take() {
+            if let Err(err) = journal.remove() {
+                update_buffer_lock.add(ProgressUpdate {
+                    msg: format!("Failed to remove the job journal: {}", err),
+                    handling_thread: 0,
+                    message_type: MessageType::Fail.into(),
+                });
+            }
+        }
+    }
+    if cancelled {
+        let mut unconverted_files = files.lock().await.clone();
+        unconverted_files.sort();
+        let unconverted_files: Vec<String> = unconverted_files
+            .iter()
+            .map(|file_path| file_path.display().to_string())
+            .collect();
+        update_buffer_lock.add(ProgressUpdate {
+            msg: format!(
+                "Conversion cancelled, {} files were left unconverted:\n{}",
+                unconverted_files.len(),
+                unconverted_files.join("\n")
+            ),
+            handling_thread: 0,
+            message_type: MessageType::Cancelled.into(),
+        });
+    }
+    if conversion_details.sync_config.incremental {
+        if let Err(err) = sync_state.lock().await.save() {
+            update_buffer_lock.add(ProgressUpdate {
+                msg: format!("Failed to save the sync state: {}", err),
+                handling_thread: 0,
+                message_type: MessageType::Fail.into(),
+            });
+        }
+    }
     update_buffer_lock.add(ProgressUpdate {
         msg: "Conversion Finished".to_string(),
         handling_thread: 0,
@@ -82,57 +318,251 @@ pub(crate) async fn handle_conversion(
     })
 }
 
-pub fn traverse_directory(
-    src: &String,
-    list_of_files: &mut Vec<String>,
-    src_path_length: usize,
-) -> Result<Vec<String>, String> {
-    let paths = match fs::read_dir(src) {
-        Ok(paths) => paths,
+/// Files found below a folder, as paths relative to it, along with every
+/// entry that could not be walked.
+pub struct DirectoryWalk {
+    pub files: Vec<PathBuf>,
+    pub warnings: Vec<String>,
+}
+
+/// Walks the folder at `root`. Only a root that can not be read fails the
+/// walk, entries below it that can not be read are left out with a warning.
+pub fn traverse_directory(root: &Path, filter: &WalkFilter) -> Result<DirectoryWalk, String> {
+    let entries = match fs::read_dir(root) {
+        Ok(entries) => entries,
         Err(err) => {
             return Err(err.to_string());
         }
     };
-    for path in paths {
-        let entry = path.unwrap();
-        let metadata = entry.metadata().unwrap();
-        if metadata.is_dir() {
-            match traverse_directory(
-                &entry.path().to_str().unwrap().to_owned(),
-                list_of_files,
-                src_path_length,
-            ) {
-                Ok(_) => {}
+    let mut walk = DirectoryWalk {
+        files: Vec::new(),
+        warnings: Vec::new(),
+    };
+    // folders already walked, so a link back up the tree is not walked
+    // forever
+    let mut visited = HashSet::new();
+    if let Ok(root) = fs::canonicalize(root) {
+        visited.insert(root);
+    }
+    walk_directory(entries, Path::new(""), filter, &mut visited, &mut walk);
+    Ok(walk)
+}
+
+fn walk_directory(
+    entries: ReadDir,
+    relative_dir: &Path,
+    filter: &WalkFilter,
+    visited: &mut HashSet<PathBuf>,
+    walk: &mut DirectoryWalk,
+) {
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                walk.warnings.push(format!(
+                    "Skipped an entry of the folder {}: {}",
+                    relative_dir.display(),
+                    err
+                ));
+                continue;
+            }
+        };
+        let relative_path = relative_dir.join(entry.file_name());
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                walk.warnings
+                    .push(format!("Skipped {}: {}", relative_path.display(), err));
+                continue;
+            }
+        };
+        let metadata = match metadata.file_type().is_symlink() {
+            false => metadata,
+            true if filter.follows_symlinks() => match fs::metadata(entry.path()) {
+                Ok(metadata) => metadata,
                 Err(err) => {
-                    return Err(err);
+                    walk.warnings.push(format!(
+                        "Skipped the link {}: {}",
+                        relative_path.display(),
+                        err
+                    ));
+                    continue;
                 }
-            };
-        } else if metadata.is_file() {
+            },
+            true if filter.lists_symlinks() => {
+                walk.files.push(relative_path);
+                continue;
+            }
+            true => continue,
+        };
+
+        if metadata.is_dir() {
+            let depth = relative_path.components().count();
+            if !filter.enters_directory(&relative_path, &metadata, depth) {
+                continue;
+            }
             let path = entry.path();
-            let path = path.to_str().unwrap().chars();
-            let path: String = path.skip(src_path_length).collect();
-            list_of_files.push(path);
+            match fs::canonicalize(&path) {
+                Ok(canonical_path) => {
+                    if !visited.insert(canonical_path) {
+                        walk.warnings.push(format!(
+                            "Skipped {}, it links to a folder that is already walked",
+                            relative_path.display()
+                        ));
+                        continue;
+                    }
+                }
+                Err(err) => {
+                    walk.warnings
+                        .push(format!("Skipped {}: {}", relative_path.display(), err));
+                    continue;
+                }
+            }
+            match fs::read_dir(&path) {
+                Ok(entries) => walk_directory(entries, &relative_path, filter, visited, walk),
+                Err(err) => walk.warnings.push(format!(
+                    "Skipped the folder {}: {}",
+                    relative_path.display(),
+                    err
+                )),
+            }
+        } else if metadata.is_file() && filter.includes_file(&relative_path, &metadata) {
+            walk.files.push(relative_path);
+        }
+    }
+}
+
+// The sync state is text, it can not hold names that are not valid UTF-8, so
+// those files are converted again on every incremental run.
+fn get_sync_warnings(instruction: &ConversionInstructions, files: &[PathBuf]) -> Vec<String> {
+    if !instruction.sync_config.incremental {
+        return Vec::new();
+    }
+    files
+        .iter()
+        .filter(|file_path| file_path.to_str().is_none())
+        .map(|file_path| {
+            format!(
+                "{} is not valid UTF-8, it can not be synced incrementally",
+                file_path.display()
+            )
+        })
+        .collect()
+}
+
+/// Fills in what depends on the whole job: the action of every file, its
+/// templated path and the files that would overwrite each other. Every file
+/// is probed once here, so this is blocking work.
+pub(crate) fn prepare_job(
+    instruction: ConversionInstructions,
+    files: &[PathBuf],
+) -> ConversionInstructions {
+    let file_actions = files
+        .iter()
+        .map(|file_path| (file_path.clone(), instruction.get_file_action(file_path)))
+        .collect();
+    let instruction = ConversionInstructions {
+        file_actions: Arc::new(file_actions),
+        ..instruction
+    };
+    let instruction = ConversionInstructions {
+        templated_paths: Arc::new(get_templated_paths(&instruction, files)),
+        ..instruction
+    };
+    ConversionInstructions {
+        collisions: Arc::new(find_collisions(&instruction, files)),
+        ..instruction
+    }
+}
+
+/// Prepares a job from the entries of its journal. Files that were being
+/// written when a resumed job stopped only ever left a temporary file behind.
+/// Its path depends on the naming template and the collisions of the job, so
+/// it is only removed once the job is prepared, and the file is converted
+/// again.
+pub(crate) fn prepare_journaled_job(
+    instruction: ConversionInstructions,
+    entries: &mut [JournalEntry],
+) -> ConversionInstructions {
+    let files: Vec<PathBuf> = entries
+        .iter()
+        .map(|entry| entry.file_path.clone())
+        .collect();
+    let instruction = prepare_job(instruction, &files);
+    for entry in entries.iter_mut() {
+        if entry.state != FileState::InProgress {
+            continue;
+        }
+        if let Some(output_path) = get_expected_output(&instruction, &entry.file_path) {
+            let _ = fs::remove_file(get_temp_path(&output_path));
         }
+        entry.state = FileState::Pending;
     }
-    // for i in list_of_files.clone() {
-    //     println!("{}", i)
-    // }
-    Ok(list_of_files.clone())
+    instruction
 }
 
+// Runs `prepare_journaled_job` on a blocking thread, as probing every file
+// and reading its tags is blocking work. Without the prepared job outputs
+// would land at the wrong paths and the mirror would take the right ones for
+// orphans, so a failure ends the job before anything is written.
+async fn prepare_job_in_background(
+    conversion_details: ConversionInstructions,
+    mut entries: Vec<JournalEntry>,
+) -> Option<(ConversionInstructions, Vec<JournalEntry>)> {
+    match tokio::task::spawn_blocking(move || {
+        let conversion_details = prepare_journaled_job(conversion_details, &mut entries);
+        (conversion_details, entries)
+    })
+    .await
+    {
+        Ok(prepared) => Some(prepared),
+        Err(err) => {
+            debug_print!("Error in preparing thread: {:?}", err);
+            ProgressUpdate {
+                msg: format!("Failed to prepare the conversion: {}", err),
+                handling_thread: 0,
+                message_type: MessageType::Fail.into(),
+            }
+            .send_signal_to_dart();
+            ProgressUpdate {
+                msg: "Conversion Finished".to_string(),
+                handling_thread: 0,
+                message_type: MessageType::ConversionFinish.into(),
+            }
+            .send_signal_to_dart();
+            None
+        }
+    }
+}
+
+// the state shared by the workers of a job, passed one by one
+#[allow(clippy::too_many_arguments)]
 async fn process_files_till_empty(
     instruction: ConversionInstructions,
     thread_no: i32,
-    files: Arc<Mutex<Vec<String>>>,
+    files: Arc<Mutex<Vec<PathBuf>>>,
     app_state: Arc<Mutex<AppState>>,
     update_buffer: Arc<Mutex<ProgressBuffer>>,
+    sync_state: Arc<Mutex<SyncState>>,
+    journal: Arc<Mutex<Option<Journal>>>,
+    written_outputs: Arc<Mutex<HashSet<PathBuf>>>,
 ) {
     loop {
-        {
+        let paused = {
             let state = app_state.lock().await;
-            if let AppState::DoNothing = *state {
-                return;
+            match *state {
+                AppState::DoNothing => return,
+                AppState::Paused => true,
+                AppState::Convert => false,
             }
+        };
+        // parked until resumed, the queue is left untouched
+        if paused {
+            sleep(Duration::from_millis(200)).await;
+            continue;
+        }
+        if instruction.cancel_token.is_cancelled() {
+            return;
         }
         let path_option = {
             let mut list_of_files = files.lock().await;
@@ -141,7 +571,36 @@ async fn process_files_till_empty(
         match path_option {
             Some(path) => {
                 let update_buffer_clone = Arc::clone(&update_buffer);
-                handle_file(&instruction, path, thread_no, update_buffer_clone).await;
+                let sync_state_clone = Arc::clone(&sync_state);
+                record_state(&journal, &path, FileState::InProgress, None).await;
+                let outcome = handle_file(
+                    &instruction,
+                    path.clone(),
+                    thread_no,
+                    update_buffer_clone,
+                    sync_state_clone,
+                )
+                .await;
+                match outcome {
+                    FileOutcome::Done(output_path) => {
+                        let checksum = output_path
+                            .as_ref()
+                            .and_then(|output_path| get_content_hash(output_path).ok());
+                        record_state(&journal, &path, FileState::Done, checksum).await;
+                        if let Some(output_path) = output_path {
+                            written_outputs.lock().await.insert(output_path);
+                        }
+                    }
+                    FileOutcome::Failed => {
+                        record_state(&journal, &path, FileState::Failed, None).await;
+                    }
+                    FileOutcome::Interrupted => {
+                        record_state(&journal, &path, FileState::Pending, None).await;
+                        // put back so it is listed as unconverted
+                        files.lock().await.push(path);
+                        return;
+                    }
+                }
             }
             None => {
                 let mut buffer_lock = update_buffer.lock().await;
@@ -165,127 +624,565 @@ async fn process_files_till_empty(
 
 async fn handle_file(
     instruction: &ConversionInstructions,
-    file_path: String,
+    file_path: PathBuf,
     thread: i32,
     update_buffer: Arc<Mutex<ProgressBuffer>>,
-) {
-    match decide_file_action(&file_path) {
-        // If the file isn't of a supported audio format then it will be copied
-        FileAction::Copy => {
-            if !instruction.copy_unrecognised_files {
-                return;
+    sync_state: Arc<Mutex<SyncState>>,
+) -> FileOutcome {
+    let src_file_path = Path::new(&instruction.src_path).join(&file_path);
+    let file_action = instruction.get_file_action(&file_path);
+    if let FileAction::Copy = file_action {
+        if !instruction.copy_unrecognised_files {
+            return FileOutcome::Done(None);
+        }
+    }
+    match instruction.collisions.get(&file_path) {
+        Some(Collision::LeftOut(msg)) => {
+            let mut update_buffer_lock = update_buffer.lock().await;
+            update_buffer_lock.add(ProgressUpdate {
+                handling_thread: thread,
+                message_type: MessageType::Skipped.into(),
+                msg: msg.clone(),
+            });
+            return FileOutcome::Done(None);
+        }
+        Some(Collision::Failed(msg)) => {
+            let mut update_buffer_lock = update_buffer.lock().await;
+            update_buffer_lock.add(ProgressUpdate {
+                handling_thread: thread,
+                message_type: MessageType::Fail.into(),
+                msg: msg.clone(),
+            });
+            return FileOutcome::Failed;
+        }
+        _ => {}
+    }
+    let output_path = get_output_path(instruction, &file_path, &file_action);
+    let settings = get_sync_settings(instruction, &file_action);
+
+    // in incremental mode files whose output is still current are skipped
+    let mut source_state = None;
+    if instruction.sync_config.incremental {
+        let src_path = src_file_path.as_path();
+        if let Ok(mut state) = SourceState::new_from_path(src_path) {
+            let mut up_to_date =
+                sync_state
+                    .lock()
+                    .await
+                    .is_up_to_date(&file_path, &state, &settings, &output_path);
+            if !up_to_date && instruction.sync_config.compare_content_hash {
+                if let Ok(hashed_state) = state.clone().with_content_hash(src_path) {
+                    state = hashed_state;
+                    up_to_date = sync_state.lock().await.is_up_to_date(
+                        &file_path,
+                        &state,
+                        &settings,
+                        &output_path,
+                    );
+                }
+            }
+            if up_to_date {
+                let mut update_buffer_lock = update_buffer.lock().await;
+                update_buffer_lock.add(ProgressUpdate {
+                    handling_thread: thread,
+                    message_type: MessageType::Skipped.into(),
+                    msg: format!("Skipped {}, its output is up to date", file_path.display()),
+                });
+                return FileOutcome::Done(Some(output_path));
             }
+            source_state = Some(state);
+        }
+    }
 
-            let target_path = instruction.dest_path.clone() + &file_path;
+    // an output the sync state knows of was made by an earlier run, it is
+    // simply replaced
+    let made_by_us =
+        instruction.sync_config.incremental && sync_state.lock().await.has_record(&file_path);
+    let output_path = match made_by_us {
+        true => output_path,
+        false => match resolve_existing_output(
+            instruction.conflict_policy,
+            &src_file_path,
+            output_path.clone(),
+        ) {
+            Ok(Some(output_path)) => output_path,
+            Ok(None) => {
+                let mut update_buffer_lock = update_buffer.lock().await;
+                update_buffer_lock.add(ProgressUpdate {
+                    handling_thread: thread,
+                    message_type: MessageType::Skipped.into(),
+                    msg: format!(
+                        "Skipped {}, {} already exists",
+                        file_path.display(),
+                        output_path.display()
+                    ),
+                });
+                return FileOutcome::Done(None);
+            }
+            Err(err) => {
+                let mut update_buffer_lock = update_buffer.lock().await;
+                update_buffer_lock.add(ProgressUpdate {
+                    handling_thread: thread,
+                    message_type: MessageType::Fail.into(),
+                    msg: format!("Failed to write {}: {}", file_path.display(), err),
+                });
+                return FileOutcome::Failed;
+            }
+        },
+    };
 
-            let directory_path = get_target_directory(instruction.dest_path.clone(), &file_path);
-            fs::create_dir_all(directory_path).unwrap();
-            match std::fs::copy(instruction.src_path.clone() + &file_path, target_path) {
-                Ok(_) => {
+    let succeeded = match file_action {
+        // If the file isn't of a supported audio format then it will be copied
+        FileAction::Copy => {
+            match place_passthrough_file(instruction.passthrough_mode, &src_file_path, &output_path)
+            {
+                Ok((placed_as, link_error)) => {
                     // debug_print!("Copied {} to new destination", file_path,);
+                    let msg = match (placed_as, link_error) {
+                        (PassthroughMode::PassthroughCopy, None) => {
+                            format!(
+                                "Copied {} to {}",
+                                file_path.display(),
+                                output_path.display()
+                            )
+                        }
+                        (PassthroughMode::PassthroughCopy, Some(err)) => format!(
+                            "Copied {} to {}, the {} failed: {}",
+                            file_path.display(),
+                            output_path.display(),
+                            get_passthrough_name(instruction.passthrough_mode),
+                            err
+                        ),
+                        (placed_as, _) => format!(
+                            "Placed {} at {} as a {}",
+                            file_path.display(),
+                            output_path.display(),
+                            get_passthrough_name(placed_as)
+                        ),
+                    };
                     let mut update_buffer_lock = update_buffer.lock().await;
                     update_buffer_lock.add(ProgressUpdate {
                         handling_thread: thread,
-                        msg: format!(
-                            "Copied {} to {}{}",
-                            file_path, instruction.dest_path, file_path
-                        ),
+                        msg,
                         message_type: MessageType::FileFinish.into(),
                     });
+                    true
                 }
-                Err(_) => {
+                Err(err) => {
                     let mut update_buffer_lock = update_buffer.lock().await;
                     update_buffer_lock.add(ProgressUpdate {
                         handling_thread: thread,
                         msg: format!(
-                            "Failed to copy {} to {}{}",
-                            file_path, instruction.dest_path, file_path
+                            "Failed to copy {} to {}: {}",
+                            file_path.display(),
+                            output_path.display(),
+                            err
                         ),
                         message_type: MessageType::Fail.into(),
                     });
+                    false
                 }
-            };
+            }
         }
         FileAction::Convert => {
-            let src_file_path = instruction.src_path.clone() + &file_path;
-            let raw_audio = match RawAudioData::new_from_path(Path::new(&src_file_path)) {
-                Ok(data) => data,
-                Err(_) => {
-                    // debug_print!(
-                    //     "Failed to decode file at {}. Skipping this file.",
-                    //     file_path
-                    // );
-                    let mut update_buffer_lock = update_buffer.lock().await;
-                    update_buffer_lock.add(ProgressUpdate {
-                        handling_thread: thread,
-                        message_type: MessageType::Fail.into(),
-                        msg: format!(
-                            "Failed to decode file at {}. Skipping this file.",
-                            file_path
-                        ),
-                    });
-                    return;
-                }
-            };
-            let encoded_audio = match instruction.target_format {
-                TargetFormat::Mp3 => raw_audio.encode_to_mp3(
-                    instruction.mp3_config.quality(),
-                    instruction.mp3_config.bitrate(),
-                ),
-                // TargetFormat::Opus => todo!(),
-            };
-            match encoded_audio {
-                Ok(output_audio) => {
-                    let write_path = instruction.dest_path.clone() + &file_path;
-                    let write_path = Path::new(&write_path);
-                    let write_path = write_path.with_extension("mp3");
-                    let directory_path =
-                        get_target_directory(instruction.dest_path.clone(), &file_path);
-                    fs::create_dir_all(directory_path).unwrap();
-                    fs::write(&write_path, output_audio).unwrap();
-
+            let conversion =
+                stream_to_target(instruction, &file_path, &src_file_path, &output_path);
+            match conversion {
+                Ok(skipped_packets) => {
                     let mut update_buffer_lock = update_buffer.lock().await;
                     update_buffer_lock.add(ProgressUpdate {
                         handling_thread: thread,
                         message_type: MessageType::FileFinish.into(),
-                        msg: format!("Converted {} to target format", file_path,),
+                        msg: match skipped_packets {
+                            0 => format!("Converted {} to target format", file_path.display()),
+                            skipped => format!(
+                                "Converted {} to target format, skipped {} corrupt packets",
+                                file_path.display(),
+                                skipped
+                            ),
+                        },
                     });
+                    true
+                }
+                // an interrupted file is reported with the unconverted ones
+                Err(_) if instruction.cancel_token.is_cancelled() => {
+                    return FileOutcome::Interrupted
                 }
-                Err(_) => {
-                    // debug_print!(
-                    //     "Failed to encode file at {}. Skipping this file.",
-                    //     file_path
-                    // );
+                Err(msg) => {
                     let mut update_buffer_lock = update_buffer.lock().await;
                     update_buffer_lock.add(ProgressUpdate {
                         handling_thread: thread,
                         message_type: MessageType::Fail.into(),
-                        msg: format!(
-                            "Failed to encode file at {}. Skipping this file.",
-                            file_path
-                        ),
+                        msg,
                     });
+                    false
                 }
             }
         }
+    };
+
+    if let Some(mut state) = source_state {
+        let mut sync_state_lock = sync_state.lock().await;
+        if !succeeded {
+            sync_state_lock.remove(&file_path);
+            return FileOutcome::Failed;
+        }
+        if instruction.sync_config.compare_content_hash {
+            if let Ok(hashed_state) = state.clone().with_content_hash(&src_file_path) {
+                state = hashed_state;
+            }
+        }
+        sync_state_lock.update(&file_path, state, settings, &output_path);
+    }
+    match succeeded {
+        true => FileOutcome::Done(Some(output_path)),
+        false => FileOutcome::Failed,
+    }
+}
+
+// A lost record only means the file is converted again on resume.
+async fn record_state(
+    journal: &Arc<Mutex<Option<Journal>>>,
+    file_path: &Path,
+    state: FileState,
+    checksum: Option<String>,
+) {
+    if let Some(journal) = journal.lock().await.as_mut() {
+        let _ = journal.record(file_path, state, &checksum);
+    }
+}
+
+// Outputs made with other settings are not up to date, so the settings
+// that matter for the file are recorded along with it. The fields are listed
+// by hand, a field added to a message must not make every output stale.
+pub(crate) fn get_sync_settings(
+    instruction: &ConversionInstructions,
+    file_action: &FileAction,
+) -> String {
+    let settings = match file_action {
+        FileAction::Copy => return "Copy".to_string(),
+        FileAction::Convert => match instruction.target_format {
+            TargetFormat::Mp3 => {
+                let config = &instruction.mp3_config;
+                format!(
+                    "Mp3 quality={} bitrate={} id3_version={} id3v1={}",
+                    config.quality, config.bitrate, config.id3_version, config.write_id3v1
+                )
+            }
+            TargetFormat::Opus => {
+                let config = &instruction.opus_config;
+                format!(
+                    "Opus bitrate={} complexity={} application={}",
+                    config.bitrate, config.complexity, config.application
+                )
+            }
+            TargetFormat::Vorbis => {
+                let config = &instruction.vorbis_config;
+                format!(
+                    "Vorbis mode={} quality={} bitrate={}",
+                    config.mode, config.quality, config.bitrate
+                )
+            }
+            TargetFormat::Flac => format!(
+                "Flac compression_level={}",
+                instruction.flac_config.compression_level
+            ),
+            TargetFormat::Wav => "Wav".to_string(),
+            TargetFormat::Aiff => "Aiff".to_string(),
+        },
+    };
+    // left out while pictures are embedded as they are, so outputs synced
+    // before the artwork options existed stay up to date
+    match get_artwork_settings(&instruction.artwork_config) {
+        Some(artwork_settings) => format!("{} {}", settings, artwork_settings),
+        None => settings,
+    }
+}
+
+// Returns the number of corrupt packets that were skipped, or the message
+// to report on failure.
+fn stream_to_target(
+    instruction: &ConversionInstructions,
+    file_path: &Path,
+    src_file_path: &Path,
+    write_path: &Path,
+) -> Result<usize, String> {
+    let mut audio_stream =
+        match AudioStream::new_from_path(src_file_path, instruction.cancel_token.clone()) {
+            Ok(stream) => stream,
+            Err(err) => {
+                return Err(format!(
+                    "Failed to decode file at {}: {}. Skipping this file.",
+                    file_path.display(),
+                    err
+                ))
+            }
+        };
+    audio_stream.set_album_art(get_artwork(
+        audio_stream.get_album_art(),
+        &instruction.artwork_config,
+        &instruction.artwork_cache,
+    ));
+    let mut output_file = match AtomicFile::create(write_path) {
+        Ok(file) => file,
+        Err(err) => {
+            return Err(format!(
+                "Failed to create {}: {}. Skipping this file.",
+                write_path.display(),
+                err
+            ))
+        }
+    };
+    let encoded = match instruction.target_format {
+        TargetFormat::Mp3 => {
+            audio_stream.encode_to_mp3_writer(&instruction.mp3_config, &mut output_file)
+        }
+        TargetFormat::Vorbis => {
+            audio_stream.encode_to_vorbis_writer(&instruction.vorbis_config, &mut output_file)
+        }
+        TargetFormat::Flac => {
+            audio_stream.encode_to_flac_writer(&instruction.flac_config, &mut output_file)
+        }
+        TargetFormat::Wav => audio_stream.encode_to_wav_writer(&mut output_file),
+        TargetFormat::Aiff => audio_stream.encode_to_aiff_writer(&mut output_file),
+        TargetFormat::Opus => {
+            audio_stream.encode_to_opus_writer(&instruction.opus_config, &mut output_file)
+        }
+    };
+    if let Err(err) = encoded {
+        // a half written file is worse than none
+        output_file.discard();
+        return Err(format!(
+            "Failed to encode file at {}: {}. Skipping this file.",
+            file_path.display(),
+            err
+        ));
+    }
+    if let Err(err) = output_file.commit() {
+        return Err(format!(
+            "Failed to write {}: {}. Skipping this file.",
+            write_path.display(),
+            err
+        ));
     }
+    Ok(audio_stream.get_skipped_packets())
 }
 
-fn decide_file_action(file_path: &String) -> FileAction {
-    if file_path.ends_with(".flac") {
-        return FileAction::Convert;
+/// Path of the file that `file_path` turns into in the destination, `None`
+/// if it is left out.
+pub(crate) fn get_expected_output(
+    instruction: &ConversionInstructions,
+    file_path: &Path,
+) -> Option<PathBuf> {
+    let file_action = instruction.get_file_action(file_path);
+    match (&file_action, instruction.collisions.get(file_path)) {
+        (FileAction::Copy, _) if !instruction.copy_unrecognised_files => None,
+        (_, Some(Collision::LeftOut(_))) | (_, Some(Collision::Failed(_))) => None,
+        _ => Some(get_output_path(instruction, file_path, &file_action)),
     }
-    FileAction::Copy
 }
 
-fn get_target_directory(dest_path: String, file_path: &String) -> String {
-    let target_path = dest_path + &file_path;
-    let target_path = Path::new(&target_path);
-    let directory_path = target_path.parent().unwrap().to_str().unwrap().to_string();
-    return directory_path;
+/// Path the output of `file_path` is written to, before the conflict policy
+/// is applied to files already in the destination.
+pub(crate) fn get_output_path(
+    instruction: &ConversionInstructions,
+    file_path: &Path,
+    file_action: &FileAction,
+) -> PathBuf {
+    if let Some(Collision::Renamed(renamed_path)) = instruction.collisions.get(file_path) {
+        return renamed_path.clone();
+    }
+    match file_action {
+        FileAction::Copy => Path::new(&instruction.dest_path).join(file_path),
+        FileAction::Convert => get_write_path(instruction, file_path),
+    }
+}
+
+pub(crate) fn get_write_path(instruction: &ConversionInstructions, file_path: &Path) -> PathBuf {
+    // files without the tags the template needs keep the path they have in
+    // the source
+    if let Some(templated_path) = instruction.templated_paths.get(file_path) {
+        return Path::new(&instruction.dest_path).join(templated_path);
+    }
+    Path::new(&instruction.dest_path)
+        .join(file_path)
+        .with_extension(get_target_extension(&instruction.target_format))
+}
+
+fn decide_file_action(src_file_path: &Path, convert_formats: &[SourceFormat]) -> FileAction {
+    match get_source_format(src_file_path) {
+        Some(source_format) if convert_formats.contains(&source_format) => FileAction::Convert,
+        _ => FileAction::Copy,
+    }
+}
+
+pub(crate) fn get_target_extension(target_format: &TargetFormat) -> &'static str {
+    match target_format {
+        TargetFormat::Mp3 => "mp3",
+        TargetFormat::Opus => "opus",
+        TargetFormat::Vorbis => "ogg",
+        TargetFormat::Flac => "flac",
+        TargetFormat::Wav => "wav",
+        TargetFormat::Aiff => "aiff",
+    }
 }
 
-enum FileAction {
+#[derive(Clone, Copy)]
+pub(crate) enum FileAction {
     Copy,
     Convert,
 }
+
+enum FileOutcome {
+    // carries the output that is now in place, if there is one
+    Done(Option<PathBuf>),
+    Failed,
+    // stopped by a cancellation, the file is still to be converted
+    Interrupted,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use symphonia::core::{
+        meta::{StandardTagKey, Tag, Value},
+        sample::SampleFormat,
+    };
+
+    use super::*;
+    use crate::encoder_decoder::{
+        encoders::flac::flac::FlacEncoder,
+        raw_audio_data::raw_audio_data::{AudioChannels, RawAudioData},
+    };
+
+    // A short silent FLAC track with an artist and a title.
+    fn write_tagged_flac(path: &Path) {
+        let tags = vec![
+            Tag::new(
+                Some(StandardTagKey::Artist),
+                "ARTIST",
+                Value::from("Artist"),
+            ),
+            Tag::new(
+                Some(StandardTagKey::TrackTitle),
+                "TITLE",
+                Value::from("Title"),
+            ),
+        ];
+        let audio = HashMap::from([(AudioChannels::FrontLeft, vec![0; 4096])]);
+        let raw_audio =
+            RawAudioData::new(audio, 44100, 16, SampleFormat::S16, Vec::new(), None, tags);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, raw_audio.encode_to_flac(&Default::default()).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn removes_the_temporary_file_at_the_templated_output_on_resume() {
+        let root = std::env::temp_dir().join(format!(
+            "diraudio-conversion-handler-resume-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&root);
+        let (src_path, dest_path) = (root.join("music"), root.join("converted"));
+        write_tagged_flac(&src_path.join("Album/01.flac"));
+        let message = Convert {
+            src_path: src_path.to_string_lossy().to_string(),
+            dest_path: dest_path.to_string_lossy().to_string(),
+            naming_template: "{artist}/{title}".to_string(),
+            ..Default::default()
+        };
+        let instruction = crate::get_conversion_instructions(&message);
+
+        // what an interrupted worker leaves behind
+        let output_path = dest_path.join("Artist/Title.mp3");
+        fs::create_dir_all(output_path.parent().unwrap()).unwrap();
+        fs::write(get_temp_path(&output_path), b"half").unwrap();
+        let mut entries = vec![JournalEntry {
+            file_path: PathBuf::from("Album/01.flac"),
+            state: FileState::InProgress,
+            checksum: None,
+        }];
+
+        let instruction = prepare_journaled_job(instruction, &mut entries);
+        assert!(!get_temp_path(&output_path).exists());
+        assert!(entries[0].state == FileState::Pending);
+        assert_eq!(
+            get_expected_output(&instruction, Path::new("Album/01.flac")),
+            Some(output_path)
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn walks_past_unreadable_folders_and_link_loops() {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+
+        let root = std::env::temp_dir().join(format!(
+            "diraudio-conversion-handler-walk-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&root);
+        let locked = root.join("locked");
+        fs::create_dir_all(root.join("album")).unwrap();
+        fs::create_dir_all(&locked).unwrap();
+        fs::write(root.join("album/01.flac"), b"").unwrap();
+        fs::write(locked.join("02.flac"), b"").unwrap();
+        symlink(&root, root.join("album/loop")).unwrap();
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+        // permissions do not hold back root, the folder is walked then
+        let locked_readable = fs::read_dir(&locked).is_ok();
+        let filter = WalkFilter::new_from_config(&FilterConfig {
+            follow_symlinks: true,
+            ..Default::default()
+        })
+        .unwrap();
+
+        let walk = traverse_directory(&root, &filter);
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        let walk = walk.unwrap();
+        assert!(walk.files.contains(&PathBuf::from("album/01.flac")));
+        assert!(walk
+            .warnings
+            .iter()
+            .any(|warning| warning.starts_with("Skipped album/loop, it links to a folder")));
+        if locked_readable {
+            assert_eq!(walk.files.len(), 2);
+            assert_eq!(walk.warnings.len(), 1);
+        } else {
+            assert_eq!(walk.files, vec![PathBuf::from("album/01.flac")]);
+            assert!(walk
+                .warnings
+                .iter()
+                .any(|warning| warning.starts_with("Skipped the folder locked")));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn warns_of_names_the_sync_state_can_not_hold_only_when_incremental() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        // Latin-1 `café.flac`, which is not valid UTF-8
+        let files = vec![
+            PathBuf::from(OsStr::from_bytes(b"caf\xe9.flac")),
+            PathBuf::from("song.flac"),
+        ];
+        let incremental = crate::get_conversion_instructions(&Convert {
+            sync_config: Some(SyncConfig {
+                incremental: true,
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        let warnings = get_sync_warnings(&incremental, &files);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].ends_with("is not valid UTF-8, it can not be synced incrementally"));
+
+        let full = crate::get_conversion_instructions(&Convert::default());
+        assert!(get_sync_warnings(&full, &files).is_empty());
+    }
+}
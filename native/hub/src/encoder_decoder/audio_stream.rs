@@ -0,0 +1,582 @@
+pub mod audio_stream {
+    use std::{collections::HashMap, fs::File, io::ErrorKind, path::Path};
+
+    use symphonia::core::{
+        audio::{AudioBufferRef, Channels},
+        codecs::{Decoder, CODEC_TYPE_NULL},
+        errors::Error,
+        formats::FormatReader,
+        io::MediaSourceStream,
+        meta::{Tag, VendorData, Visual},
+        sample::SampleFormat,
+    };
+
+    use crate::{
+        cancellation::CancellationToken,
+        encoder_decoder::{
+            decode_error::decode_error::DecodeError, raw_audio_data::raw_audio_data::AudioChannels,
+        },
+    };
+
+    // What a packet decodes to, one plane per channel.
+    type DecodedPacket = (SampleFormat, Channels, Vec<Vec<i32>>);
+
+    /// A decoder over the audio track of a file that hands out the samples
+    /// one packet at a time, so a track never has to be held in memory as a
+    /// whole. Samples are full scale i32, one plane per channel in the order
+    /// of `get_channels`.
+    pub struct AudioStream {
+        reader: Box<dyn FormatReader>,
+        decoder: Box<dyn Decoder>,
+        track_id: u32,
+        channels: Vec<AudioChannels>,
+        sample_rate: u32,
+        bits_per_sample: u32,
+        sample_format: SampleFormat,
+        image_data: Vec<Visual>,
+        vendor_data: Option<VendorData>,
+        tag_data: Vec<Tag>,
+        // the first packet is decoded up front to learn the sample format
+        pending_block: Option<Vec<Vec<i32>>>,
+        skipped_packets: usize,
+        last_timestamp: u64,
+        cancel_token: CancellationToken,
+    }
+
+    impl AudioStream {
+        pub fn new_from_path(
+            path: &Path,
+            cancel_token: CancellationToken,
+        ) -> Result<AudioStream, DecodeError> {
+            let codecs = symphonia::default::get_codecs();
+            let probe = symphonia::default::get_probe();
+            let mss = MediaSourceStream::new(Box::new(File::open(path)?), Default::default());
+            let mut probed = match probe.format(
+                &Default::default(),
+                mss,
+                &Default::default(),
+                &Default::default(),
+            ) {
+                Ok(probed) => probed,
+                Err(err) => return Err(DecodeError::UnsupportedFormat(err.to_string())),
+            };
+            let track = match probed
+                .format
+                .tracks()
+                .iter()
+                .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+            {
+                Some(track) => track,
+                None => return Err(DecodeError::NoAudioTrack),
+            };
+            let track_id = track.id;
+
+            let decoder = match codecs.make(&track.codec_params, &Default::default()) {
+                Ok(decoder) => decoder,
+                Err(err) => return Err(DecodeError::UnsupportedCodec(err.to_string())),
+            };
+            let sample_rate = match decoder.codec_params().sample_rate {
+                Some(sample_rate) => sample_rate,
+                None => return Err(DecodeError::MissingSampleRate),
+            };
+            let bits_per_sample = decoder.codec_params().bits_per_sample;
+            let named_channels = decoder.codec_params().channels;
+
+            // working with meta data, tags in front of the container (like
+            // ID3v2) are found by the probe instead of the reader
+            let latest_meta = match probed.format.metadata().skip_to_latest() {
+                Some(revision) => Some(revision.clone()),
+                None => probed
+                    .metadata
+                    .get()
+                    .and_then(|mut metadata| metadata.skip_to_latest().cloned()),
+            };
+            let (image_data, vendor_data, tag_data) = match latest_meta {
+                Some(meta) => (
+                    meta.visuals().to_vec(),
+                    meta.vendor_data().first().cloned(),
+                    meta.tags().to_vec(),
+                ),
+                None => (Vec::new(), None, Vec::new()),
+            };
+
+            let mut stream = AudioStream {
+                reader: probed.format,
+                decoder,
+                track_id,
+                channels: Vec::new(),
+                sample_rate,
+                bits_per_sample: 0,
+                sample_format: SampleFormat::S32,
+                image_data,
+                vendor_data,
+                tag_data,
+                pending_block: None,
+                skipped_packets: 0,
+                last_timestamp: 0,
+                cancel_token,
+            };
+            let (sample_format, decoded_channels, block) = match stream.decode_next_packet()? {
+                Some(first_packet) => first_packet,
+                None => {
+                    return Err(DecodeError::NoDecodablePackets {
+                        skipped: stream.skipped_packets,
+                    })
+                }
+            };
+            // some decoders only know the layout once they decoded audio
+            let named_channels = named_channels.unwrap_or(decoded_channels);
+            let channel_mappings = get_channel_mappings();
+            for channel in named_channels.iter() {
+                match channel_mappings.get(&channel) {
+                    Some(channel) => stream.channels.push(channel.clone()),
+                    None => return Err(DecodeError::UnknownChannelLayout),
+                }
+            }
+            if stream.channels.len() != block.len() {
+                return Err(DecodeError::UnknownChannelLayout);
+            }
+            stream.sample_format = sample_format;
+            stream.bits_per_sample =
+                bits_per_sample.unwrap_or_else(|| get_effective_bit_depth(sample_format));
+            stream.pending_block = Some(block);
+            Ok(stream)
+        }
+
+        /// Decodes the next packet of the audio track. Returns `None` once
+        /// the end of the track is reached.
+        pub fn next_block(&mut self) -> Result<Option<Vec<Vec<i32>>>, DecodeError> {
+            if let Some(block) = self.pending_block.take() {
+                return Ok(Some(block));
+            }
+            match self.decode_next_packet()? {
+                Some((_, _, block)) => Ok(Some(block)),
+                None => Ok(None),
+            }
+        }
+
+        fn decode_next_packet(&mut self) -> Result<Option<DecodedPacket>, DecodeError> {
+            loop {
+                self.cancel_token.wait_while_paused();
+                if self.cancel_token.is_cancelled() {
+                    return Err(DecodeError::Cancelled);
+                }
+                let packet = match self.reader.next_packet() {
+                    Ok(packet) => packet,
+                    // the end of the stream is reported as an unexpected eof
+                    Err(Error::IoError(err)) if err.kind() == ErrorKind::UnexpectedEof => {
+                        return Ok(None)
+                    }
+                    Err(Error::IoError(err)) => return Err(DecodeError::Io(err)),
+                    // a new stream starts, like in a chained Ogg file
+                    Err(Error::ResetRequired) => {
+                        self.reset_decoder()?;
+                        continue;
+                    }
+                    Err(_) => {
+                        return Err(DecodeError::CorruptPacket {
+                            offset: self.last_timestamp,
+                        })
+                    }
+                };
+                if packet.track_id() != self.track_id {
+                    continue;
+                }
+                self.last_timestamp = packet.ts();
+                let decoded = match self.decoder.decode(&packet) {
+                    Ok(decoded) => decoded,
+                    // a damaged packet only loses its own samples
+                    Err(Error::DecodeError(_)) | Err(Error::IoError(_)) => {
+                        self.skipped_packets += 1;
+                        continue;
+                    }
+                    Err(Error::ResetRequired) => {
+                        self.decoder.reset();
+                        self.skipped_packets += 1;
+                        continue;
+                    }
+                    Err(err) => return Err(DecodeError::UnsupportedCodec(err.to_string())),
+                };
+                // the channels are only known once the first packet is decoded
+                if !self.channels.is_empty()
+                    && decoded.spec().channels.count() != self.channels.len()
+                {
+                    return Err(DecodeError::StreamChanged);
+                }
+                let sample_format = get_sample_format(&decoded);
+                let channels = decoded.spec().channels;
+                return Ok(Some((
+                    sample_format,
+                    channels,
+                    get_full_scale_planes(&decoded),
+                )));
+            }
+        }
+
+        // Picks up the audio track of the new stream. The samples go on in
+        // the same output, so it has to have the same rate and channels.
+        fn reset_decoder(&mut self) -> Result<(), DecodeError> {
+            let track = match self
+                .reader
+                .tracks()
+                .iter()
+                .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+            {
+                Some(track) => track,
+                None => return Err(DecodeError::NoAudioTrack),
+            };
+            let track_id = track.id;
+            let decoder = match symphonia::default::get_codecs()
+                .make(&track.codec_params, &Default::default())
+            {
+                Ok(decoder) => decoder,
+                Err(err) => return Err(DecodeError::UnsupportedCodec(err.to_string())),
+            };
+            let same_channels = match decoder.codec_params().channels {
+                Some(channels) => {
+                    self.channels.is_empty() || channels.count() == self.channels.len()
+                }
+                None => true,
+            };
+            if decoder.codec_params().sample_rate != Some(self.sample_rate) || !same_channels {
+                return Err(DecodeError::StreamChanged);
+            }
+            self.track_id = track_id;
+            self.decoder = decoder;
+            Ok(())
+        }
+
+        pub fn get_channels(&self) -> &Vec<AudioChannels> {
+            &self.channels
+        }
+
+        pub fn get_no_of_channels(&self) -> u8 {
+            self.channels.len() as u8
+        }
+
+        /// Positions of the planes in the WAVE channel order, the order of
+        /// `RawAudioData::get_ordered_channels`.
+        pub fn get_ordered_positions(&self) -> Vec<usize> {
+            let mut positions: Vec<usize> = (0..self.channels.len()).collect();
+            positions.sort_by_key(|position| self.channels[*position].clone() as u32);
+            positions
+        }
+
+        /// Positions of the planes that make up the left and right channel
+        /// of a stereo down mix.
+        pub fn get_stereo_pair_positions(&self) -> Result<(usize, usize), String> {
+            let position = |candidates: [AudioChannels; 3]| {
+                candidates
+                    .iter()
+                    .find_map(|candidate| self.channels.iter().position(|c| c == candidate))
+            };
+            let left = match position([
+                AudioChannels::FrontLeft,
+                AudioChannels::SideLeft,
+                AudioChannels::RearLeft,
+            ]) {
+                Some(left) => left,
+                None => return Err("Could not find appropriate left channel".to_string()),
+            };
+            let right = match position([
+                AudioChannels::FrontRight,
+                AudioChannels::SideRight,
+                AudioChannels::RearRight,
+            ]) {
+                Some(right) => right,
+                None => return Err("Could not find appropriate right channel".to_string()),
+            };
+            Ok((left, right))
+        }
+
+        pub fn get_sample_rate(&self) -> u32 {
+            self.sample_rate
+        }
+
+        pub fn get_bit_depth(&self) -> u32 {
+            self.bits_per_sample
+        }
+
+        pub fn get_sample_format(&self) -> SampleFormat {
+            self.sample_format
+        }
+
+        pub fn get_album_art(&self) -> &Vec<Visual> {
+            &self.image_data
+        }
+
+        pub fn set_album_art(&mut self, visuals: Vec<Visual>) {
+            self.image_data = visuals;
+        }
+
+        pub fn get_vendor_data(&self) -> &Option<VendorData> {
+            &self.vendor_data
+        }
+
+        pub fn get_tags(&self) -> &Vec<Tag> {
+            &self.tag_data
+        }
+
+        pub fn get_skipped_packets(&self) -> usize {
+            self.skipped_packets
+        }
+    }
+
+    fn get_channel_mappings() -> HashMap<Channels, AudioChannels> {
+        HashMap::from([
+            (
+                Channels::from_bits_truncate(0x0000_0001),
+                AudioChannels::FrontLeft,
+            ),
+            (
+                Channels::from_bits_truncate(0x0000_0002),
+                AudioChannels::FrontRight,
+            ),
+            (
+                Channels::from_bits_truncate(0x0000_0004),
+                AudioChannels::FrontCentre,
+            ),
+            (
+                Channels::from_bits_truncate(0x0000_0008),
+                AudioChannels::LFE1,
+            ),
+            (
+                Channels::from_bits_truncate(0x0000_0010),
+                AudioChannels::RearLeft,
+            ),
+            (
+                Channels::from_bits_truncate(0x0000_0020),
+                AudioChannels::RearRight,
+            ),
+            (
+                Channels::from_bits_truncate(0x0000_0040),
+                AudioChannels::FrontLeftCentre,
+            ),
+            (
+                Channels::from_bits_truncate(0x0000_0080),
+                AudioChannels::FrontRightCentre,
+            ),
+            (
+                Channels::from_bits_truncate(0x0000_0100),
+                AudioChannels::RearCentre,
+            ),
+            (
+                Channels::from_bits_truncate(0x0000_0200),
+                AudioChannels::SideLeft,
+            ),
+            (
+                Channels::from_bits_truncate(0x0000_0400),
+                AudioChannels::SideRight,
+            ),
+            (
+                Channels::from_bits_truncate(0x0000_0800),
+                AudioChannels::TopCentre,
+            ),
+            (
+                Channels::from_bits_truncate(0x0000_1000),
+                AudioChannels::TopFrontLeft,
+            ),
+            (
+                Channels::from_bits_truncate(0x0000_2000),
+                AudioChannels::TopFrontCentre,
+            ),
+            (
+                Channels::from_bits_truncate(0x0000_4000),
+                AudioChannels::TopFrontRight,
+            ),
+            (
+                Channels::from_bits_truncate(0x0000_8000),
+                AudioChannels::TopRearLeft,
+            ),
+            (
+                Channels::from_bits_truncate(0x0001_0000),
+                AudioChannels::TopRearCentre,
+            ),
+            (
+                Channels::from_bits_truncate(0x0002_0000),
+                AudioChannels::TopRearRight,
+            ),
+            (
+                Channels::from_bits_truncate(0x0004_0000),
+                AudioChannels::RearLeftCentre,
+            ),
+            (
+                Channels::from_bits_truncate(0x0008_0000),
+                AudioChannels::RearRightCentre,
+            ),
+            (
+                Channels::from_bits_truncate(0x0010_0000),
+                AudioChannels::FrontLeftWide,
+            ),
+            (
+                Channels::from_bits_truncate(0x0020_0000),
+                AudioChannels::FrontRightWide,
+            ),
+            (
+                Channels::from_bits_truncate(0x0040_0000),
+                AudioChannels::FrontLeftHigh,
+            ),
+            (
+                Channels::from_bits_truncate(0x0080_0000),
+                AudioChannels::FrontCentreHigh,
+            ),
+            (
+                Channels::from_bits_truncate(0x0100_0000),
+                AudioChannels::FrontRightHigh,
+            ),
+            (
+                Channels::from_bits_truncate(0x0200_0000),
+                AudioChannels::LFE2,
+            ),
+        ])
+    }
+
+    // Every sample format is brought to full scale i32, one plane per
+    // channel. Floating point samples outside of -1.0 to 1.0 are clipped.
+    fn get_full_scale_planes(buffer: &AudioBufferRef) -> Vec<Vec<i32>> {
+        let mut converted = buffer.make_equivalent::<i32>();
+        buffer.convert(&mut converted);
+        let planes = converted.planes();
+        planes.planes().iter().map(|plane| plane.to_vec()).collect()
+    }
+
+    fn get_sample_format(buffer: &AudioBufferRef) -> SampleFormat {
+        match buffer {
+            AudioBufferRef::U8(_) => SampleFormat::U8,
+            AudioBufferRef::U16(_) => SampleFormat::U16,
+            AudioBufferRef::U24(_) => SampleFormat::U24,
+            AudioBufferRef::U32(_) => SampleFormat::U32,
+            AudioBufferRef::S8(_) => SampleFormat::S8,
+            AudioBufferRef::S16(_) => SampleFormat::S16,
+            AudioBufferRef::S24(_) => SampleFormat::S24,
+            AudioBufferRef::S32(_) => SampleFormat::S32,
+            AudioBufferRef::F32(_) => SampleFormat::F32,
+            AudioBufferRef::F64(_) => SampleFormat::F64,
+        }
+    }
+
+    // Lossy decoders do not report a bit depth, for these the depth of the
+    // decoded samples is used instead. Floating point output carries about
+    // 24 bits of precision.
+    fn get_effective_bit_depth(sample_format: SampleFormat) -> u32 {
+        match sample_format {
+            SampleFormat::U8 | SampleFormat::S8 => 8,
+            SampleFormat::U16 | SampleFormat::S16 => 16,
+            SampleFormat::U24 | SampleFormat::S24 => 24,
+            SampleFormat::U32 | SampleFormat::S32 => 32,
+            SampleFormat::F32 | SampleFormat::F64 => 24,
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::{collections::HashMap, fs};
+
+        use symphonia::core::{
+            audio::{AsAudioBufferRef, AudioBuffer, Channels, Signal, SignalSpec},
+            sample::{i24, Sample, SampleFormat},
+        };
+
+        use super::{get_full_scale_planes, AudioStream};
+        use crate::{
+            cancellation::CancellationToken,
+            encoder_decoder::{
+                encoders::pcm::wav::WavEncoder,
+                raw_audio_data::raw_audio_data::{AudioChannels, RawAudioData},
+            },
+        };
+
+        #[test]
+        fn hands_out_the_same_samples_as_the_decoded_file() {
+            let dir =
+                std::env::temp_dir().join(format!("diraudio-audio-stream-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("source.wav");
+            // long enough to be decoded in several packets
+            let left: Vec<i32> = (0..10_000)
+                .map(|i| ((i * 7919) % 65_536 - 32_768) << 16)
+                .collect();
+            let right: Vec<i32> = left.iter().rev().cloned().collect();
+            let source = RawAudioData::new(
+                HashMap::from([
+                    (AudioChannels::FrontLeft, left.clone()),
+                    (AudioChannels::FrontRight, right.clone()),
+                ]),
+                44_100,
+                16,
+                SampleFormat::S16,
+                Vec::new(),
+                None,
+                Vec::new(),
+            );
+            fs::write(&path, source.encode_to_wav().unwrap()).unwrap();
+
+            let mut stream = AudioStream::new_from_path(&path, CancellationToken::new()).unwrap();
+            let mut planes = vec![Vec::new(); stream.get_channels().len()];
+            let mut no_of_blocks = 0;
+            while let Some(block) = stream.next_block().unwrap() {
+                for (plane, mut block_plane) in planes.iter_mut().zip(block) {
+                    plane.append(&mut block_plane);
+                }
+                no_of_blocks += 1;
+            }
+            let decoded = RawAudioData::new_from_path(&path, &CancellationToken::new()).unwrap();
+
+            assert!(no_of_blocks > 1);
+            assert_eq!(stream.get_sample_rate(), decoded.get_sample_rate());
+            assert_eq!(stream.get_bit_depth(), decoded.get_bit_depth());
+            assert_eq!(planes.len(), decoded.get_audio_data().len());
+            for (channel, plane) in stream.get_channels().iter().zip(planes.iter()) {
+                assert!(plane == &decoded.get_audio_data()[channel]);
+            }
+            assert!(planes == vec![left, right]);
+            fs::remove_dir_all(&dir).unwrap();
+        }
+
+        // A single channel buffer holding `samples`, converted like a decoded
+        // packet.
+        fn get_converted<S: Sample>(samples: &[S]) -> Vec<i32>
+        where
+            AudioBuffer<S>: AsAudioBufferRef,
+        {
+            let spec = SignalSpec::new(44_100, Channels::FRONT_LEFT);
+            let mut buffer = AudioBuffer::<S>::new(samples.len() as u64, spec);
+            buffer.render_reserved(Some(samples.len()));
+            buffer.chan_mut(0).copy_from_slice(samples);
+            get_full_scale_planes(&buffer.as_audio_buffer_ref()).remove(0)
+        }
+
+        #[test]
+        fn brings_integer_samples_to_full_scale() {
+            // unsigned samples are centred on half of their range
+            assert_eq!(get_converted(&[0u8, 128, 255]), [i32::MIN, 0, 127 << 24]);
+            assert_eq!(
+                get_converted(&[i16::MIN, -1, 0, 1, i16::MAX]),
+                [i32::MIN, -1 << 16, 0, 1 << 16, (i16::MAX as i32) << 16]
+            );
+            assert_eq!(
+                get_converted(&[
+                    i24::from(-8_388_608),
+                    i24::from(-1),
+                    i24::from(1),
+                    i24::from(8_388_607)
+                ]),
+                [i32::MIN, -1 << 8, 1 << 8, 8_388_607 << 8]
+            );
+        }
+
+        #[test]
+        fn clips_floating_point_samples_at_full_scale() {
+            let expected = [i32::MIN, i32::MIN, -1 << 30, 0, 1 << 30, i32::MAX, i32::MAX];
+            assert_eq!(
+                get_converted(&[-2.0f32, -1.0, -0.5, 0.0, 0.5, 1.0, 2.0]),
+                expected
+            );
+            assert_eq!(
+                get_converted(&[-2.0f64, -1.0, -0.5, 0.0, 0.5, 1.0, 2.0]),
+                expected
+            );
+        }
+    }
+}
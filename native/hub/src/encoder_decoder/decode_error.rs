@@ -0,0 +1,61 @@
+pub mod decode_error {
+    use std::fmt;
+
+    /// Reasons for which a file could not be turned into `RawAudioData`.
+    #[derive(Debug)]
+    pub enum DecodeError {
+        /// The file could not be opened or read.
+        Io(std::io::Error),
+        /// No container reader recognised the file.
+        UnsupportedFormat(String),
+        /// The container does not hold any audio track.
+        NoAudioTrack,
+        /// There is no decoder for the codec of the audio track.
+        UnsupportedCodec(String),
+        /// The audio track does not declare a sample rate.
+        MissingSampleRate,
+        /// The audio track uses a channel that can not be mapped.
+        UnknownChannelLayout,
+        /// The container itself is damaged, `offset` is the timestamp (in
+        /// frames) of the last packet that could be read.
+        CorruptPacket { offset: u64 },
+        /// Not a single packet of the audio track could be decoded.
+        NoDecodablePackets { skipped: usize },
+        /// A new stream starts within the file with another sample rate or
+        /// channel layout, like in a chained Ogg file.
+        StreamChanged,
+        /// The conversion was cancelled while decoding.
+        Cancelled,
+    }
+
+    impl fmt::Display for DecodeError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                DecodeError::Io(err) => write!(f, "could not read the file: {}", err),
+                DecodeError::UnsupportedFormat(err) => write!(f, "unsupported format: {}", err),
+                DecodeError::NoAudioTrack => write!(f, "no audio track found"),
+                DecodeError::UnsupportedCodec(err) => write!(f, "unsupported codec: {}", err),
+                DecodeError::MissingSampleRate => write!(f, "the audio has no sample rate"),
+                DecodeError::UnknownChannelLayout => write!(f, "unknown channel layout"),
+                DecodeError::CorruptPacket { offset } => {
+                    write!(f, "corrupt container after timestamp {}", offset)
+                }
+                DecodeError::NoDecodablePackets { skipped } => {
+                    write!(f, "all {} packets of the audio are corrupt", skipped)
+                }
+                DecodeError::StreamChanged => {
+                    write!(f, "the sample rate or channels change within the file")
+                }
+                DecodeError::Cancelled => write!(f, "the conversion was cancelled"),
+            }
+        }
+    }
+
+    impl std::error::Error for DecodeError {}
+
+    impl From<std::io::Error> for DecodeError {
+        fn from(err: std::io::Error) -> Self {
+            DecodeError::Io(err)
+        }
+    }
+}
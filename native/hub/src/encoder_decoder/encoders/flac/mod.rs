@@ -0,0 +1,463 @@
+use crate::messages::dart_signal::FlacConfig;
+
+pub mod flac {
+    use std::io::{Seek, SeekFrom, Write};
+
+    use flacenc::{
+        bitsink::ByteSink,
+        component::{BitRepr, StreamInfo},
+        error::Verify,
+        source::{Fill, FrameBuf},
+    };
+    use md5::{Digest, Md5};
+    use symphonia::core::{
+        meta::{Tag, Visual},
+        sample::SampleFormat,
+    };
+
+    use crate::{
+        encoder_decoder::{
+            audio_stream::audio_stream::AudioStream,
+            encoders::vorbis_comment::vorbis_comment::{
+                get_comment_header, get_picture_block, get_vorbis_comments,
+            },
+            raw_audio_data::raw_audio_data::RawAudioData,
+        },
+        messages::dart_signal::FlacConfig,
+    };
+
+    use super::get_correct_encoder_config;
+
+    const BLOCK_TYPE_STREAMINFO: u8 = 0;
+    const BLOCK_TYPE_VORBIS_COMMENT: u8 = 4;
+    const BLOCK_TYPE_PICTURE: u8 = 6;
+    const LAST_BLOCK_FLAG: u8 = 0x80;
+    // "fLaC" + metadata block header
+    const STREAMINFO_OFFSET: usize = 8;
+    const STREAMINFO_MD5_OFFSET: usize = STREAMINFO_OFFSET + 18;
+    const STREAMINFO_SIZE: usize = 34;
+
+    pub trait FlacEncoder {
+        fn encode_to_flac(&self, config: &FlacConfig) -> Result<Vec<u8>, String>;
+    }
+
+    pub trait FlacStreamEncoder {
+        /// Decodes and encodes the audio frame by frame, writing the FLAC
+        /// stream to `writer` as it is produced. The STREAMINFO block is
+        /// filled in once the end of the audio is reached.
+        fn encode_to_flac_writer<W: Write + Seek>(
+            &mut self,
+            config: &FlacConfig,
+            writer: &mut W,
+        ) -> Result<(), String>;
+    }
+
+    impl FlacEncoder for RawAudioData {
+        fn encode_to_flac(&self, config: &FlacConfig) -> Result<Vec<u8>, String> {
+            // readying the input, samples are stored at full 32 bit scale
+            // so they have to be brought back to the output bit depth
+            let bits_per_sample =
+                get_flac_bit_depth(self.get_bit_depth(), self.get_sample_format())?;
+            let shift = 32 - bits_per_sample;
+            let channels = self.get_ordered_channels();
+            if channels.is_empty() || channels.len() > 8 {
+                return Err(format!(
+                    "FLAC can not hold {} channels of audio.",
+                    channels.len()
+                ));
+            }
+            let no_of_samples = channels[0].1.len();
+            let mut interleaved: Vec<i32> = Vec::with_capacity(no_of_samples * channels.len());
+            for i in 0..no_of_samples {
+                for (_, plane) in channels.iter() {
+                    interleaved.push(plane[i] >> shift);
+                }
+            }
+
+            // encoding the input data
+            let encoder_config = match get_correct_encoder_config(config).into_verified() {
+                Ok(encoder_config) => encoder_config,
+                Err((_, err)) => return Err(err.to_string()),
+            };
+            let source = flacenc::source::MemSource::from_samples(
+                &interleaved,
+                channels.len(),
+                bits_per_sample as usize,
+                self.get_sample_rate() as usize,
+            );
+            let flac_stream = match flacenc::encode_with_fixed_block_size(
+                &encoder_config,
+                source,
+                encoder_config.block_size,
+            ) {
+                Ok(stream) => stream,
+                Err(err) => return Err(format!("{:?}", err)),
+            };
+            let mut sink = flacenc::bitsink::ByteSink::new();
+            if let Err(err) = flac_stream.write(&mut sink) {
+                return Err(err.to_string());
+            }
+            let mut flac_out = sink.as_slice().to_vec();
+
+            // STREAMINFO md5 of the unencoded samples
+            let bytes_per_sample = bits_per_sample.div_ceil(8) as usize;
+            let mut hasher = Md5::new();
+            for sample in interleaved.iter() {
+                hasher.update(&sample.to_le_bytes()[..bytes_per_sample]);
+            }
+            flac_out[STREAMINFO_MD5_OFFSET..STREAMINFO_MD5_OFFSET + 16]
+                .copy_from_slice(&hasher.finalize());
+
+            // adding tags and album art
+            insert_metadata_blocks(
+                flac_out,
+                get_metadata_blocks(self.get_tags(), self.get_album_art()),
+            )
+        }
+    }
+
+    impl FlacStreamEncoder for AudioStream {
+        fn encode_to_flac_writer<W: Write + Seek>(
+            &mut self,
+            config: &FlacConfig,
+            writer: &mut W,
+        ) -> Result<(), String> {
+            let bits_per_sample =
+                get_flac_bit_depth(self.get_bit_depth(), self.get_sample_format())?;
+            let shift = 32 - bits_per_sample;
+            let positions = self.get_ordered_positions();
+            let channel_count = positions.len();
+            if channel_count > 8 {
+                return Err(format!(
+                    "FLAC can not hold {} channels of audio.",
+                    channel_count
+                ));
+            }
+            let encoder_config = match get_correct_encoder_config(config).into_verified() {
+                Ok(encoder_config) => encoder_config,
+                Err((_, err)) => return Err(err.to_string()),
+            };
+            let block_size = encoder_config.block_size;
+            let mut stream_info = match StreamInfo::new(
+                self.get_sample_rate() as usize,
+                channel_count,
+                bits_per_sample as usize,
+            ) {
+                Ok(stream_info) => stream_info,
+                Err(err) => return Err(err.to_string()),
+            };
+
+            // the STREAMINFO block is left empty until the end of the audio,
+            // the tags and album art follow it
+            let mut header = b"fLaC".to_vec();
+            header.push(BLOCK_TYPE_STREAMINFO);
+            header.extend_from_slice(&(STREAMINFO_SIZE as u32).to_be_bytes()[1..]);
+            header.extend_from_slice(&[0u8; STREAMINFO_SIZE]);
+            header.append(&mut get_metadata_block_bytes(get_metadata_blocks(
+                self.get_tags(),
+                self.get_album_art(),
+            ))?);
+            write_out(writer, &header)?;
+
+            // decoded packets are gathered until a whole frame can be
+            // encoded, only the last frame is shorter
+            let mut framebuf = match FrameBuf::with_size(channel_count, block_size) {
+                Ok(framebuf) => framebuf,
+                Err(err) => return Err(err.to_string()),
+            };
+            let mut sink = ByteSink::new();
+            let mut hasher = Md5::new();
+            let bytes_per_sample = bits_per_sample.div_ceil(8) as usize;
+            let mut pending: Vec<i32> = Vec::with_capacity(block_size * channel_count);
+            let mut frame_number = 0;
+            let mut no_of_samples = 0;
+            let mut end_of_stream = false;
+            while !end_of_stream {
+                match self.next_block() {
+                    Ok(Some(block)) => {
+                        let planes: Vec<&Vec<i32>> =
+                            positions.iter().map(|position| &block[*position]).collect();
+                        for i in 0..planes[0].len() {
+                            for plane in planes.iter() {
+                                pending.push(plane[i] >> shift);
+                            }
+                        }
+                    }
+                    Ok(None) => end_of_stream = true,
+                    Err(err) => return Err(err.to_string()),
+                }
+                while pending.len() >= block_size * channel_count
+                    || (end_of_stream && !pending.is_empty())
+                {
+                    let end = usize::min(block_size * channel_count, pending.len());
+                    let frame_len = end / channel_count;
+                    if frame_len != framebuf.size() {
+                        framebuf.resize(frame_len);
+                    }
+                    if let Err(err) = framebuf.fill_interleaved(&pending[..end]) {
+                        return Err(err.to_string());
+                    }
+                    // STREAMINFO md5 of the unencoded samples
+                    for sample in pending[..end].iter() {
+                        hasher.update(&sample.to_le_bytes()[..bytes_per_sample]);
+                    }
+                    let frame = match flacenc::encode_fixed_size_frame(
+                        &encoder_config,
+                        &framebuf,
+                        frame_number,
+                        &stream_info,
+                    ) {
+                        Ok(frame) => frame,
+                        Err(err) => return Err(format!("{:?}", err)),
+                    };
+                    stream_info.update_frame_info(&frame);
+                    sink.clear();
+                    if let Err(err) = frame.write(&mut sink) {
+                        return Err(err.to_string());
+                    }
+                    write_out(writer, sink.as_slice())?;
+                    pending.drain(..end);
+                    frame_number += 1;
+                    no_of_samples += frame_len;
+                }
+            }
+
+            // a shorter last frame does not count for the block sizes
+            if let Err(err) = stream_info.set_block_sizes(block_size, block_size) {
+                return Err(err.to_string());
+            }
+            stream_info.set_total_samples(no_of_samples);
+            stream_info.set_md5_digest(&hasher.finalize().into());
+            sink.clear();
+            if let Err(err) = stream_info.write(&mut sink) {
+                return Err(err.to_string());
+            }
+            let patched = writer
+                .seek(SeekFrom::Start(STREAMINFO_OFFSET as u64))
+                .and_then(|_| writer.write_all(sink.as_slice()))
+                .and_then(|_| writer.seek(SeekFrom::End(0)));
+            match patched {
+                Ok(_) => Ok(()),
+                Err(err) => Err(err.to_string()),
+            }
+        }
+    }
+
+    // flacenc supports up to 24 bits. Floating point sources carry about 24
+    // bits of precision, deeper integer sources would lose theirs.
+    fn get_flac_bit_depth(
+        bits_per_sample: u32,
+        sample_format: SampleFormat,
+    ) -> Result<u32, String> {
+        match (bits_per_sample, sample_format) {
+            (_, SampleFormat::F32 | SampleFormat::F64) => Ok(24),
+            (0, _) => Ok(16),
+            (bits, _) if bits > 24 => Err(format!(
+                "FLAC can not hold {} bit audio without losing precision, at most 24 bits are supported.",
+                bits
+            )),
+            (bits, _) => Ok(bits),
+        }
+    }
+
+    fn get_metadata_blocks(tags: &[Tag], visuals: &[Visual]) -> Vec<(u8, Vec<u8>)> {
+        let mut blocks = vec![(
+            BLOCK_TYPE_VORBIS_COMMENT,
+            get_comment_header(&get_vorbis_comments(tags)),
+        )];
+        for visual in visuals {
+            blocks.push((BLOCK_TYPE_PICTURE, get_picture_block(visual)));
+        }
+        blocks
+    }
+
+    fn write_out<W: Write>(writer: &mut W, bytes: &[u8]) -> Result<(), String> {
+        match writer.write_all(bytes) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    // Appends the given blocks after the metadata already written by the
+    // encoder, moving the last-metadata-block flag onto the final one.
+    fn insert_metadata_blocks(
+        flac: Vec<u8>,
+        blocks: Vec<(u8, Vec<u8>)>,
+    ) -> Result<Vec<u8>, String> {
+        if flac.len() < 4 || &flac[..4] != b"fLaC" {
+            return Err("Encoder output is not a FLAC stream".to_string());
+        }
+
+        let mut output =
+            Vec::with_capacity(flac.len() + blocks.iter().map(|b| b.1.len() + 4).sum::<usize>());
+        output.extend_from_slice(b"fLaC");
+        let mut position = 4;
+        loop {
+            if position + 4 > flac.len() {
+                return Err("Encoder output has truncated metadata".to_string());
+            }
+            let is_last = flac[position] & LAST_BLOCK_FLAG != 0;
+            let length = u32::from_be_bytes([
+                0,
+                flac[position + 1],
+                flac[position + 2],
+                flac[position + 3],
+            ]) as usize;
+            if position + 4 + length > flac.len() {
+                return Err("Encoder output has truncated metadata".to_string());
+            }
+            output.push(flac[position] & !LAST_BLOCK_FLAG);
+            output.extend_from_slice(&flac[position + 1..position + 4 + length]);
+            position += 4 + length;
+            if is_last {
+                break;
+            }
+        }
+
+        output.append(&mut get_metadata_block_bytes(blocks)?);
+        output.extend_from_slice(&flac[position..]);
+        Ok(output)
+    }
+
+    // Serialises the blocks, the last one carrying the last-metadata-block
+    // flag.
+    fn get_metadata_block_bytes(blocks: Vec<(u8, Vec<u8>)>) -> Result<Vec<u8>, String> {
+        let mut output = Vec::new();
+        let no_of_blocks = blocks.len();
+        for (i, (block_type, block)) in blocks.into_iter().enumerate() {
+            if block.len() >= 1 << 24 {
+                return Err("Metadata block is too large for FLAC".to_string());
+            }
+            let flag = if i + 1 == no_of_blocks {
+                LAST_BLOCK_FLAG
+            } else {
+                0
+            };
+            output.push(block_type | flag);
+            output.extend_from_slice(&(block.len() as u32).to_be_bytes()[1..]);
+            output.extend_from_slice(&block);
+        }
+        Ok(output)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::{collections::HashMap, fs, io::Cursor};
+
+        use symphonia::core::sample::SampleFormat;
+
+        use super::{FlacEncoder, FlacStreamEncoder, STREAMINFO_MD5_OFFSET};
+        use crate::{
+            cancellation::CancellationToken,
+            encoder_decoder::{
+                audio_stream::audio_stream::AudioStream,
+                encoders::pcm::wav::WavEncoder,
+                raw_audio_data::raw_audio_data::{AudioChannels, RawAudioData},
+            },
+            messages::dart_signal::FlacConfig,
+        };
+
+        #[test]
+        fn streams_a_flac_that_decodes_to_the_source_samples() {
+            let dir =
+                std::env::temp_dir().join(format!("diraudio-flac-stream-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            // not a multiple of the block size, so the last frame is shorter
+            let left: Vec<i32> = (0..10_000)
+                .map(|i| ((i * 7919) % 65_536 - 32_768) << 16)
+                .collect();
+            let right: Vec<i32> = left.iter().rev().cloned().collect();
+            let source = RawAudioData::new(
+                HashMap::from([
+                    (AudioChannels::FrontLeft, left.clone()),
+                    (AudioChannels::FrontRight, right.clone()),
+                ]),
+                44_100,
+                16,
+                SampleFormat::S16,
+                Vec::new(),
+                None,
+                Vec::new(),
+            );
+            let source_path = dir.join("source.wav");
+            fs::write(&source_path, source.encode_to_wav().unwrap()).unwrap();
+            let config = FlacConfig {
+                compression_level: 5,
+            };
+
+            let mut streamed = Cursor::new(Vec::new());
+            AudioStream::new_from_path(&source_path, CancellationToken::new())
+                .unwrap()
+                .encode_to_flac_writer(&config, &mut streamed)
+                .unwrap();
+            let streamed = streamed.into_inner();
+            let in_memory = RawAudioData::new_from_path(&source_path, &CancellationToken::new())
+                .unwrap()
+                .encode_to_flac(&config)
+                .unwrap();
+            let flac_path = dir.join("output.flac");
+            fs::write(&flac_path, &streamed).unwrap();
+            let decoded =
+                RawAudioData::new_from_path(&flac_path, &CancellationToken::new()).unwrap();
+
+            assert_eq!(
+                streamed[STREAMINFO_MD5_OFFSET..STREAMINFO_MD5_OFFSET + 16],
+                in_memory[STREAMINFO_MD5_OFFSET..STREAMINFO_MD5_OFFSET + 16]
+            );
+            assert_eq!(decoded.get_bit_depth(), 16);
+            assert!(decoded.get_audio_data()[&AudioChannels::FrontLeft] == left);
+            assert!(decoded.get_audio_data()[&AudioChannels::FrontRight] == right);
+            fs::remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn refuses_integer_sources_deeper_than_24_bits() {
+            let source = |bits_per_sample, sample_format| {
+                RawAudioData::new(
+                    HashMap::from([(AudioChannels::FrontCentre, vec![0, i32::MAX, i32::MIN])]),
+                    44_100,
+                    bits_per_sample,
+                    sample_format,
+                    Vec::new(),
+                    None,
+                    Vec::new(),
+                )
+            };
+            let config = FlacConfig {
+                compression_level: 5,
+            };
+
+            assert!(source(32, SampleFormat::S32)
+                .encode_to_flac(&config)
+                .is_err());
+            // floating point sources are converted to 24 bits
+            assert!(source(32, SampleFormat::F32)
+                .encode_to_flac(&config)
+                .is_ok());
+            assert!(source(24, SampleFormat::S24)
+                .encode_to_flac(&config)
+                .is_ok());
+        }
+    }
+}
+
+// Approximates the libFLAC compression presets (0 to 8) with the
+// options exposed by flacenc.
+fn get_correct_encoder_config(config: &FlacConfig) -> flacenc::config::Encoder {
+    let level = config.compression_level.clamp(0, 8);
+    let mut encoder_config = flacenc::config::Encoder::default();
+    encoder_config.block_size = if level <= 2 { 1152 } else { 4096 };
+    encoder_config.stereo_coding.use_leftside = level >= 1;
+    encoder_config.stereo_coding.use_rightside = level >= 1;
+    encoder_config.stereo_coding.use_midside = level >= 1;
+    encoder_config.subframe_coding.use_lpc = level >= 3;
+    if level >= 3 {
+        encoder_config.subframe_coding.qlpc.lpc_order = match level {
+            3 => 6,
+            4..=6 => 8,
+            _ => 12,
+        };
+    }
+    encoder_config
+}
@@ -0,0 +1,740 @@
+pub mod id3 {
+    use symphonia::core::meta::{StandardTagKey, StandardVisualKey, Tag, Value, Visual};
+
+    use crate::messages::dart_signal::Id3Version;
+
+    // ID3v2 writer, used for the MP3 output and the ID3 chunks of the PCM
+    // containers. ID3v2.4 text is written as UTF-8, ID3v2.3 has no UTF-8 so
+    // its text is Latin-1 when it fits and UTF-16 otherwise. Either way
+    // nothing has to be cut short.
+
+    const TEXT_ENCODING_LATIN1: u8 = 0;
+    const TEXT_ENCODING_UTF16: u8 = 1;
+    const TEXT_ENCODING_UTF8: u8 = 3;
+    // Owner of the UFID frame holding the MusicBrainz recording id.
+    const MUSICBRAINZ_OWNER: &str = "http://musicbrainz.org";
+    const ID3V1_TAG_SIZE: usize = 128;
+    // Genre byte of an ID3v1 tag without a genre.
+    const ID3V1_NO_GENRE: u8 = 255;
+    // Text frames of ID3v2.4 and the ones of ID3v2.3 it kept. Left out are
+    // the user defined TXXX and the ID3v2.3 frames ID3v2.4 replaced (TYER,
+    // TDAT, TIME, TORY, TRDA and TSIZ).
+    const TEXT_FRAME_IDS: [&str; 45] = [
+        "TALB", "TBPM", "TCOM", "TCON", "TCOP", "TDEN", "TDLY", "TDOR", "TDRC", "TDRL", "TDTG",
+        "TENC", "TEXT", "TFLT", "TIPL", "TIT1", "TIT2", "TIT3", "TKEY", "TLAN", "TLEN", "TMCL",
+        "TMED", "TMOO", "TOAL", "TOFN", "TOLY", "TOPE", "TOWN", "TPE1", "TPE2", "TPE3", "TPE4",
+        "TPOS", "TPRO", "TPUB", "TRCK", "TRSN", "TRSO", "TSOA", "TSOP", "TSOT", "TSRC", "TSSE",
+        "TSST",
+    ];
+
+    // Where the value of a tag ends up in the ID3v2 tag.
+    #[derive(PartialEq)]
+    enum Field {
+        Text([u8; 4]),
+        UserText(String),
+        InvolvedPerson(&'static str),
+        Musician,
+        Url([u8; 4]),
+        UserUrl(String),
+        Comment,
+        Lyrics,
+        RecordingId,
+    }
+
+    /// Builds a complete ID3v2 tag (header included) out of the tags of the
+    /// audio and every picture in `visuals`. Returns an empty vector if there
+    /// is nothing to write.
+    pub fn get_id3v2_tag(tags: &[Tag], visuals: &[Visual], version: Id3Version) -> Vec<u8> {
+        let track_total = get_value(tags, StandardTagKey::TrackTotal);
+        let disc_total = get_value(tags, StandardTagKey::DiscTotal);
+
+        // a frame is allowed only once, values of the same frame are
+        // gathered and written as one frame with several values
+        let mut fields: Vec<(Field, Vec<String>)> = Vec::new();
+        for tag in tags {
+            if let Value::Binary(_) = tag.value {
+                continue;
+            }
+            let mut value = tag.value.to_string();
+            if value.is_empty() {
+                continue;
+            }
+            let field = match tag.std_key {
+                // the totals are part of the number frames
+                Some(StandardTagKey::TrackTotal)
+                    if has_value(tags, StandardTagKey::TrackNumber) =>
+                {
+                    continue
+                }
+                Some(StandardTagKey::DiscTotal) if has_value(tags, StandardTagKey::DiscNumber) => {
+                    continue
+                }
+                // the band goes to TPE2 unless that already holds the album
+                // artist
+                Some(StandardTagKey::Ensemble) if has_value(tags, StandardTagKey::AlbumArtist) => {
+                    Field::UserText("ENSEMBLE".to_string())
+                }
+                Some(StandardTagKey::TrackNumber) => {
+                    value = get_position(value, &track_total);
+                    Field::Text(*b"TRCK")
+                }
+                Some(StandardTagKey::DiscNumber) => {
+                    value = get_position(value, &disc_total);
+                    Field::Text(*b"TPOS")
+                }
+                Some(std_key) => get_field(std_key, &tag.key),
+                None => get_unknown_field(&tag.key),
+            };
+            let (field, value) = match version {
+                Id3Version::Id3v24 => (field, value),
+                Id3Version::Id3v23 => get_v23_field(field, value),
+            };
+            match fields.iter_mut().find(|(existing, _)| *existing == field) {
+                Some((_, values)) => {
+                    if !values.contains(&value) {
+                        values.push(value);
+                    }
+                }
+                None => fields.push((field, vec![value])),
+            }
+        }
+
+        let mut writer = FrameWriter {
+            version,
+            frames: Vec::new(),
+        };
+        let mut involved_people = Vec::new();
+        let mut musicians = Vec::new();
+        for (field, values) in fields {
+            match field {
+                Field::Text(frame_id) => writer.text_frame(&frame_id, &values),
+                Field::UserText(description) => writer.user_text_frame(&description, &values),
+                Field::InvolvedPerson(role) => {
+                    for value in values {
+                        involved_people.push(role.to_string());
+                        involved_people.push(value);
+                    }
+                }
+                Field::Musician => {
+                    for value in values {
+                        let (instrument, name) = get_musician_credit(&value);
+                        musicians.push(instrument);
+                        musicians.push(name);
+                    }
+                }
+                // only WOAR and WCOM may be there more than once
+                Field::Url(frame_id) if &frame_id == b"WOAR" || &frame_id == b"WCOM" => {
+                    for value in values {
+                        writer.url_frame(&frame_id, &value);
+                    }
+                }
+                Field::Url(frame_id) => writer.url_frame(&frame_id, &values[0]),
+                Field::UserUrl(description) => writer.user_url_frame(&description, &values[0]),
+                Field::Comment => writer.comment_frame(b"COMM", &values.join("\n")),
+                Field::Lyrics => writer.comment_frame(b"USLT", &values.join("\n")),
+                Field::RecordingId => writer.unique_file_id_frame(&values[0]),
+            }
+        }
+        // pairs of instrument and name, ID3v2.3 keeps them with the other
+        // people involved
+        match version {
+            Id3Version::Id3v24 if !musicians.is_empty() => writer.text_frame(b"TMCL", &musicians),
+            Id3Version::Id3v24 => {}
+            Id3Version::Id3v23 => involved_people.append(&mut musicians),
+        }
+        if !involved_people.is_empty() {
+            // pairs of role and name, which ID3v2.3 has a frame of its own for
+            match version {
+                Id3Version::Id3v24 => writer.text_frame(b"TIPL", &involved_people),
+                Id3Version::Id3v23 => writer.string_list_frame(b"IPLS", &involved_people),
+            }
+        }
+        // the description of a picture has to be unique
+        let mut descriptions: Vec<String> = Vec::new();
+        for visual in visuals {
+            let mut description = get_picture_description(visual);
+            let mut number = 1;
+            while descriptions.contains(&description) {
+                number += 1;
+                description = format!("{} ({})", get_picture_description(visual), number)
+                    .trim_start()
+                    .to_string();
+            }
+            writer.picture_frame(visual, &description);
+            descriptions.push(description);
+        }
+        if writer.frames.is_empty() {
+            return Vec::new();
+        }
+
+        let mut tag = Vec::with_capacity(writer.frames.len() + 10);
+        tag.extend_from_slice(b"ID3");
+        let major_version = match version {
+            Id3Version::Id3v24 => 4,
+            Id3Version::Id3v23 => 3,
+        };
+        // no revision and no flags
+        tag.extend_from_slice(&[major_version, 0, 0]);
+        // the size of the whole tag is synchsafe in both versions
+        tag.extend_from_slice(&synchsafe(writer.frames.len() as u32));
+        tag.append(&mut writer.frames);
+        tag
+    }
+
+    /// Builds an ID3v1.1 tag, which goes at the very end of the file. Its
+    /// fields are Latin-1 and of a fixed size, so text is cut short to fit
+    /// and characters outside Latin-1 are replaced. Returns an empty vector
+    /// if there is nothing to write.
+    pub fn get_id3v1_tag(tags: &[Tag]) -> Vec<u8> {
+        let title = get_value(tags, StandardTagKey::TrackTitle);
+        let artist = get_value(tags, StandardTagKey::Artist);
+        let album = get_value(tags, StandardTagKey::Album);
+        let year = get_value(tags, StandardTagKey::Date);
+        let comment = get_value(tags, StandardTagKey::Comment);
+        let track = get_value(tags, StandardTagKey::TrackNumber);
+        let genre = get_value(tags, StandardTagKey::Genre);
+        if [&title, &artist, &album, &year, &comment, &track, &genre]
+            .iter()
+            .all(|value| value.is_none())
+        {
+            return Vec::new();
+        }
+
+        let mut tag = Vec::with_capacity(ID3V1_TAG_SIZE);
+        tag.extend_from_slice(b"TAG");
+        push_fixed_field(&mut tag, &title, 30);
+        push_fixed_field(&mut tag, &artist, 30);
+        push_fixed_field(&mut tag, &album, 30);
+        push_fixed_field(&mut tag, &year, 4);
+        push_fixed_field(&mut tag, &comment, 28);
+        // a zero byte in front of the track number marks ID3v1.1
+        tag.push(0);
+        tag.push(
+            track
+                .and_then(|track| track.split('/').next()?.trim().parse::<u8>().ok())
+                .unwrap_or(0),
+        );
+        // only genres given by their number, like `17` or `(17)`, are known
+        tag.push(
+            genre
+                .and_then(|genre| genre.trim_matches(['(', ')', ' ']).parse::<u8>().ok())
+                .unwrap_or(ID3V1_NO_GENRE),
+        );
+        tag
+    }
+
+    /// Picture types as defined by the ID3v2 APIC frame, which FLAC reuses.
+    pub fn get_picture_type(usage: Option<StandardVisualKey>) -> u8 {
+        match usage {
+            None => 0,
+            Some(StandardVisualKey::FileIcon) => 1,
+            Some(StandardVisualKey::OtherIcon) => 2,
+            Some(StandardVisualKey::FrontCover) => 3,
+            Some(StandardVisualKey::BackCover) => 4,
+            Some(StandardVisualKey::Leaflet) => 5,
+            Some(StandardVisualKey::Media) => 6,
+            Some(StandardVisualKey::LeadArtistPerformerSoloist) => 7,
+            Some(StandardVisualKey::ArtistPerformer) => 8,
+            Some(StandardVisualKey::Conductor) => 9,
+            Some(StandardVisualKey::BandOrchestra) => 10,
+            Some(StandardVisualKey::Composer) => 11,
+            Some(StandardVisualKey::Lyricist) => 12,
+            Some(StandardVisualKey::RecordingLocation) => 13,
+            Some(StandardVisualKey::RecordingSession) => 14,
+            Some(StandardVisualKey::Performance) => 15,
+            Some(StandardVisualKey::ScreenCapture) => 16,
+            Some(StandardVisualKey::Illustration) => 18,
+            Some(StandardVisualKey::BandArtistLogo) => 19,
+            Some(StandardVisualKey::PublisherStudioLogo) => 20,
+        }
+    }
+
+    pub fn get_picture_description(visual: &Visual) -> String {
+        visual
+            .tags
+            .iter()
+            .find(|tag| tag.key.eq_ignore_ascii_case("description"))
+            .map(|tag| tag.value.to_string())
+            .unwrap_or_default()
+    }
+
+    fn get_field(std_key: StandardTagKey, key: &str) -> Field {
+        match std_key {
+            StandardTagKey::TrackTitle => Field::Text(*b"TIT2"),
+            StandardTagKey::TrackSubtitle => Field::Text(*b"TIT3"),
+            StandardTagKey::ContentGroup => Field::Text(*b"TIT1"),
+            StandardTagKey::Artist => Field::Text(*b"TPE1"),
+            StandardTagKey::AlbumArtist => Field::Text(*b"TPE2"),
+            StandardTagKey::Conductor => Field::Text(*b"TPE3"),
+            StandardTagKey::Remixer => Field::Text(*b"TPE4"),
+            StandardTagKey::Album => Field::Text(*b"TALB"),
+            StandardTagKey::DiscSubtitle => Field::Text(*b"TSST"),
+            StandardTagKey::Date => Field::Text(*b"TDRC"),
+            StandardTagKey::OriginalDate => Field::Text(*b"TDOR"),
+            StandardTagKey::ReleaseDate => Field::Text(*b"TDRL"),
+            StandardTagKey::EncodingDate => Field::Text(*b"TDEN"),
+            StandardTagKey::TaggingDate => Field::Text(*b"TDTG"),
+            StandardTagKey::Genre => Field::Text(*b"TCON"),
+            StandardTagKey::Mood => Field::Text(*b"TMOO"),
+            StandardTagKey::Composer => Field::Text(*b"TCOM"),
+            StandardTagKey::Lyricist | StandardTagKey::Writer => Field::Text(*b"TEXT"),
+            StandardTagKey::Ensemble => Field::Text(*b"TPE2"),
+            StandardTagKey::OriginalAlbum => Field::Text(*b"TOAL"),
+            StandardTagKey::OriginalArtist => Field::Text(*b"TOPE"),
+            StandardTagKey::OriginalWriter => Field::Text(*b"TOLY"),
+            StandardTagKey::OriginalFile => Field::Text(*b"TOFN"),
+            StandardTagKey::Label => Field::Text(*b"TPUB"),
+            StandardTagKey::Copyright => Field::Text(*b"TCOP"),
+            StandardTagKey::Owner => Field::Text(*b"TOWN"),
+            StandardTagKey::EncodedBy => Field::Text(*b"TENC"),
+            StandardTagKey::Encoder => Field::Text(*b"TSSE"),
+            StandardTagKey::Bpm => Field::Text(*b"TBPM"),
+            StandardTagKey::IdentIsrc => Field::Text(*b"TSRC"),
+            StandardTagKey::Language => Field::Text(*b"TLAN"),
+            StandardTagKey::MediaFormat => Field::Text(*b"TMED"),
+            StandardTagKey::Compilation => Field::Text(*b"TCMP"),
+            StandardTagKey::MovementName => Field::Text(*b"MVNM"),
+            StandardTagKey::MovementNumber => Field::Text(*b"MVIN"),
+            StandardTagKey::SortTrackTitle => Field::Text(*b"TSOT"),
+            StandardTagKey::SortArtist => Field::Text(*b"TSOP"),
+            StandardTagKey::SortAlbum => Field::Text(*b"TSOA"),
+            StandardTagKey::SortAlbumArtist => Field::Text(*b"TSO2"),
+            StandardTagKey::SortComposer => Field::Text(*b"TSOC"),
+            StandardTagKey::Arranger => Field::InvolvedPerson("arranger"),
+            StandardTagKey::Engineer => Field::InvolvedPerson("engineer"),
+            StandardTagKey::Producer => Field::InvolvedPerson("producer"),
+            StandardTagKey::MixDj => Field::InvolvedPerson("DJ-mix"),
+            StandardTagKey::MixEngineer => Field::InvolvedPerson("mix"),
+            StandardTagKey::Performer => Field::Musician,
+            StandardTagKey::UrlArtist => Field::Url(*b"WOAR"),
+            StandardTagKey::UrlCopyright => Field::Url(*b"WCOP"),
+            StandardTagKey::UrlInternetRadio => Field::Url(*b"WORS"),
+            StandardTagKey::UrlLabel => Field::Url(*b"WPUB"),
+            StandardTagKey::UrlOfficial => Field::Url(*b"WOAF"),
+            StandardTagKey::UrlPayment => Field::Url(*b"WPAY"),
+            StandardTagKey::UrlPurchase => Field::Url(*b"WCOM"),
+            StandardTagKey::UrlSource => Field::Url(*b"WOAS"),
+            // the iTunes podcast frame, which is a text frame despite its name
+            StandardTagKey::UrlPodcast => Field::Text(*b"WFED"),
+            StandardTagKey::Url => {
+                Field::UserUrl(key.strip_prefix("WXXX:").unwrap_or_default().to_string())
+            }
+            StandardTagKey::Comment => Field::Comment,
+            StandardTagKey::Lyrics => Field::Lyrics,
+            StandardTagKey::MusicBrainzRecordingId | StandardTagKey::MusicBrainzTrackId => {
+                Field::RecordingId
+            }
+            // the descriptions MusicBrainz Picard uses, so other players
+            // and taggers find them
+            std_key => match get_user_text_description(std_key) {
+                Some(description) => Field::UserText(description.to_string()),
+                None => get_unknown_field(key),
+            },
+        }
+    }
+
+    fn get_user_text_description(std_key: StandardTagKey) -> Option<&'static str> {
+        match std_key {
+            StandardTagKey::MusicBrainzAlbumId => Some("MusicBrainz Album Id"),
+            StandardTagKey::MusicBrainzArtistId => Some("MusicBrainz Artist Id"),
+            StandardTagKey::MusicBrainzAlbumArtistId => Some("MusicBrainz Album Artist Id"),
+            StandardTagKey::MusicBrainzReleaseGroupId => Some("MusicBrainz Release Group Id"),
+            StandardTagKey::MusicBrainzReleaseTrackId => Some("MusicBrainz Release Track Id"),
+            StandardTagKey::MusicBrainzWorkId => Some("MusicBrainz Work Id"),
+            StandardTagKey::MusicBrainzDiscId => Some("MusicBrainz Disc Id"),
+            StandardTagKey::MusicBrainzLabelId => Some("MusicBrainz Label Id"),
+            StandardTagKey::MusicBrainzOriginalAlbumId => Some("MusicBrainz Original Album Id"),
+            StandardTagKey::MusicBrainzOriginalArtistId => Some("MusicBrainz Original Artist Id"),
+            StandardTagKey::MusicBrainzReleaseStatus => Some("MusicBrainz Album Status"),
+            StandardTagKey::MusicBrainzReleaseType => Some("MusicBrainz Album Type"),
+            StandardTagKey::ReleaseCountry => Some("MusicBrainz Album Release Country"),
+            StandardTagKey::AcoustidId => Some("Acoustid Id"),
+            StandardTagKey::AcoustidFingerprint => Some("Acoustid Fingerprint"),
+            StandardTagKey::IdentBarcode => Some("BARCODE"),
+            StandardTagKey::IdentCatalogNumber => Some("CATALOGNUMBER"),
+            StandardTagKey::IdentAsin => Some("ASIN"),
+            StandardTagKey::Script => Some("SCRIPT"),
+            StandardTagKey::ReplayGainTrackGain => Some("REPLAYGAIN_TRACK_GAIN"),
+            StandardTagKey::ReplayGainTrackPeak => Some("REPLAYGAIN_TRACK_PEAK"),
+            StandardTagKey::ReplayGainAlbumGain => Some("REPLAYGAIN_ALBUM_GAIN"),
+            StandardTagKey::ReplayGainAlbumPeak => Some("REPLAYGAIN_ALBUM_PEAK"),
+            _ => None,
+        }
+    }
+
+    // Tags without a standard meaning keep the key they were stored with,
+    // text frames of an ID3 source are written back as they were.
+    fn get_unknown_field(key: &str) -> Field {
+        if let Some(frame_id) = get_text_frame_id(key) {
+            return Field::Text(frame_id);
+        }
+        let description = key.strip_prefix("TXXX:").unwrap_or(key);
+        Field::UserText(description.to_string())
+    }
+
+    // Only keys that are a known text frame are written as one, anything
+    // else that merely looks like a frame id goes to TXXX.
+    fn get_text_frame_id(key: &str) -> Option<[u8; 4]> {
+        if !TEXT_FRAME_IDS.contains(&key) {
+            return None;
+        }
+        key.as_bytes().try_into().ok()
+    }
+
+    // ID3v2.3 has no timestamp frames and fewer text frames, the frames
+    // ID3v2.4 added are kept as TXXX.
+    fn get_v23_field(field: Field, value: String) -> (Field, String) {
+        let frame_id = match field {
+            Field::Text(frame_id) => frame_id,
+            field => return (field, value),
+        };
+        match &frame_id {
+            b"TDRC" => (Field::Text(*b"TYER"), value.chars().take(4).collect()),
+            b"TDOR" => (Field::Text(*b"TORY"), value.chars().take(4).collect()),
+            b"TDRL" => (Field::UserText("RELEASETIME".to_string()), value),
+            b"TDEN" => (Field::UserText("ENCODINGTIME".to_string()), value),
+            b"TDTG" => (Field::UserText("TAGGINGTIME".to_string()), value),
+            b"TMOO" => (Field::UserText("MOOD".to_string()), value),
+            b"TSST" => (Field::UserText("DISCSUBTITLE".to_string()), value),
+            b"TPRO" => (Field::UserText("PRODUCEDNOTICE".to_string()), value),
+            _ => (Field::Text(frame_id), value),
+        }
+    }
+
+    fn get_value(tags: &[Tag], std_key: StandardTagKey) -> Option<String> {
+        tags.iter()
+            .find(|tag| tag.std_key == Some(std_key))
+            .map(|tag| tag.value.to_string())
+    }
+
+    fn has_value(tags: &[Tag], std_key: StandardTagKey) -> bool {
+        tags.iter().any(|tag| tag.std_key == Some(std_key))
+    }
+
+    // Performers are written like `Name (instrument)` in Vorbis comments,
+    // a performer without an instrument is credited as such.
+    fn get_musician_credit(value: &str) -> (String, String) {
+        if let Some((name, instrument)) = value
+            .strip_suffix(')')
+            .and_then(|value| value.rsplit_once(" ("))
+        {
+            if !name.trim().is_empty() && !instrument.trim().is_empty() {
+                return (instrument.trim().to_string(), name.trim().to_string());
+            }
+        }
+        ("performer".to_string(), value.to_string())
+    }
+
+    // TRCK and TPOS hold the total as well, like `3/12`.
+    fn get_position(number: String, total: &Option<String>) -> String {
+        match total {
+            Some(total) if !number.contains('/') && !total.is_empty() => {
+                format!("{}/{}", number, total)
+            }
+            _ => number,
+        }
+    }
+
+    fn push_fixed_field(tag: &mut Vec<u8>, value: &Option<String>, size: usize) {
+        let mut field: Vec<u8> = value
+            .as_deref()
+            .unwrap_or_default()
+            .chars()
+            .map(|c| u8::try_from(c).unwrap_or(b'?'))
+            .take(size)
+            .collect();
+        field.resize(size, 0);
+        tag.append(&mut field);
+    }
+
+    struct FrameWriter {
+        version: Id3Version,
+        frames: Vec<u8>,
+    }
+
+    impl FrameWriter {
+        // Several values of one frame are separated by a null character in
+        // ID3v2.4 and by a slash in ID3v2.3.
+        fn text_frame(&mut self, frame_id: &[u8; 4], values: &[String]) {
+            let separator = match self.version {
+                Id3Version::Id3v24 => "\0",
+                Id3Version::Id3v23 => "/",
+            };
+            let value = values.join(separator);
+            let encoding = self.get_encoding(&[&value]);
+            let mut body = vec![encoding];
+            push_text(&mut body, encoding, &value);
+            self.frame(frame_id, body);
+        }
+
+        // A list of strings that each end with a terminator.
+        fn string_list_frame(&mut self, frame_id: &[u8; 4], values: &[String]) {
+            let texts: Vec<&str> = values.iter().map(|value| value.as_str()).collect();
+            let encoding = self.get_encoding(&texts);
+            let mut body = vec![encoding];
+            for text in texts {
+                push_text(&mut body, encoding, text);
+                push_terminator(&mut body, encoding);
+            }
+            self.frame(frame_id, body);
+        }
+
+        fn user_text_frame(&mut self, description: &str, values: &[String]) {
+            // ID3v2.3 does not have several values here either
+            let value = match self.version {
+                Id3Version::Id3v24 => values.join("\0"),
+                Id3Version::Id3v23 => values.join("/"),
+            };
+            let encoding = self.get_encoding(&[description, &value]);
+            let mut body = vec![encoding];
+            push_text(&mut body, encoding, description);
+            push_terminator(&mut body, encoding);
+            push_text(&mut body, encoding, &value);
+            self.frame(b"TXXX", body);
+        }
+
+        // URLs are always Latin-1 and have no encoding byte.
+        fn url_frame(&mut self, frame_id: &[u8; 4], url: &str) {
+            let mut body = Vec::new();
+            push_text(&mut body, TEXT_ENCODING_LATIN1, url);
+            self.frame(frame_id, body);
+        }
+
+        fn user_url_frame(&mut self, description: &str, url: &str) {
+            let encoding = self.get_encoding(&[description]);
+            let mut body = vec![encoding];
+            push_text(&mut body, encoding, description);
+            push_terminator(&mut body, encoding);
+            push_text(&mut body, TEXT_ENCODING_LATIN1, url);
+            self.frame(b"WXXX", body);
+        }
+
+        // COMM and USLT share the same layout.
+        fn comment_frame(&mut self, frame_id: &[u8; 4], value: &str) {
+            let encoding = self.get_encoding(&[value]);
+            let mut body = vec![encoding];
+            body.extend_from_slice(b"eng");
+            // empty description
+            push_text(&mut body, encoding, "");
+            push_terminator(&mut body, encoding);
+            push_text(&mut body, encoding, value);
+            self.frame(frame_id, body);
+        }
+
+        fn unique_file_id_frame(&mut self, id: &str) {
+            let mut body = MUSICBRAINZ_OWNER.as_bytes().to_vec();
+            body.push(0);
+            body.extend_from_slice(id.as_bytes());
+            self.frame(b"UFID", body);
+        }
+
+        fn picture_frame(&mut self, visual: &Visual, description: &str) {
+            let encoding = self.get_encoding(&[description]);
+            let mut body = vec![encoding];
+            // the media type is always Latin-1
+            body.extend_from_slice(visual.media_type.as_bytes());
+            body.push(0);
+            // a picture without a usage is taken as the cover
+            body.push(match visual.usage {
+                None => 3,
+                usage => get_picture_type(usage),
+            });
+            push_text(&mut body, encoding, description);
+            push_terminator(&mut body, encoding);
+            body.extend_from_slice(&visual.data);
+            self.frame(b"APIC", body);
+        }
+
+        fn frame(&mut self, frame_id: &[u8; 4], mut body: Vec<u8>) {
+            self.frames.extend_from_slice(frame_id);
+            let size = match self.version {
+                Id3Version::Id3v24 => synchsafe(body.len() as u32),
+                Id3Version::Id3v23 => (body.len() as u32).to_be_bytes(),
+            };
+            self.frames.extend_from_slice(&size);
+            // no frame flags
+            self.frames.extend_from_slice(&[0, 0]);
+            self.frames.append(&mut body);
+        }
+
+        fn get_encoding(&self, texts: &[&str]) -> u8 {
+            match self.version {
+                Id3Version::Id3v24 => TEXT_ENCODING_UTF8,
+                Id3Version::Id3v23 => {
+                    match texts
+                        .iter()
+                        .all(|text| text.chars().all(|c| (c as u32) < 0x100))
+                    {
+                        true => TEXT_ENCODING_LATIN1,
+                        false => TEXT_ENCODING_UTF16,
+                    }
+                }
+            }
+        }
+    }
+
+    fn push_text(body: &mut Vec<u8>, encoding: u8, text: &str) {
+        match encoding {
+            TEXT_ENCODING_LATIN1 => {
+                body.extend(text.chars().map(|c| u8::try_from(c).unwrap_or(b'?')))
+            }
+            TEXT_ENCODING_UTF16 => {
+                // little endian with a byte order mark
+                body.extend_from_slice(&[0xff, 0xfe]);
+                for unit in text.encode_utf16() {
+                    body.extend_from_slice(&unit.to_le_bytes());
+                }
+            }
+            _ => body.extend_from_slice(text.as_bytes()),
+        }
+    }
+
+    fn push_terminator(body: &mut Vec<u8>, encoding: u8) {
+        match encoding {
+            TEXT_ENCODING_UTF16 => body.extend_from_slice(&[0, 0]),
+            _ => body.push(0),
+        }
+    }
+
+    fn synchsafe(value: u32) -> [u8; 4] {
+        [
+            ((value >> 21) & 0x7f) as u8,
+            ((value >> 14) & 0x7f) as u8,
+            ((value >> 7) & 0x7f) as u8,
+            (value & 0x7f) as u8,
+        ]
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use symphonia::core::meta::{StandardTagKey, Tag, Value};
+
+        use super::get_id3v2_tag;
+        use crate::messages::dart_signal::Id3Version;
+
+        fn tag(std_key: StandardTagKey, key: &str, value: &str) -> Tag {
+            Tag::new(Some(std_key), key, Value::from(value))
+        }
+
+        // The raw size bytes and the body of a frame.
+        fn get_frame<'a>(id3_tag: &'a [u8], frame_id: &[u8; 4]) -> Option<(&'a [u8], &'a [u8])> {
+            let mut frames = &id3_tag[10..];
+            while frames.len() >= 10 {
+                let size_bytes = &frames[4..8];
+                let size = match id3_tag[3] {
+                    4 => size_bytes
+                        .iter()
+                        .fold(0, |size, byte| (size << 7) | *byte as usize),
+                    _ => u32::from_be_bytes(size_bytes.try_into().unwrap()) as usize,
+                };
+                if &frames[..4] == frame_id {
+                    return Some((size_bytes, &frames[10..10 + size]));
+                }
+                frames = &frames[10 + size..];
+            }
+            None
+        }
+
+        #[test]
+        fn writes_the_header_and_a_text_frame() {
+            let tags = vec![tag(StandardTagKey::TrackTitle, "TITLE", "Song")];
+            let id3_tag = get_id3v2_tag(&tags, &[], Id3Version::Id3v24);
+            let mut expected = b"ID3\x04\x00\x00\x00\x00\x00\x0f".to_vec();
+            expected.extend_from_slice(b"TIT2\x00\x00\x00\x05\x00\x00\x03Song");
+            assert_eq!(id3_tag, expected);
+
+            // Latin-1 in ID3v2.3
+            let id3_tag = get_id3v2_tag(&tags, &[], Id3Version::Id3v23);
+            assert_eq!(&id3_tag[..10], b"ID3\x03\x00\x00\x00\x00\x00\x0f");
+            assert_eq!(&id3_tag[10..], b"TIT2\x00\x00\x00\x05\x00\x00\x00Song");
+        }
+
+        #[test]
+        fn only_id3v24_frame_sizes_are_synchsafe() {
+            let title = "a".repeat(200);
+            let tags = vec![tag(StandardTagKey::TrackTitle, "TITLE", &title)];
+
+            // 201 bytes of body and 211 bytes of frames
+            let id3_tag = get_id3v2_tag(&tags, &[], Id3Version::Id3v24);
+            assert_eq!(&id3_tag[6..10], [0, 0, 1, 0x53]);
+            assert_eq!(&id3_tag[14..18], [0, 0, 1, 0x49]);
+
+            let id3_tag = get_id3v2_tag(&tags, &[], Id3Version::Id3v23);
+            assert_eq!(&id3_tag[6..10], [0, 0, 1, 0x53]);
+            assert_eq!(&id3_tag[14..18], [0, 0, 0, 0xc9]);
+            assert_eq!(get_frame(&id3_tag, b"TIT2").unwrap().1.len(), 201);
+        }
+
+        #[test]
+        fn writes_urls_to_url_frames() {
+            let tags = vec![
+                tag(StandardTagKey::UrlArtist, "WOAR", "http://artist"),
+                tag(StandardTagKey::UrlLabel, "WPUB", "http://label"),
+                tag(StandardTagKey::Url, "WXXX:shop", "http://shop"),
+            ];
+            let id3_tag = get_id3v2_tag(&tags, &[], Id3Version::Id3v24);
+            // no encoding byte in front of the URL
+            assert_eq!(
+                get_frame(&id3_tag, b"WOAR"),
+                Some((&[0, 0, 0, 13][..], &b"http://artist"[..]))
+            );
+            assert_eq!(get_frame(&id3_tag, b"WPUB").unwrap().1, b"http://label");
+            assert_eq!(
+                get_frame(&id3_tag, b"WXXX").unwrap().1,
+                b"\x03shop\x00http://shop"
+            );
+            assert_eq!(get_frame(&id3_tag, b"TXXX"), None);
+        }
+
+        #[test]
+        fn credits_performers_writers_and_ensembles() {
+            let tags = vec![
+                tag(StandardTagKey::Performer, "PERFORMER", "Ann (piano)"),
+                tag(StandardTagKey::Performer, "PERFORMER", "Bob"),
+                tag(StandardTagKey::Writer, "WRITER", "Cid"),
+                tag(StandardTagKey::Ensemble, "ENSEMBLE", "Band"),
+            ];
+            let id3_tag = get_id3v2_tag(&tags, &[], Id3Version::Id3v24);
+            assert_eq!(
+                get_frame(&id3_tag, b"TMCL").unwrap().1,
+                b"\x03piano\x00Ann\x00performer\x00Bob"
+            );
+            assert_eq!(get_frame(&id3_tag, b"TEXT").unwrap().1, b"\x03Cid");
+            assert_eq!(get_frame(&id3_tag, b"TPE2").unwrap().1, b"\x03Band");
+
+            // ID3v2.3 has no TMCL
+            let id3_tag = get_id3v2_tag(&tags, &[], Id3Version::Id3v23);
+            assert_eq!(get_frame(&id3_tag, b"TMCL"), None);
+            assert_eq!(
+                get_frame(&id3_tag, b"IPLS").unwrap().1,
+                b"\x00piano\x00Ann\x00performer\x00Bob\x00"
+            );
+        }
+
+        #[test]
+        fn keeps_the_album_artist_in_tpe2() {
+            let tags = vec![
+                tag(StandardTagKey::AlbumArtist, "ALBUMARTIST", "Singer"),
+                tag(StandardTagKey::Ensemble, "ENSEMBLE", "Band"),
+            ];
+            let id3_tag = get_id3v2_tag(&tags, &[], Id3Version::Id3v24);
+            assert_eq!(get_frame(&id3_tag, b"TPE2").unwrap().1, b"\x03Singer");
+            assert_eq!(
+                get_frame(&id3_tag, b"TXXX").unwrap().1,
+                b"\x03ENSEMBLE\x00Band"
+            );
+        }
+
+        #[test]
+        fn writes_only_known_text_frames_as_they_are() {
+            let tags = vec![
+                Tag::new(None, "TKEY", Value::from("Am")),
+                Tag::new(None, "TYPE", Value::from("Live")),
+            ];
+            let id3_tag = get_id3v2_tag(&tags, &[], Id3Version::Id3v24);
+            assert_eq!(get_frame(&id3_tag, b"TKEY").unwrap().1, b"\x03Am");
+            assert!(get_frame(&id3_tag, b"TYPE").is_none());
+            assert_eq!(get_frame(&id3_tag, b"TXXX").unwrap().1, b"\x03TYPE\x00Live");
+        }
+    }
+}
@@ -1 +1,7 @@
+pub mod flac;
+pub mod id3;
 pub mod mp3;
+pub mod opus;
+pub mod pcm;
+pub mod vorbis;
+pub mod vorbis_comment;
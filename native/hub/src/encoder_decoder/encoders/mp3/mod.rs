@@ -1,193 +1,227 @@
 use crate::messages::dart_signal::{Mp3Bitrate, Mp3Quality};
 
 pub mod mp3 {
-    use std::collections::HashMap;
+    use std::io::Write;
 
-    use mp3lame_encoder::{Builder, DualPcm, Encoder, FlushNoGap, Id3Tag, MonoPcm};
+    use mp3lame_encoder::{Builder, DualPcm, Encoder, EncoderInput, FlushNoGap, MonoPcm};
+    use symphonia::core::sample::SampleFormat;
 
     use crate::{
-        encoder_decoder::raw_audio_data::raw_audio_data::{AudioChannels, RawAudioData},
-        messages::dart_signal::{Mp3Bitrate, Mp3Quality},
+        encoder_decoder::{
+            audio_stream::audio_stream::AudioStream,
+            encoders::id3::id3::{get_id3v1_tag, get_id3v2_tag},
+            raw_audio_data::raw_audio_data::RawAudioData,
+        },
+        messages::dart_signal::Mp3Config,
     };
 
     use super::{get_correct_bitrate, get_correct_quality};
 
+    // Number of frames handed to LAME at once, a multiple of the 1152
+    // samples of an MPEG-1 Layer III frame.
+    const BLOCK_SIZE: usize = 1152 * 32;
+
     pub trait Mp3Encoder {
-        fn encode_to_mp3(
-            &self,
-            quality: Mp3Quality,
-            bitrate: Mp3Bitrate,
-        ) -> Result<Vec<u8>, String>;
+        fn encode_to_mp3(&self, config: &Mp3Config) -> Result<Vec<u8>, String>;
+    }
+
+    pub trait Mp3StreamEncoder {
+        /// Decodes and encodes the audio block by block, writing the MP3
+        /// stream to `writer` as it is produced.
+        fn encode_to_mp3_writer(
+            &mut self,
+            config: &Mp3Config,
+            writer: &mut dyn Write,
+        ) -> Result<(), String>;
     }
 
     impl Mp3Encoder for RawAudioData {
-        fn encode_to_mp3(
-            &self,
-            quality: Mp3Quality,
-            bitrate: Mp3Bitrate,
-        ) -> Result<Vec<u8>, String> {
+        fn encode_to_mp3(&self, config: &Mp3Config) -> Result<Vec<u8>, String> {
             // TODO
             // 1. find a way to set the album art in the output mp3 file                                        -- done
             // 2. manage and map the channels in the raw audio data to the mp3 file                             -- actually done now, managing 1 or 2 channels
-            // 3. try and make options to add more tags which are not atcually exposed by mp3lame_encoder
-
-            // readying the encoder
-            let quality = get_correct_quality(quality);
-            let bitrate = get_correct_bitrate(bitrate);
-
-            let mut mp3_encoder = Builder::new().expect("Create LAME builder");
-            mp3_encoder
-                .set_num_channels(self.get_no_of_channels())
-                .expect("Setting number of channels");
-            mp3_encoder
-                .set_sample_rate(self.get_sample_rate())
-                .expect("Setting sample rate");
-            mp3_encoder.set_brate(bitrate).expect("Setting bitrate");
-            mp3_encoder.set_quality(quality).expect("Setting quality");
-            let tags_iter = self.get_tags().iter();
-            let mut tags_map = HashMap::new();
-            for tag in tags_iter {
-                let key = match tag.std_key {
-                    Some(key) => key,
-                    None => {
-                        // println!("{}", tag.key);
-                        symphonia::core::meta::StandardTagKey::Comment
-                    }
-                };
-                tags_map.insert(key, tag.value.clone());
-            }
-
-            let title = match tags_map.get(&symphonia::core::meta::StandardTagKey::TrackTitle) {
-                Some(value) => value.to_string().into_bytes(),
-                None => "".to_string().into_bytes(),
-            };
-
-            let artist = match tags_map.get(&symphonia::core::meta::StandardTagKey::Artist) {
-                Some(value) => value.to_string().into_bytes(),
-                None => "".to_string().into_bytes(),
-            };
-
-            let album = match tags_map.get(&symphonia::core::meta::StandardTagKey::Album) {
-                Some(value) => value.to_string().into_bytes(),
-                None => "".to_string().into_bytes(),
-            };
-
-            let year = match tags_map.get(&symphonia::core::meta::StandardTagKey::Date) {
-                Some(value) => value.to_string().into_bytes(),
-                None => "".to_string().into_bytes(),
-            };
-
-            let comment = match tags_map.get(&symphonia::core::meta::StandardTagKey::Comment) {
-                Some(value) => value.to_string().into_bytes(),
-                None => "".to_string().into_bytes(),
-            };
+            // 3. try and make options to add more tags which are not atcually exposed by mp3lame_encoder  -- done, the ID3v2.4 tag is written by us
 
-            match mp3_encoder.set_id3_tag(Id3Tag {
-                title: title.as_slice(),
-                artist: artist.as_slice(),
-                album: album.as_slice(),
-                year: year.as_slice(),
-                comment: comment.as_slice(),
-                album_art: match self.get_album_art() {
-                    Some(image) => &image.data,
+            let planes: Vec<&Vec<i32>> = if self.get_no_of_channels() == 1 {
+                match self.get_audio_data().values().next() {
+                    Some(data) => vec![data],
                     None => {
-                        let empty_image: &[u8] = &[];
-                        empty_image
+                        return Err(
+                            "Could not find any channels in the mono audio file.".to_string()
+                        )
                     }
-                },
-            }) {
-                Ok(_) => (),
-                Err(err) => return Err(format!("{:?}", err)),
-            };
-
-            let mp3_encoder = match mp3_encoder.build() {
-                Ok(encoder) => encoder,
-                Err(err) => return Err(err.to_string()),
+                }
+            } else {
+                let (left, right) = self.get_stereo_pair()?;
+                vec![left, right]
             };
+            let mut mp3_encoder =
+                get_mp3_encoder(planes.len() as u8, self.get_sample_rate(), config)?;
+
+            // the tag goes in front of the encoded audio
+            let mut mp3_out_buffer = get_id3v2_tag(
+                self.get_tags(),
+                self.get_album_art().as_slice(),
+                config.id3_version(),
+            );
+            let no_of_samples = planes[0].len();
+            let mut start = 0;
+            while start < no_of_samples {
+                let end = usize::min(start + BLOCK_SIZE, no_of_samples);
+                let block: Vec<&[i32]> = planes.iter().map(|plane| &plane[start..end]).collect();
+                encode_block(
+                    &mut mp3_encoder,
+                    self.get_sample_format(),
+                    &block,
+                    &mut mp3_out_buffer,
+                )?;
+                start = end;
+            }
+            flush(&mut mp3_encoder, &mut mp3_out_buffer)?;
+            if config.write_id3v1 {
+                mp3_out_buffer.append(&mut get_id3v1_tag(self.get_tags()));
+            }
+            Ok(mp3_out_buffer)
+        }
+    }
 
-            // encoding the input data
-
-            if self.get_no_of_channels() == 1 {
-                return encode_one_channel_input(self.get_audio_data(), mp3_encoder);
+    impl Mp3StreamEncoder for AudioStream {
+        fn encode_to_mp3_writer(
+            &mut self,
+            config: &Mp3Config,
+            writer: &mut dyn Write,
+        ) -> Result<(), String> {
+            let positions = if self.get_no_of_channels() == 1 {
+                vec![0]
             } else {
-                return encode_two_channel_input(self.get_audio_data(), mp3_encoder);
+                let (left, right) = self.get_stereo_pair_positions()?;
+                vec![left, right]
+            };
+            let mut mp3_encoder =
+                get_mp3_encoder(positions.len() as u8, self.get_sample_rate(), config)?;
+            let sample_format = self.get_sample_format();
+
+            // decoded packets are gathered until a whole block can be
+            // encoded, the output is written out after every block, the
+            // first time with the tag in front of it
+            let mut mp3_out_buffer = get_id3v2_tag(
+                self.get_tags(),
+                self.get_album_art().as_slice(),
+                config.id3_version(),
+            );
+            let mut pending: Vec<Vec<i32>> = vec![Vec::with_capacity(BLOCK_SIZE); positions.len()];
+            let mut end_of_stream = false;
+            while !end_of_stream {
+                match self.next_block() {
+                    Ok(Some(mut block)) => {
+                        for (plane, position) in pending.iter_mut().zip(positions.iter()) {
+                            plane.append(&mut block[*position]);
+                        }
+                    }
+                    Ok(None) => end_of_stream = true,
+                    Err(err) => return Err(err.to_string()),
+                }
+                while pending[0].len() >= BLOCK_SIZE || (end_of_stream && !pending[0].is_empty()) {
+                    let end = usize::min(BLOCK_SIZE, pending[0].len());
+                    let block: Vec<&[i32]> = pending.iter().map(|plane| &plane[..end]).collect();
+                    encode_block(&mut mp3_encoder, sample_format, &block, &mut mp3_out_buffer)?;
+                    for plane in pending.iter_mut() {
+                        plane.drain(..end);
+                    }
+                    write_out(writer, &mut mp3_out_buffer)?;
+                }
             }
+            flush(&mut mp3_encoder, &mut mp3_out_buffer)?;
+            if config.write_id3v1 {
+                mp3_out_buffer.append(&mut get_id3v1_tag(self.get_tags()));
+            }
+            write_out(writer, &mut mp3_out_buffer)
         }
     }
 
-    fn encode_two_channel_input(
-        audio_data: &HashMap<AudioChannels, Vec<i32>>,
-        mut mp3_encoder: Encoder,
-    ) -> Result<Vec<u8>, String> {
-        let left = match audio_data.get(&AudioChannels::FrontLeft) {
-            Some(data) => data,
-            None => match audio_data.get(&AudioChannels::SideLeft) {
-                Some(data) => data,
-                None => match audio_data.get(&AudioChannels::RearLeft) {
-                    Some(data) => data,
-                    None => return Err("Could not find appropriate left channel".to_string()),
-                },
-            },
-        };
-
-        let right = match audio_data.get(&AudioChannels::FrontRight) {
-            Some(data) => data,
-            None => match audio_data.get(&AudioChannels::SideRight) {
-                Some(data) => data,
-                None => match audio_data.get(&AudioChannels::RearRight) {
-                    Some(data) => data,
-                    None => return Err("Could not find appropriate right channel".to_string()),
-                },
-            },
-        };
-
-        let input = DualPcm { left, right };
-        let len = input.left.len();
-        // On setting the maximum image size to 16MB the
-        // encoder was sometime throwing error that the output
-        // buffer is not big enough for the encoded
-        // data, this solved the problem.
-        let len = len + (16 * 1024 * 1024);
-
-        let mut mp3_out_buffer = Vec::new();
-        mp3_out_buffer.reserve(mp3lame_encoder::max_required_buffer_size(len));
-        let encoded_size = match mp3_encoder.encode(input, mp3_out_buffer.spare_capacity_mut()) {
-            Ok(size) => size,
-
-            Err(err) => return Err(err.to_string()),
-        };
-
-        unsafe {
-            mp3_out_buffer.set_len(mp3_out_buffer.len().wrapping_add(encoded_size));
+    fn get_mp3_encoder(
+        no_of_channels: u8,
+        sample_rate: u32,
+        config: &Mp3Config,
+    ) -> Result<Encoder, String> {
+        // readying the encoder
+        let quality = get_correct_quality(config.quality());
+        let bitrate = get_correct_bitrate(config.bitrate());
+
+        let mut mp3_encoder = Builder::new().expect("Create LAME builder");
+        mp3_encoder
+            .set_num_channels(no_of_channels)
+            .expect("Setting number of channels");
+        mp3_encoder
+            .set_sample_rate(sample_rate)
+            .expect("Setting sample rate");
+        mp3_encoder.set_brate(bitrate).expect("Setting bitrate");
+        mp3_encoder.set_quality(quality).expect("Setting quality");
+        // the tags are written by us, LAME cuts text short at 250 bytes and
+        // can only write a single picture
+        mp3_encoder.set_write_id3_tag_automatic(false);
+        match mp3_encoder.build() {
+            Ok(encoder) => Ok(encoder),
+            Err(err) => Err(err.to_string()),
         }
+    }
 
-        let encoded_size =
-            match mp3_encoder.flush::<FlushNoGap>(mp3_out_buffer.spare_capacity_mut()) {
-                Ok(size) => size,
-                Err(err) => return Err(err.to_string()),
-            };
-
-        unsafe {
-            mp3_out_buffer.set_len(mp3_out_buffer.len().wrapping_add(encoded_size));
+    // LAME is fed with the input type closest to the sample format of the
+    // source.
+    fn encode_block(
+        mp3_encoder: &mut Encoder,
+        sample_format: SampleFormat,
+        planes: &[&[i32]],
+        mp3_out_buffer: &mut Vec<u8>,
+    ) -> Result<(), String> {
+        match sample_format {
+            SampleFormat::U8 | SampleFormat::S8 | SampleFormat::U16 | SampleFormat::S16 => {
+                let planes: Vec<Vec<i16>> =
+                    planes.iter().map(|plane| to_i16_plane(plane)).collect();
+                encode_planes::<i16, _>(mp3_encoder, &planes, mp3_out_buffer)
+            }
+            SampleFormat::F32 | SampleFormat::F64 => {
+                let planes: Vec<Vec<f32>> =
+                    planes.iter().map(|plane| to_f32_plane(plane)).collect();
+                encode_planes::<f32, _>(mp3_encoder, &planes, mp3_out_buffer)
+            }
+            _ => encode_planes::<i32, _>(mp3_encoder, planes, mp3_out_buffer),
         }
-
-        return Ok(mp3_out_buffer);
     }
 
-    fn encode_one_channel_input(
-        audio_data: &HashMap<AudioChannels, Vec<i32>>,
-        mut mp3_encoder: Encoder,
-    ) -> Result<Vec<u8>, String> {
-        let input = match audio_data.iter().next() {
-            Some(data) => MonoPcm(data.1),
-            None => return Err("Could not find any channels in the mono audio file.".to_string()),
-        };
+    fn to_i16_plane(plane: &[i32]) -> Vec<i16> {
+        plane.iter().map(|sample| (*sample >> 16) as i16).collect()
+    }
 
-        let len = input.0.len();
+    fn to_f32_plane(plane: &[i32]) -> Vec<f32> {
+        plane
+            .iter()
+            .map(|sample| *sample as f32 / 2_147_483_648.0)
+            .collect()
+    }
 
-        let mut mp3_out_buffer = Vec::new();
+    fn encode_planes<T, P: AsRef<[T]>>(
+        mp3_encoder: &mut Encoder,
+        planes: &[P],
+        mp3_out_buffer: &mut Vec<u8>,
+    ) -> Result<(), String>
+    where
+        for<'a> MonoPcm<'a, T>: EncoderInput,
+        for<'a> DualPcm<'a, T>: EncoderInput,
+    {
+        let len = planes[0].as_ref().len();
         mp3_out_buffer.reserve(mp3lame_encoder::max_required_buffer_size(len));
-        let encoded_size = match mp3_encoder.encode(input, mp3_out_buffer.spare_capacity_mut()) {
+        let encoded = if planes.len() == 1 {
+            let input = MonoPcm(planes[0].as_ref());
+            mp3_encoder.encode(input, mp3_out_buffer.spare_capacity_mut())
+        } else {
+            let input = DualPcm {
+                left: planes[0].as_ref(),
+                right: planes[1].as_ref(),
+            };
+            mp3_encoder.encode(input, mp3_out_buffer.spare_capacity_mut())
+        };
+        let encoded_size = match encoded {
             Ok(size) => size,
             Err(err) => return Err(err.to_string()),
         };
@@ -195,7 +229,11 @@ pub mod mp3 {
         unsafe {
             mp3_out_buffer.set_len(mp3_out_buffer.len().wrapping_add(encoded_size));
         }
+        Ok(())
+    }
 
+    fn flush(mp3_encoder: &mut Encoder, mp3_out_buffer: &mut Vec<u8>) -> Result<(), String> {
+        mp3_out_buffer.reserve(mp3lame_encoder::max_required_buffer_size(0));
         let encoded_size =
             match mp3_encoder.flush::<FlushNoGap>(mp3_out_buffer.spare_capacity_mut()) {
                 Ok(size) => size,
@@ -205,8 +243,16 @@ pub mod mp3 {
         unsafe {
             mp3_out_buffer.set_len(mp3_out_buffer.len().wrapping_add(encoded_size));
         }
+        Ok(())
+    }
 
-        return Ok(mp3_out_buffer);
+    fn write_out(writer: &mut dyn Write, mp3_out_buffer: &mut Vec<u8>) -> Result<(), String> {
+        if let Err(err) = writer.write_all(mp3_out_buffer) {
+            return Err(err.to_string());
+        }
+        // the capacity is kept for the next block
+        mp3_out_buffer.clear();
+        Ok(())
     }
 }
 
@@ -225,7 +271,7 @@ fn get_correct_quality(quality: Mp3Quality) -> mp3lame_encoder::Quality {
     }
 }
 
-fn get_correct_bitrate(bitrate: Mp3Bitrate) -> mp3lame_encoder::Bitrate {
+pub(crate) fn get_correct_bitrate(bitrate: Mp3Bitrate) -> mp3lame_encoder::Bitrate {
     match bitrate {
         Mp3Bitrate::Kbps8 => mp3lame_encoder::Bitrate::Kbps8,
         Mp3Bitrate::Kbps16 => mp3lame_encoder::Bitrate::Kbps16,
@@ -49,6 +49,7 @@
 
 pub use mp3lame_sys as ffi;
 
+use core::ffi::CStr;
 use core::mem::{self, MaybeUninit};
 use core::ptr::{self, NonNull};
 use core::{cmp, fmt};
@@ -78,6 +79,14 @@ pub const fn max_required_buffer_size(sample_number: usize) -> usize {
 pub enum Id3TagError {
     ///Specified buffer exceed limit of 128kb
     AlbumArtOverflow,
+    ///Track number is outside of 1 to 255.
+    ///
+    ///The track is still written, but only to `v2`.
+    TrackOutOfV1Range,
+    ///Genre number is not part of the ID3v1 genre list
+    InvalidGenre,
+    ///Frame id is unknown or not supported, or field is not in `FRAME=value` form
+    InvalidField,
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -332,9 +341,37 @@ impl Id3Tag<'_> {
     }
 }
 
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+///ID3 genre
+pub enum Id3Genre<'a> {
+    ///Genre name, like `Rock`.
+    ///
+    ///Names that are not part of the ID3v1 genre list are only written to `v2`.
+    Name(&'a CStr),
+    ///Number of the genre in the ID3v1 genre list.
+    Number(u8),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+///Versions of ID3 tag to write
+pub enum Id3Version {
+    ///`v1` at the end, `v2` at the beginning only when fields do not fit into `v1`.
+    ///
+    ///This is LAME's default.
+    Auto,
+    ///Both `v1` and `v2`
+    Both,
+    ///Only `v1`
+    V1Only,
+    ///Only `v2`
+    V2Only,
+}
+
 ///Builder of C LAME encoder.
 pub struct Builder {
     inner: NonNull<ffi::lame_global_flags>,
+    id3_initialized: bool,
+    id3_version: Id3Version,
 }
 
 impl Builder {
@@ -348,7 +385,9 @@ impl Builder {
         };
 
         NonNull::new(ptr).map(|inner| Self {
-            inner
+            inner,
+            id3_initialized: false,
+            id3_version: Id3Version::Auto,
         })
     }
 
@@ -491,8 +530,8 @@ impl Builder {
         const MAX_BUFFER: usize = 250;
         let mut buffer = [0u8; MAX_BUFFER + 1];
 
+        self.init_id3_tag();
         unsafe {
-            ffi::id3tag_init(self.ptr());
             ffi::id3tag_add_v2(self.ptr());
 
             if !value.album_art.is_empty() {
@@ -543,11 +582,168 @@ impl Builder {
         Ok(())
     }
 
+    #[inline]
+    ///Sets whether ID3 tag is written by encoder itself: `v2` into output of first encoding call and `v1` on flush.
+    ///
+    ///Defaults to true
+    pub fn set_write_id3_tag_automatic(&mut self, value: bool) {
+        unsafe {
+            ffi::lame_set_write_id3tag_automatic(self.ptr(), value as _)
+        }
+    }
+
+    #[inline(always)]
+    //Tag data is reset by `id3tag_init`, so it is only called before the first field is set.
+    fn init_id3_tag(&mut self) {
+        if !self.id3_initialized {
+            unsafe {
+                ffi::id3tag_init(self.ptr());
+            }
+            self.id3_initialized = true;
+        }
+    }
+
+    #[inline]
+    ///Sets which versions of ID3 tag are written.
+    ///
+    ///Defaults to [Auto](Id3Version::Auto)
+    ///
+    ///Only the last version set is applied, when the encoder is built.
+    pub fn set_id3_version(&mut self, version: Id3Version) {
+        self.id3_version = version;
+    }
+
+    #[inline]
+    ///Sets number of padding bytes after `v2` tag, which also makes sure `v2` is written.
+    ///
+    ///Defaults to 128
+    pub fn set_id3_padding(&mut self, size: usize) {
+        self.init_id3_tag();
+        unsafe {
+            ffi::id3tag_set_pad(self.ptr(), size);
+        }
+    }
+
+    #[inline]
+    ///Sets track number, like `3` or `3/12` with total number of tracks.
+    ///
+    ///Total number of tracks is only written to `v2`.
+    pub fn set_id3_track(&mut self, track: &CStr) -> Result<(), Id3TagError> {
+        self.init_id3_tag();
+        let res = unsafe {
+            ffi::id3tag_set_track(self.ptr(), track.as_ptr())
+        };
+
+        match res {
+            0 => Ok(()),
+            _ => Err(Id3TagError::TrackOutOfV1Range),
+        }
+    }
+
+    #[inline]
+    ///Sets genre by its name or its number in the ID3v1 genre list.
+    pub fn set_id3_genre(&mut self, genre: Id3Genre<'_>) -> Result<(), Id3TagError> {
+        self.init_id3_tag();
+        //Longest number is 3 digits, followed by terminating zero
+        let mut buffer = [0u8; 4];
+        let genre = match genre {
+            Id3Genre::Name(name) => name.as_ptr(),
+            Id3Genre::Number(number) => {
+                let mut len = 0;
+                let mut divisor = 100;
+                while divisor > 0 {
+                    let digit = number / divisor % 10;
+                    if digit > 0 || len > 0 || divisor == 1 {
+                        buffer[len] = b'0' + digit;
+                        len += 1;
+                    }
+                    divisor /= 10;
+                }
+                buffer.as_ptr() as _
+            }
+        };
+        let res = unsafe {
+            ffi::id3tag_set_genre(self.ptr(), genre)
+        };
+
+        match res {
+            0 => Ok(()),
+            _ => Err(Id3TagError::InvalidGenre),
+        }
+    }
+
+    #[inline]
+    ///Sets arbitrary frame in `FRAME=value` form, like `TPE2=Album artist`.
+    ///
+    ///User defined frames take description first: `TXXX=description=value` or `COMM=description=value`.
+    ///
+    ///Text must be Latin-1.
+    pub fn set_id3_field(&mut self, field: &CStr) -> Result<(), Id3TagError> {
+        self.init_id3_tag();
+        let res = unsafe {
+            ffi::id3tag_set_fieldvalue(self.ptr(), field.as_ptr())
+        };
+
+        match res {
+            0 => Ok(()),
+            _ => Err(Id3TagError::InvalidField),
+        }
+    }
+
+    #[inline]
+    ///Sets text frame, like `TCOM`, to the specified text.
+    ///
+    ///Text must be Latin-1.
+    pub fn set_id3_text(&mut self, frame_id: [u8; 4], text: &CStr) -> Result<(), Id3TagError> {
+        self.init_id3_tag();
+        let mut id = [0u8; 5];
+        id[..4].copy_from_slice(&frame_id);
+        let res = unsafe {
+            ffi::id3tag_set_textinfo_latin1(self.ptr(), id.as_ptr() as _, text.as_ptr())
+        };
+
+        match res {
+            0 => Ok(()),
+            _ => Err(Id3TagError::InvalidField),
+        }
+    }
+
+    #[inline]
+    ///Adds comment with ISO-639-2 language code, like `eng`, and description.
+    ///
+    ///Text must be Latin-1.
+    pub fn set_id3_comment(&mut self, lang: [u8; 3], description: &CStr, text: &CStr) -> Result<(), Id3TagError> {
+        self.init_id3_tag();
+        let mut language = [0u8; 4];
+        language[..3].copy_from_slice(&lang);
+        let res = unsafe {
+            ffi::id3tag_set_comment_latin1(self.ptr(), language.as_ptr() as _, description.as_ptr(), text.as_ptr())
+        };
+
+        match res {
+            0 => Ok(()),
+            _ => Err(Id3TagError::InvalidField),
+        }
+    }
+
     #[inline]
     ///Attempts to initialize encoder with specified parameters.
     ///
     ///Returns `None` if parameters are invalid or incompatible.
     pub fn build(mut self) -> Result<Encoder, BuildError> {
+        //LAME can not clear these flags once set, so they are applied only here
+        if self.id3_version != Id3Version::Auto {
+            self.init_id3_tag();
+            unsafe {
+                match self.id3_version {
+                    Id3Version::Auto => (),
+                    Id3Version::Both => ffi::id3tag_add_v2(self.ptr()),
+                    Id3Version::V1Only => ffi::id3tag_v1_only(self.ptr()),
+                    Id3Version::V2Only => ffi::id3tag_v2_only(self.ptr()),
+                }
+            }
+        }
+
         let res = unsafe {
             ffi::lame_init_params(self.ptr())
         };
@@ -5,7 +5,7 @@ use symphonia::core::io::MediaSourceStream;
 use symphonia::core::probe::Hint;
 use symphonia::core::errors::Error as SymError;
 
-use mp3lame_encoder::{Builder, MonoPcm, FlushNoGap, Id3Tag, MAX_ALBUM_ART_SIZE};
+use mp3lame_encoder::{Builder, MonoPcm, FlushGap, FlushNoGap, Id3Tag, Id3Genre, Id3TagError, Id3Version, MAX_ALBUM_ART_SIZE};
 
 static ALBUM_ART: &[u8] = include_bytes!("album_art.jpg");
 
@@ -142,3 +142,106 @@ fn should_decode_and_encode() {
     }
     fs::write(NEW_FILE, &mp3_out_buffer).expect("write file")
 }
+
+#[test]
+fn should_write_extended_id3_tag() {
+    let mut mp3_encoder = Builder::new().expect("Create LAME builder");
+    mp3_encoder.set_num_channels(1).expect("set channels");
+    mp3_encoder.set_sample_rate(44_100).expect("set sample rate");
+    mp3_encoder.set_id3_version(Id3Version::V2Only);
+    mp3_encoder.set_id3_track(c"3/12").expect("set track");
+    mp3_encoder.set_id3_genre(Id3Genre::Number(17)).expect("set genre");
+    mp3_encoder.set_id3_field(c"TPE2=Album artist").expect("set field");
+    mp3_encoder.set_id3_field(c"TXXX=CATALOGNUMBER=ABC-123").expect("set user field");
+    mp3_encoder.set_id3_text(*b"TCOM", c"Composer").expect("set text");
+    mp3_encoder.set_id3_comment(*b"eng", c"", c"Just a comment").expect("set comment");
+    assert_eq!(mp3_encoder.set_id3_field(c"nonsense"), Err(Id3TagError::InvalidField));
+    let mut mp3_encoder = mp3_encoder.build().expect("To initialize LAME encoder");
+
+    //several frames, so the bit reservoir can be flushed cleanly
+    let silence = [0i16; 1152 * 8];
+    let mut mp3_out_buffer = Vec::new();
+    mp3_out_buffer.reserve(mp3lame_encoder::max_required_buffer_size(silence.len()));
+    let encoded_size = mp3_encoder.encode(MonoPcm(&silence), mp3_out_buffer.spare_capacity_mut()).expect("To encode");
+    unsafe {
+        mp3_out_buffer.set_len(mp3_out_buffer.len().wrapping_add(encoded_size));
+    }
+    //flush may output every sample still buffered by the encoder
+    mp3_out_buffer.reserve(mp3lame_encoder::max_required_buffer_size(silence.len()));
+    let encoded_size = mp3_encoder.flush::<FlushNoGap>(mp3_out_buffer.spare_capacity_mut()).expect("to flush");
+    unsafe {
+        mp3_out_buffer.set_len(mp3_out_buffer.len().wrapping_add(encoded_size));
+    }
+
+    let frames = read_id3v2_frames(&mp3_out_buffer);
+    let frame_ids: Vec<&[u8]> = frames.iter().map(|(id, _)| &id[..]).collect();
+    for frame_id in [b"TRCK", b"TCON", b"TPE2", b"TXXX", b"TCOM", b"COMM"] {
+        assert!(frame_ids.contains(&&frame_id[..]), "{} is missing", String::from_utf8_lossy(frame_id));
+    }
+    let frame_body = |frame_id: &[u8; 4]| frames.iter().find(|(id, _)| id == frame_id).map(|(_, body)| body.as_slice()).unwrap();
+    assert!(frame_body(b"TRCK").ends_with(b"3/12"));
+    assert!(frame_body(b"TPE2").ends_with(b"Album artist"));
+    assert!(frame_body(b"TCOM").ends_with(b"Composer"));
+    assert!(frame_body(b"COMM").ends_with(b"Just a comment"));
+    //v2 only, no v1 at the end
+    assert_ne!(&mp3_out_buffer[mp3_out_buffer.len() - 128..][..3], b"TAG");
+}
+
+#[test]
+fn should_reset_id3_version_to_auto() {
+    let mut mp3_encoder = Builder::new().expect("Create LAME builder");
+    mp3_encoder.set_num_channels(1).expect("set channels");
+    mp3_encoder.set_sample_rate(44_100).expect("set sample rate");
+    mp3_encoder.set_id3_version(Id3Version::V2Only);
+    mp3_encoder.set_id3_version(Id3Version::Auto);
+    mp3_encoder.set_id3_track(c"3").expect("set track");
+    let mut mp3_encoder = mp3_encoder.build().expect("To initialize LAME encoder");
+
+    let silence = [0i16; 1152 * 8];
+    let mut mp3_out_buffer = Vec::new();
+    mp3_out_buffer.reserve(mp3lame_encoder::max_required_buffer_size(silence.len()));
+    let encoded_size = mp3_encoder.encode(MonoPcm(&silence), mp3_out_buffer.spare_capacity_mut()).expect("To encode");
+    unsafe {
+        mp3_out_buffer.set_len(mp3_out_buffer.len().wrapping_add(encoded_size));
+    }
+    mp3_out_buffer.reserve(mp3lame_encoder::max_required_buffer_size(silence.len()));
+    let encoded_size = mp3_encoder.flush::<FlushGap>(mp3_out_buffer.spare_capacity_mut()).expect("to flush");
+    unsafe {
+        mp3_out_buffer.set_len(mp3_out_buffer.len().wrapping_add(encoded_size));
+    }
+
+    //track alone fits into v1, so auto writes only v1, which only the full flush appends
+    assert!(!mp3_out_buffer.starts_with(b"ID3"));
+    assert_eq!(&mp3_out_buffer[mp3_out_buffer.len() - 128..][..3], b"TAG");
+}
+
+//Walks the frames of the ID3v2 tag at the start of `data` by their sizes
+fn read_id3v2_frames(data: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+    assert!(data.starts_with(b"ID3"));
+    let major_version = data[3];
+    assert!(major_version == 3 || major_version == 4, "unexpected ID3v2.{major_version}");
+    let synchsafe = |bytes: &[u8]| bytes.iter().fold(0usize, |size, byte| {
+        assert!(byte & 0x80 == 0, "size is not synchsafe");
+        (size << 7) | *byte as usize
+    });
+    let tag_size = synchsafe(&data[6..10]);
+    let tag = &data[10..10 + tag_size];
+
+    let mut frames = Vec::new();
+    let mut position = 0;
+    //stop at the padding
+    while position + 10 <= tag.len() && tag[position] != 0 {
+        let frame_id: [u8; 4] = tag[position..position + 4].try_into().unwrap();
+        let size_bytes = &tag[position + 4..position + 8];
+        let frame_size = match major_version {
+            4 => synchsafe(size_bytes),
+            _ => u32::from_be_bytes(size_bytes.try_into().unwrap()) as usize,
+        };
+        let body_start = position + 10;
+        assert!(body_start + frame_size <= tag.len(), "{} overflows the tag", String::from_utf8_lossy(&frame_id));
+        frames.push((frame_id, tag[body_start..body_start + frame_size].to_vec()));
+        position = body_start + frame_size;
+    }
+    assert!(tag[position..].iter().all(|byte| *byte == 0), "tag does not end with padding");
+    frames
+}
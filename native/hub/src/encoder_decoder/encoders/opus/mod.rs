@@ -0,0 +1,655 @@
+use crate::messages::dart_signal::OpusApplication;
+
+pub mod opus {
+    use std::{
+        io::Write,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    use audiopus::{coder::Encoder, Channels, SampleRate};
+    use ogg::{PacketWriteEndInfo, PacketWriter};
+    use rubato::{FftFixedIn, Resampler};
+    use symphonia::core::meta::{Tag, Visual};
+
+    use crate::{
+        encoder_decoder::{
+            audio_stream::audio_stream::AudioStream,
+            encoders::vorbis_comment::vorbis_comment::{
+                get_comment_header, get_picture_comment, get_vorbis_comments,
+            },
+            raw_audio_data::raw_audio_data::RawAudioData,
+        },
+        messages::dart_signal::OpusConfig,
+    };
+
+    use super::{get_correct_application, get_correct_bitrate, get_correct_complexity};
+
+    // Opus always works at 48kHz internally, other rates are resampled
+    // before encoding.
+    const OPUS_SAMPLE_RATE: u32 = 48_000;
+    // 20ms frames, the recommended frame size for music.
+    const FRAME_SIZE: usize = 960;
+    // Largest packet size recommended by the libopus documentation.
+    const MAX_PACKET_SIZE: usize = 4000;
+    const RESAMPLER_CHUNK_SIZE: usize = 1024;
+    // Number of samples per channel handed over at once from memory.
+    const BLOCK_SIZE: usize = 4096;
+
+    pub trait OpusEncoder {
+        fn encode_to_opus(&self, config: &OpusConfig) -> Result<Vec<u8>, String>;
+    }
+
+    pub trait OpusStreamEncoder {
+        /// Decodes, resamples and encodes the audio block by block, writing
+        /// the Ogg stream to `writer` as it is produced.
+        fn encode_to_opus_writer(
+            &mut self,
+            config: &OpusConfig,
+            writer: &mut dyn Write,
+        ) -> Result<(), String>;
+    }
+
+    impl OpusEncoder for RawAudioData {
+        fn encode_to_opus(&self, config: &OpusConfig) -> Result<Vec<u8>, String> {
+            // readying the input, opus only deals with mono and stereo input
+            let planes = self.get_f32_mono_or_stereo()?;
+            let no_of_samples = planes[0].len();
+            let mut opus_writer = OggOpusWriter::new(
+                Vec::new(),
+                planes.len(),
+                self.get_sample_rate(),
+                self.get_tags(),
+                self.get_album_art(),
+                config,
+            )?;
+
+            // encoding the input data
+            let mut position = 0;
+            while position < no_of_samples {
+                let end = usize::min(position + BLOCK_SIZE, no_of_samples);
+                let block: Vec<&[f32]> = planes.iter().map(|plane| &plane[position..end]).collect();
+                opus_writer.write_block(&block)?;
+                position = end;
+            }
+
+            opus_writer.finish()
+        }
+    }
+
+    impl OpusStreamEncoder for AudioStream {
+        fn encode_to_opus_writer(
+            &mut self,
+            config: &OpusConfig,
+            writer: &mut dyn Write,
+        ) -> Result<(), String> {
+            // opus only deals with mono and stereo input
+            let positions = if self.get_no_of_channels() == 1 {
+                vec![0]
+            } else {
+                let (left, right) = self.get_stereo_pair_positions()?;
+                vec![left, right]
+            };
+            let mut opus_writer = OggOpusWriter::new(
+                writer,
+                positions.len(),
+                self.get_sample_rate(),
+                self.get_tags(),
+                self.get_album_art(),
+                config,
+            )?;
+
+            loop {
+                let block = match self.next_block() {
+                    Ok(Some(block)) => block,
+                    Ok(None) => break,
+                    Err(err) => return Err(err.to_string()),
+                };
+                let planes: Vec<Vec<f32>> = positions
+                    .iter()
+                    .map(|position| to_f32_plane(&block[*position]))
+                    .collect();
+                let planes: Vec<&[f32]> = planes.iter().map(|plane| &plane[..]).collect();
+                opus_writer.write_block(&planes)?;
+            }
+
+            opus_writer.finish()?;
+            Ok(())
+        }
+    }
+
+    // Writes the Ogg Opus stream as the audio comes in, only a frame worth
+    // of samples and one packet are held back at a time.
+    struct OggOpusWriter<W: Write> {
+        ogg_writer: PacketWriter<W>,
+        serial: u32,
+        opus_encoder: Encoder,
+        pre_skip: u16,
+        resampler: Option<StreamResampler>,
+        // 48kHz samples waiting for a whole frame
+        pending: Vec<Vec<f32>>,
+        frame: Vec<f32>,
+        packet: Vec<u8>,
+        position: u64,
+        // the last packet is only known to be the last one at the end, it
+        // has to end the stream
+        held_packet: Option<(Vec<u8>, u64)>,
+    }
+
+    impl<W: Write> OggOpusWriter<W> {
+        fn new(
+            writer: W,
+            channel_count: usize,
+            sample_rate: u32,
+            tags: &[Tag],
+            visuals: &[Visual],
+            config: &OpusConfig,
+        ) -> Result<OggOpusWriter<W>, String> {
+            // readying the encoder
+            let channels = match channel_count {
+                1 => Channels::Mono,
+                2 => Channels::Stereo,
+                _ => return Err("Could not find any channels in the audio file.".to_string()),
+            };
+            let mut opus_encoder = match Encoder::new(
+                SampleRate::Hz48000,
+                channels,
+                get_correct_application(config.application()),
+            ) {
+                Ok(encoder) => encoder,
+                Err(err) => return Err(err.to_string()),
+            };
+            if let Err(err) = opus_encoder.set_bitrate(get_correct_bitrate(config.bitrate)) {
+                return Err(err.to_string());
+            }
+            if let Err(err) = opus_encoder.set_complexity(get_correct_complexity(config.complexity))
+            {
+                return Err(err.to_string());
+            }
+            let pre_skip = match opus_encoder.lookahead() {
+                Ok(lookahead) => lookahead as u16,
+                Err(err) => return Err(err.to_string()),
+            };
+            let resampler = if sample_rate == OPUS_SAMPLE_RATE {
+                None
+            } else {
+                Some(StreamResampler::new(channel_count, sample_rate)?)
+            };
+
+            // writing the ogg headers
+            let serial = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.subsec_nanos())
+                .unwrap_or(0);
+            let mut ogg_writer = PacketWriter::new(writer);
+            let head = opus_head(channel_count as u8, pre_skip, sample_rate);
+            let tags = opus_tags(tags, visuals);
+            for header in [head, tags] {
+                if let Err(err) = ogg_writer.write_packet(
+                    header.into_boxed_slice(),
+                    serial,
+                    PacketWriteEndInfo::EndPage,
+                    0,
+                ) {
+                    return Err(err.to_string());
+                }
+            }
+
+            Ok(OggOpusWriter {
+                ogg_writer,
+                serial,
+                opus_encoder,
+                pre_skip,
+                resampler,
+                pending: vec![Vec::with_capacity(FRAME_SIZE * 2); channel_count],
+                frame: vec![0f32; FRAME_SIZE * channel_count],
+                packet: vec![0u8; MAX_PACKET_SIZE],
+                position: 0,
+                held_packet: None,
+            })
+        }
+
+        fn write_block(&mut self, planes: &[&[f32]]) -> Result<(), String> {
+            match self.resampler.as_mut() {
+                Some(resampler) => {
+                    for (channel, plane) in resampler.process(planes)?.into_iter().enumerate() {
+                        self.pending[channel].extend_from_slice(&plane);
+                    }
+                }
+                None => {
+                    for (channel, plane) in planes.iter().enumerate() {
+                        self.pending[channel].extend_from_slice(plane);
+                    }
+                }
+            }
+            while self.pending[0].len() >= FRAME_SIZE {
+                self.encode_frame()?;
+            }
+            Ok(())
+        }
+
+        fn finish(mut self) -> Result<W, String> {
+            if let Some(resampler) = self.resampler.as_mut() {
+                for (channel, plane) in resampler.finish()?.into_iter().enumerate() {
+                    self.pending[channel].extend_from_slice(&plane);
+                }
+            }
+            // an empty input still gets one silent frame, only a packet can
+            // end the stream
+            while !self.pending[0].is_empty() || self.held_packet.is_none() {
+                self.encode_frame()?;
+            }
+
+            // the granule position of the last page has to mark the real
+            // end of the audio, so that the padding of the last frame is
+            // dropped by the decoder.
+            if let Some((packet, _)) = self.held_packet.take() {
+                if let Err(err) = self.ogg_writer.write_packet(
+                    packet.into_boxed_slice(),
+                    self.serial,
+                    PacketWriteEndInfo::EndStream,
+                    self.pre_skip as u64 + self.position,
+                ) {
+                    return Err(err.to_string());
+                }
+            }
+            Ok(self.ogg_writer.into_inner())
+        }
+
+        fn encode_frame(&mut self) -> Result<(), String> {
+            let channel_count = self.pending.len();
+            let frame_len = usize::min(FRAME_SIZE, self.pending[0].len());
+            self.frame.fill(0.0);
+            for (channel, plane) in self.pending.iter_mut().enumerate() {
+                for (i, sample) in plane.drain(..frame_len).enumerate() {
+                    self.frame[i * channel_count + channel] = sample;
+                }
+            }
+            let packet_size = match self
+                .opus_encoder
+                .encode_float(&self.frame, &mut self.packet)
+            {
+                Ok(size) => size,
+                Err(err) => return Err(err.to_string()),
+            };
+            self.position += frame_len as u64;
+
+            if let Some((packet, granule)) = self.held_packet.take() {
+                if let Err(err) = self.ogg_writer.write_packet(
+                    packet.into_boxed_slice(),
+                    self.serial,
+                    PacketWriteEndInfo::NormalPacket,
+                    granule,
+                ) {
+                    return Err(err.to_string());
+                }
+            }
+            self.held_packet = Some((
+                self.packet[..packet_size].to_vec(),
+                self.pre_skip as u64 + self.position,
+            ));
+            Ok(())
+        }
+    }
+
+    // Resamples to 48kHz one chunk at a time, dropping the delay of the
+    // resampler at the start and cutting the tail to the length of the input.
+    struct StreamResampler {
+        resampler: FftFixedIn<f32>,
+        sample_rate: u32,
+        // input samples waiting for a whole chunk
+        input: Vec<Vec<f32>>,
+        no_of_input_samples: u64,
+        no_of_output_samples: u64,
+        delay: usize,
+    }
+
+    impl StreamResampler {
+        fn new(channel_count: usize, sample_rate: u32) -> Result<StreamResampler, String> {
+            let resampler = match FftFixedIn::<f32>::new(
+                sample_rate as usize,
+                OPUS_SAMPLE_RATE as usize,
+                RESAMPLER_CHUNK_SIZE,
+                2,
+                channel_count,
+            ) {
+                Ok(resampler) => resampler,
+                Err(err) => return Err(err.to_string()),
+            };
+            let delay = resampler.output_delay();
+            Ok(StreamResampler {
+                resampler,
+                sample_rate,
+                input: vec![Vec::with_capacity(RESAMPLER_CHUNK_SIZE * 2); channel_count],
+                no_of_input_samples: 0,
+                no_of_output_samples: 0,
+                delay,
+            })
+        }
+
+        fn process(&mut self, planes: &[&[f32]]) -> Result<Vec<Vec<f32>>, String> {
+            for (channel, plane) in planes.iter().enumerate() {
+                self.input[channel].extend_from_slice(plane);
+            }
+            self.no_of_input_samples += planes[0].len() as u64;
+
+            let mut resampled = vec![Vec::new(); self.input.len()];
+            while self.input[0].len() >= self.resampler.input_frames_next() {
+                let chunk_len = self.resampler.input_frames_next();
+                let chunk: Vec<&[f32]> =
+                    self.input.iter().map(|plane| &plane[..chunk_len]).collect();
+                let output = match self.resampler.process(&chunk, None) {
+                    Ok(output) => output,
+                    Err(err) => return Err(err.to_string()),
+                };
+                self.take_output(output, &mut resampled);
+                for plane in self.input.iter_mut() {
+                    plane.drain(..chunk_len);
+                }
+            }
+            Ok(resampled)
+        }
+
+        fn finish(&mut self) -> Result<Vec<Vec<f32>>, String> {
+            let expected_len =
+                self.no_of_input_samples * OPUS_SAMPLE_RATE as u64 / self.sample_rate as u64;
+            let mut resampled = vec![Vec::new(); self.input.len()];
+            if !self.input[0].is_empty() {
+                let chunk: Vec<&[f32]> = self.input.iter().map(|plane| &plane[..]).collect();
+                let output = match self.resampler.process_partial(Some(&chunk), None) {
+                    Ok(output) => output,
+                    Err(err) => return Err(err.to_string()),
+                };
+                self.take_output(output, &mut resampled);
+                for plane in self.input.iter_mut() {
+                    plane.clear();
+                }
+            }
+            // flushing out the samples still held back by the resampler
+            while self.no_of_output_samples < expected_len {
+                let output = match self.resampler.process_partial::<&[f32]>(None, None) {
+                    Ok(output) => output,
+                    Err(err) => return Err(err.to_string()),
+                };
+                self.take_output(output, &mut resampled);
+            }
+
+            let excess = (self.no_of_output_samples - expected_len) as usize;
+            for plane in resampled.iter_mut() {
+                plane.truncate(plane.len().saturating_sub(excess));
+            }
+            Ok(resampled)
+        }
+
+        fn take_output(&mut self, output: Vec<Vec<f32>>, resampled: &mut [Vec<f32>]) {
+            let skipped = usize::min(self.delay, output[0].len());
+            self.delay -= skipped;
+            self.no_of_output_samples += (output[0].len() - skipped) as u64;
+            for (channel, plane) in output.into_iter().enumerate() {
+                resampled[channel].extend_from_slice(&plane[skipped..]);
+            }
+        }
+    }
+
+    // Identification header, RFC 7845 section 5.1
+    fn opus_head(channel_count: u8, pre_skip: u16, input_sample_rate: u32) -> Vec<u8> {
+        let mut head = Vec::with_capacity(19);
+        head.extend_from_slice(b"OpusHead");
+        head.push(1);
+        head.push(channel_count);
+        head.extend_from_slice(&pre_skip.to_le_bytes());
+        head.extend_from_slice(&input_sample_rate.to_le_bytes());
+        head.extend_from_slice(&0i16.to_le_bytes());
+        // channel mapping family 0, mono or stereo
+        head.push(0);
+        head
+    }
+
+    // Comment header, RFC 7845 section 5.2
+    fn opus_tags(tags: &[Tag], visuals: &[Visual]) -> Vec<u8> {
+        let mut comments = get_vorbis_comments(tags);
+        for visual in visuals {
+            comments.push(get_picture_comment(visual));
+        }
+
+        let mut opus_tags = Vec::new();
+        opus_tags.extend_from_slice(b"OpusTags");
+        opus_tags.append(&mut get_comment_header(&comments));
+        opus_tags
+    }
+
+    fn to_f32_plane(plane: &[i32]) -> Vec<f32> {
+        plane
+            .iter()
+            .map(|sample| *sample as f32 / 2_147_483_648.0)
+            .collect()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::{collections::HashMap, fs, io::Cursor};
+
+        use audiopus::{coder::Decoder, Channels, SampleRate};
+        use ogg::PacketReader;
+        use symphonia::core::{
+            meta::{StandardTagKey, Tag, Value},
+            sample::SampleFormat,
+        };
+
+        use super::{
+            OpusEncoder, OpusStreamEncoder, StreamResampler, FRAME_SIZE, OPUS_SAMPLE_RATE,
+        };
+        use crate::{
+            cancellation::CancellationToken,
+            encoder_decoder::{
+                audio_stream::audio_stream::AudioStream,
+                encoders::pcm::wav::WavEncoder,
+                raw_audio_data::raw_audio_data::{AudioChannels, RawAudioData},
+            },
+            messages::dart_signal::OpusConfig,
+        };
+
+        fn get_source(no_of_samples: usize, sample_rate: u32) -> RawAudioData {
+            // a 1kHz sine at half scale
+            let sine: Vec<i32> = (0..no_of_samples)
+                .map(|i| {
+                    let phase = i as f64 * 1000.0 / sample_rate as f64;
+                    ((phase * std::f64::consts::TAU).sin() * i32::MAX as f64 / 2.0) as i32
+                })
+                .collect();
+            RawAudioData::new(
+                HashMap::from([
+                    (AudioChannels::FrontLeft, sine.clone()),
+                    (AudioChannels::FrontRight, sine),
+                ]),
+                sample_rate,
+                16,
+                SampleFormat::S16,
+                Vec::new(),
+                None,
+                vec![Tag::new(
+                    Some(StandardTagKey::TrackTitle),
+                    "TITLE",
+                    Value::from("Song"),
+                )],
+            )
+        }
+
+        fn read_packets(opus: Vec<u8>) -> Vec<ogg::Packet> {
+            let mut reader = PacketReader::new(Cursor::new(opus));
+            let mut packets = Vec::new();
+            while let Some(packet) = reader.read_packet().unwrap() {
+                packets.push(packet);
+            }
+            packets
+        }
+
+        #[test]
+        fn writes_headers_and_granule_positions() {
+            let opus = get_source(44_100, 44_100)
+                .encode_to_opus(&OpusConfig::default())
+                .unwrap();
+            let packets = read_packets(opus);
+
+            let head = &packets[0].data;
+            assert!(head.starts_with(b"OpusHead"));
+            assert_eq!(head.len(), 19);
+            assert_eq!(head[8], 1);
+            assert_eq!(head[9], 2);
+            let pre_skip = u16::from_le_bytes([head[10], head[11]]) as u64;
+            assert!(pre_skip > 0);
+            // the rate of the source is kept for information only
+            assert_eq!(u32::from_le_bytes(head[12..16].try_into().unwrap()), 44_100);
+            let tags = &packets[1].data;
+            assert!(tags.starts_with(b"OpusTags"));
+            assert!(tags.windows(10).any(|window| window == b"TITLE=Song"));
+
+            // one second, resampled to 48kHz, in 20ms packets
+            let audio = &packets[2..];
+            assert_eq!(audio.len(), 48_000 / FRAME_SIZE);
+            let mut decoder = Decoder::new(SampleRate::Hz48000, Channels::Stereo).unwrap();
+            let mut decoded = vec![0f32; FRAME_SIZE * 2];
+            let mut granule = pre_skip;
+            for packet in audio {
+                let samples = decoder
+                    .decode_float(
+                        Some(packet.data[..].try_into().unwrap()),
+                        (&mut decoded[..]).try_into().unwrap(),
+                        false,
+                    )
+                    .unwrap();
+                assert_eq!(samples, FRAME_SIZE);
+                granule += samples as u64;
+                if packet.last_in_page() {
+                    assert_eq!(packet.absgp_page(), granule);
+                }
+            }
+            let last = audio.last().unwrap();
+            assert!(last.last_in_stream());
+            assert_eq!(last.absgp_page(), pre_skip + OPUS_SAMPLE_RATE as u64);
+        }
+
+        #[test]
+        fn resamples_to_48khz_keeping_the_pitch() {
+            let source = get_source(44_100, 44_100);
+            let planes = source.get_f32_mono_or_stereo().unwrap();
+            let mut resampler = StreamResampler::new(2, 44_100).unwrap();
+            let mut resampled = vec![Vec::new(); 2];
+            // blocks that do not line up with the chunks of the resampler
+            for start in (0..44_100).step_by(1000) {
+                let end = usize::min(start + 1000, 44_100);
+                let block: Vec<&[f32]> = planes.iter().map(|plane| &plane[start..end]).collect();
+                for (channel, plane) in resampler.process(&block).unwrap().into_iter().enumerate() {
+                    resampled[channel].extend(plane);
+                }
+            }
+            for (channel, plane) in resampler.finish().unwrap().into_iter().enumerate() {
+                resampled[channel].extend(plane);
+            }
+            assert_eq!(resampled[0].len(), 48_000);
+            assert_eq!(resampled[1].len(), 48_000);
+            // a 1kHz sine crosses zero upwards once every millisecond
+            let rising = resampled[0]
+                .windows(2)
+                .filter(|pair| pair[0] < 0.0 && pair[1] >= 0.0)
+                .count();
+            assert!((999..=1001).contains(&rising), "{} zero crossings", rising);
+        }
+
+        #[test]
+        fn streams_the_same_packets_as_the_encoder_in_memory() {
+            let dir =
+                std::env::temp_dir().join(format!("diraudio-opus-stream-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            let source = get_source(44_100, 44_100);
+            let source_path = dir.join("source.wav");
+            fs::write(&source_path, source.encode_to_wav().unwrap()).unwrap();
+            let config = OpusConfig::default();
+
+            let mut streamed = Cursor::new(Vec::new());
+            let encoded = AudioStream::new_from_path(&source_path, CancellationToken::new())
+                .unwrap()
+                .encode_to_opus_writer(&config, &mut streamed);
+            let in_memory = RawAudioData::new_from_path(&source_path, &CancellationToken::new())
+                .unwrap()
+                .encode_to_opus(&config);
+            fs::remove_dir_all(&dir).unwrap();
+
+            encoded.unwrap();
+            let streamed = read_packets(streamed.into_inner());
+            let in_memory = read_packets(in_memory.unwrap());
+            assert_eq!(streamed.len(), 2 + 48_000 / FRAME_SIZE);
+            assert_eq!(streamed.len(), in_memory.len());
+            // the serial number differs, the packets do not
+            for (streamed, in_memory) in streamed.iter().zip(in_memory.iter()) {
+                assert_eq!(streamed.data, in_memory.data);
+                assert_eq!(streamed.absgp_page(), in_memory.absgp_page());
+            }
+            assert!(streamed.last().unwrap().last_in_stream());
+        }
+
+        #[test]
+        fn stops_encoding_once_cancelled() {
+            let dir =
+                std::env::temp_dir().join(format!("diraudio-opus-cancel-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            let source_path = dir.join("source.wav");
+            fs::write(
+                &source_path,
+                get_source(441_000, 44_100).encode_to_wav().unwrap(),
+            )
+            .unwrap();
+            let cancel_token = CancellationToken::new();
+            let mut audio_stream =
+                AudioStream::new_from_path(&source_path, cancel_token.clone()).unwrap();
+            cancel_token.cancel();
+
+            let mut encoded = Cursor::new(Vec::new());
+            let result = audio_stream.encode_to_opus_writer(&OpusConfig::default(), &mut encoded);
+            fs::remove_dir_all(&dir).unwrap();
+
+            assert!(result.is_err());
+            // at most the first decoded block was encoded, the stream never
+            // got its end
+            let packets = read_packets(encoded.into_inner());
+            assert!(packets.len() < 2 + 441_000 / FRAME_SIZE / 10);
+            assert!(!packets.iter().any(|packet| packet.last_in_stream()));
+        }
+
+        #[test]
+        fn ends_the_stream_of_an_empty_input() {
+            let opus = get_source(0, 44_100)
+                .encode_to_opus(&OpusConfig::default())
+                .unwrap();
+            let packets = read_packets(opus);
+            assert_eq!(packets.len(), 3);
+            let pre_skip = u16::from_le_bytes([packets[0].data[10], packets[0].data[11]]) as u64;
+            let last = packets.last().unwrap();
+            assert!(last.last_in_stream());
+            // nothing is left once the pre-skip is dropped
+            assert_eq!(last.absgp_page(), pre_skip);
+        }
+    }
+}
+
+fn get_correct_application(application: OpusApplication) -> audiopus::Application {
+    match application {
+        OpusApplication::Audio => audiopus::Application::Audio,
+        OpusApplication::Voip => audiopus::Application::Voip,
+        OpusApplication::LowDelay => audiopus::Application::LowDelay,
+    }
+}
+
+// Opus accepts anything from 6kbps to 510kbps, an unset bitrate
+// leaves the choice to the encoder.
+fn get_correct_bitrate(bitrate: i32) -> audiopus::Bitrate {
+    if bitrate <= 0 {
+        return audiopus::Bitrate::Auto;
+    }
+    audiopus::Bitrate::BitsPerSecond(bitrate.clamp(6, 510) * 1000)
+}
+
+fn get_correct_complexity(complexity: i32) -> u8 {
+    complexity.clamp(0, 10) as u8
+}
@@ -0,0 +1,617 @@
+use std::io::{Seek, SeekFrom, Write};
+
+use crate::encoder_decoder::{
+    audio_stream::audio_stream::AudioStream, raw_audio_data::raw_audio_data::RawAudioData,
+};
+
+pub mod wav {
+    use std::io::{Seek, Write};
+
+    use symphonia::core::meta::{StandardTagKey, Tag, Visual};
+
+    use crate::{
+        encoder_decoder::{
+            audio_stream::audio_stream::AudioStream,
+            encoders::{id3::id3::get_id3v2_tag, vorbis_comment::vorbis_comment::VENDOR_STRING},
+            raw_audio_data::raw_audio_data::{AudioChannels, RawAudioData},
+        },
+        messages::dart_signal::Id3Version,
+    };
+
+    use super::{
+        chunk, get_interleaved_samples, get_pcm_bit_depth, patch, write_out, write_stream_samples,
+    };
+
+    const WAVE_FORMAT_PCM: u16 = 0x0001;
+    const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+    // KSDATAFORMAT_SUBTYPE_PCM
+    const SUBTYPE_PCM: [u8; 16] = [
+        0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xaa, 0x00, 0x38, 0x9b,
+        0x71,
+    ];
+
+    pub trait WavEncoder {
+        fn encode_to_wav(&self) -> Result<Vec<u8>, String>;
+    }
+
+    pub trait WavStreamEncoder {
+        /// Decodes the audio block by block, writing the samples to `writer`
+        /// as they are decoded. The sizes in the header are filled in once
+        /// the end of the audio is reached.
+        fn encode_to_wav_writer<W: Write + Seek>(&mut self, writer: &mut W) -> Result<(), String>;
+    }
+
+    impl WavEncoder for RawAudioData {
+        fn encode_to_wav(&self) -> Result<Vec<u8>, String> {
+            let bits_per_sample = get_pcm_bit_depth(self.get_bit_depth());
+            let channels: Vec<AudioChannels> = self
+                .get_ordered_channels()
+                .into_iter()
+                .map(|(channel, _)| channel.clone())
+                .collect();
+            let data = get_interleaved_samples(self, bits_per_sample, false)?;
+
+            let format = get_format(&channels, self.get_sample_rate(), self.get_bit_depth());
+            let mut chunks = chunk(b"fmt ", format, false);
+            chunks.append(&mut chunk(b"data", data, false));
+            chunks.append(&mut get_trailing_chunks(
+                self.get_tags(),
+                self.get_album_art(),
+            ));
+
+            let mut body = b"WAVE".to_vec();
+            body.append(&mut chunks);
+            if body.len() > u32::MAX as usize {
+                return Err("Audio is too large for a WAV file".to_string());
+            }
+            Ok(chunk(b"RIFF", body, false))
+        }
+    }
+
+    impl WavStreamEncoder for AudioStream {
+        fn encode_to_wav_writer<W: Write + Seek>(&mut self, writer: &mut W) -> Result<(), String> {
+            let bits_per_sample = get_pcm_bit_depth(self.get_bit_depth());
+            let positions = self.get_ordered_positions();
+            let channels: Vec<AudioChannels> = positions
+                .iter()
+                .map(|position| self.get_channels()[*position].clone())
+                .collect();
+
+            // the sizes are left empty until the end of the audio
+            let format = get_format(&channels, self.get_sample_rate(), self.get_bit_depth());
+            let mut header = b"RIFF\0\0\0\0WAVE".to_vec();
+            header.append(&mut chunk(b"fmt ", format, false));
+            let data_size_offset = header.len() as u64 + 4;
+            header.extend_from_slice(b"data\0\0\0\0");
+            write_out(writer, &header)?;
+
+            let data_size = write_stream_samples(self, &positions, bits_per_sample, false, writer)?;
+            // chunks are word aligned
+            let mut trailer = vec![0u8; (data_size % 2) as usize];
+            trailer.append(&mut get_trailing_chunks(
+                self.get_tags(),
+                self.get_album_art(),
+            ));
+            write_out(writer, &trailer)?;
+
+            let riff_size = (header.len() - 8 + trailer.len()) as u64 + data_size;
+            if riff_size > u32::MAX as u64 {
+                return Err("Audio is too large for a WAV file".to_string());
+            }
+            patch(writer, 4, &(riff_size as u32).to_le_bytes())?;
+            patch(writer, data_size_offset, &(data_size as u32).to_le_bytes())
+        }
+    }
+
+    // Body of the format chunk, WAVE_FORMAT_EXTENSIBLE is required to
+    // describe the speaker layout of anything above stereo.
+    fn get_format(channels: &[AudioChannels], sample_rate: u32, source_bit_depth: u32) -> Vec<u8> {
+        let bits_per_sample = get_pcm_bit_depth(source_bit_depth);
+        let channel_count = channels.len() as u16;
+        let block_align = channel_count as u32 * bits_per_sample / 8;
+        let mut format = Vec::with_capacity(40);
+        let format_tag = if channel_count > 2 {
+            WAVE_FORMAT_EXTENSIBLE
+        } else {
+            WAVE_FORMAT_PCM
+        };
+        format.extend_from_slice(&format_tag.to_le_bytes());
+        format.extend_from_slice(&channel_count.to_le_bytes());
+        format.extend_from_slice(&sample_rate.to_le_bytes());
+        format.extend_from_slice(&(sample_rate * block_align).to_le_bytes());
+        format.extend_from_slice(&(block_align as u16).to_le_bytes());
+        format.extend_from_slice(&(bits_per_sample as u16).to_le_bytes());
+        if format_tag == WAVE_FORMAT_EXTENSIBLE {
+            let channel_mask = channels
+                .iter()
+                .fold(0u32, |mask, channel| mask | channel.clone() as u32);
+            let valid_bits = u32::min(source_bit_depth, bits_per_sample);
+            format.extend_from_slice(&22u16.to_le_bytes());
+            format.extend_from_slice(&(valid_bits as u16).to_le_bytes());
+            format.extend_from_slice(&channel_mask.to_le_bytes());
+            format.extend_from_slice(&SUBTYPE_PCM);
+        }
+        format
+    }
+
+    // The tags go after the samples, in the INFO list and an ID3 chunk.
+    fn get_trailing_chunks(tags: &[Tag], visuals: &[Visual]) -> Vec<u8> {
+        let mut chunks = info_list(tags);
+        let id3_tag = get_id3v2_tag(tags, visuals, Id3Version::Id3v24);
+        if !id3_tag.is_empty() {
+            chunks.append(&mut chunk(b"id3 ", id3_tag, false));
+        }
+        chunks
+    }
+
+    fn info_list(tags: &[Tag]) -> Vec<u8> {
+        let mut info = b"INFO".to_vec();
+        for tag in tags {
+            let id = match tag.std_key {
+                Some(StandardTagKey::TrackTitle) => b"INAM",
+                Some(StandardTagKey::Artist) => b"IART",
+                Some(StandardTagKey::Album) => b"IPRD",
+                Some(StandardTagKey::Date) => b"ICRD",
+                Some(StandardTagKey::Genre) => b"IGNR",
+                Some(StandardTagKey::Comment) => b"ICMT",
+                Some(StandardTagKey::TrackNumber) => b"ITRK",
+                Some(StandardTagKey::Copyright) => b"ICOP",
+                _ => continue,
+            };
+            info.append(&mut info_entry(id, &tag.value.to_string()));
+        }
+        info.append(&mut info_entry(b"ISFT", VENDOR_STRING));
+        chunk(b"LIST", info, false)
+    }
+
+    fn info_entry(id: &[u8; 4], value: &str) -> Vec<u8> {
+        let mut value = value.as_bytes().to_vec();
+        value.push(0);
+        chunk(id, value, false)
+    }
+}
+
+pub mod aiff {
+    use std::io::{Seek, Write};
+
+    use symphonia::core::meta::{Tag, Visual};
+
+    use crate::{
+        encoder_decoder::{
+            audio_stream::audio_stream::AudioStream, encoders::id3::id3::get_id3v2_tag,
+            raw_audio_data::raw_audio_data::RawAudioData,
+        },
+        messages::dart_signal::Id3Version,
+    };
+
+    use super::{
+        chunk, get_interleaved_samples, get_pcm_bit_depth, patch, write_out, write_stream_samples,
+    };
+
+    pub trait AiffEncoder {
+        fn encode_to_aiff(&self) -> Result<Vec<u8>, String>;
+    }
+
+    pub trait AiffStreamEncoder {
+        /// Decodes the audio block by block, writing the samples to `writer`
+        /// as they are decoded. The sizes in the header are filled in once
+        /// the end of the audio is reached.
+        fn encode_to_aiff_writer<W: Write + Seek>(&mut self, writer: &mut W) -> Result<(), String>;
+    }
+
+    impl AiffEncoder for RawAudioData {
+        fn encode_to_aiff(&self) -> Result<Vec<u8>, String> {
+            let bits_per_sample = get_pcm_bit_depth(self.get_bit_depth());
+            let channel_count = self.get_no_of_channels() as u16;
+            let no_of_frames = match self.get_audio_data().values().next() {
+                Some(data) => data.len() as u32,
+                None => return Err("Could not find any channels in the audio file.".to_string()),
+            };
+
+            // no offset and no block alignment
+            let mut sound = vec![0u8; 8];
+            sound.append(&mut get_interleaved_samples(self, bits_per_sample, true)?);
+
+            let mut body = b"AIFF".to_vec();
+            body.append(&mut chunk(
+                b"COMM",
+                get_common(
+                    channel_count,
+                    no_of_frames,
+                    bits_per_sample,
+                    self.get_sample_rate(),
+                ),
+                true,
+            ));
+            body.append(&mut chunk(b"SSND", sound, true));
+            body.append(&mut get_id3_chunk(self.get_tags(), self.get_album_art()));
+            if body.len() > u32::MAX as usize {
+                return Err("Audio is too large for an AIFF file".to_string());
+            }
+            Ok(chunk(b"FORM", body, true))
+        }
+    }
+
+    impl AiffStreamEncoder for AudioStream {
+        fn encode_to_aiff_writer<W: Write + Seek>(&mut self, writer: &mut W) -> Result<(), String> {
+            let bits_per_sample = get_pcm_bit_depth(self.get_bit_depth());
+            let positions = self.get_ordered_positions();
+            let channel_count = positions.len() as u16;
+
+            // the sizes and the number of frames are left empty until the
+            // end of the audio
+            let mut header = b"FORM\0\0\0\0AIFF".to_vec();
+            let no_of_frames_offset = header.len() as u64 + 10;
+            header.append(&mut chunk(
+                b"COMM",
+                get_common(channel_count, 0, bits_per_sample, self.get_sample_rate()),
+                true,
+            ));
+            let sound_size_offset = header.len() as u64 + 4;
+            header.extend_from_slice(b"SSND\0\0\0\0");
+            // no offset and no block alignment
+            header.extend_from_slice(&[0u8; 8]);
+            write_out(writer, &header)?;
+
+            let data_size = write_stream_samples(self, &positions, bits_per_sample, true, writer)?;
+            // chunks are word aligned
+            let mut trailer = vec![0u8; (data_size % 2) as usize];
+            trailer.append(&mut get_id3_chunk(self.get_tags(), self.get_album_art()));
+            write_out(writer, &trailer)?;
+
+            let form_size = (header.len() - 8 + trailer.len()) as u64 + data_size;
+            if form_size > u32::MAX as u64 {
+                return Err("Audio is too large for an AIFF file".to_string());
+            }
+            let no_of_frames = data_size / (channel_count as u64 * bits_per_sample as u64 / 8);
+            patch(writer, 4, &(form_size as u32).to_be_bytes())?;
+            patch(
+                writer,
+                no_of_frames_offset,
+                &(no_of_frames as u32).to_be_bytes(),
+            )?;
+            patch(
+                writer,
+                sound_size_offset,
+                &(data_size as u32 + 8).to_be_bytes(),
+            )
+        }
+    }
+
+    // Body of the COMM chunk.
+    fn get_common(
+        channel_count: u16,
+        no_of_frames: u32,
+        bits_per_sample: u32,
+        sample_rate: u32,
+    ) -> Vec<u8> {
+        let mut common = Vec::with_capacity(18);
+        common.extend_from_slice(&channel_count.to_be_bytes());
+        common.extend_from_slice(&no_of_frames.to_be_bytes());
+        common.extend_from_slice(&(bits_per_sample as u16).to_be_bytes());
+        common.extend_from_slice(&extended_sample_rate(sample_rate));
+        common
+    }
+
+    fn get_id3_chunk(tags: &[Tag], visuals: &[Visual]) -> Vec<u8> {
+        let id3_tag = get_id3v2_tag(tags, visuals, Id3Version::Id3v24);
+        if id3_tag.is_empty() {
+            return Vec::new();
+        }
+        chunk(b"ID3 ", id3_tag, true)
+    }
+
+    // The sample rate of the COMM chunk is an 80 bit IEEE 754 extended
+    // precision float.
+    fn extended_sample_rate(sample_rate: u32) -> [u8; 10] {
+        let mut extended = [0u8; 10];
+        if sample_rate == 0 {
+            return extended;
+        }
+        let shift = sample_rate.leading_zeros();
+        let exponent = (16383 + 31 - shift) as u16;
+        let mantissa = (sample_rate as u64) << (32 + shift);
+        extended[..2].copy_from_slice(&exponent.to_be_bytes());
+        extended[2..].copy_from_slice(&mantissa.to_be_bytes());
+        extended
+    }
+}
+
+// Only 16, 24 and 32 bit integer PCM is written, the source depth is
+// rounded up to the closest one.
+fn get_pcm_bit_depth(bits_per_sample: u32) -> u32 {
+    match bits_per_sample {
+        0..=16 => 16,
+        17..=24 => 24,
+        _ => 32,
+    }
+}
+
+fn get_interleaved_samples(
+    audio: &RawAudioData,
+    bits_per_sample: u32,
+    big_endian: bool,
+) -> Result<Vec<u8>, String> {
+    let channels = audio.get_ordered_channels();
+    if channels.is_empty() {
+        return Err("Could not find any channels in the audio file.".to_string());
+    }
+    let planes: Vec<&[i32]> = channels.iter().map(|(_, plane)| plane.as_slice()).collect();
+    let mut data = Vec::with_capacity(planes[0].len() * planes.len() * 4);
+    interleave_samples(&planes, bits_per_sample, big_endian, &mut data);
+    Ok(data)
+}
+
+// Writes the samples of the stream as they are decoded, returning the number
+// of bytes written.
+fn write_stream_samples<W: Write>(
+    stream: &mut AudioStream,
+    positions: &[usize],
+    bits_per_sample: u32,
+    big_endian: bool,
+    writer: &mut W,
+) -> Result<u64, String> {
+    let mut data = Vec::new();
+    let mut data_size = 0;
+    loop {
+        let block = match stream.next_block() {
+            Ok(Some(block)) => block,
+            Ok(None) => return Ok(data_size),
+            Err(err) => return Err(err.to_string()),
+        };
+        let planes: Vec<&[i32]> = positions
+            .iter()
+            .map(|position| block[*position].as_slice())
+            .collect();
+        // the capacity is kept for the next block
+        data.clear();
+        interleave_samples(&planes, bits_per_sample, big_endian, &mut data);
+        write_out(writer, &data)?;
+        data_size += data.len() as u64;
+    }
+}
+
+fn interleave_samples(
+    planes: &[&[i32]],
+    bits_per_sample: u32,
+    big_endian: bool,
+    data: &mut Vec<u8>,
+) {
+    let bytes_per_sample = (bits_per_sample / 8) as usize;
+    let no_of_samples = planes[0].len();
+    data.reserve(no_of_samples * planes.len() * bytes_per_sample);
+    for i in 0..no_of_samples {
+        for plane in planes.iter() {
+            // samples are stored at full 32 bit scale, so the wanted
+            // depth is simply the most significant bytes
+            let sample = plane[i];
+            if big_endian {
+                data.extend_from_slice(&sample.to_be_bytes()[..bytes_per_sample]);
+            } else {
+                data.extend_from_slice(&sample.to_le_bytes()[4 - bytes_per_sample..]);
+            }
+        }
+    }
+}
+
+fn chunk(id: &[u8; 4], mut body: Vec<u8>, big_endian: bool) -> Vec<u8> {
+    let size = body.len() as u32;
+    let mut chunk = Vec::with_capacity(body.len() + 9);
+    chunk.extend_from_slice(id);
+    if big_endian {
+        chunk.extend_from_slice(&size.to_be_bytes());
+    } else {
+        chunk.extend_from_slice(&size.to_le_bytes());
+    }
+    chunk.append(&mut body);
+    // chunks are word aligned
+    if size % 2 == 1 {
+        chunk.push(0);
+    }
+    chunk
+}
+
+fn write_out<W: Write>(writer: &mut W, bytes: &[u8]) -> Result<(), String> {
+    match writer.write_all(bytes) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+// Overwrites what was written at `offset`, leaving the writer at the end.
+fn patch<W: Write + Seek>(writer: &mut W, offset: u64, bytes: &[u8]) -> Result<(), String> {
+    let patched = writer
+        .seek(SeekFrom::Start(offset))
+        .and_then(|_| writer.write_all(bytes))
+        .and_then(|_| writer.seek(SeekFrom::End(0)));
+    match patched {
+        Ok(_) => Ok(()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, fs, io::Cursor, path::PathBuf};
+
+    use symphonia::core::sample::SampleFormat;
+
+    use super::{
+        aiff::{AiffEncoder, AiffStreamEncoder},
+        wav::{WavEncoder, WavStreamEncoder},
+    };
+    use crate::{
+        cancellation::CancellationToken,
+        encoder_decoder::{
+            audio_stream::audio_stream::AudioStream,
+            raw_audio_data::raw_audio_data::{AudioChannels, RawAudioData},
+        },
+    };
+
+    // Three channels of 24 bit audio with an odd number of frames, so the
+    // data chunk needs a pad byte and the format is WAVE_FORMAT_EXTENSIBLE.
+    fn write_source(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("diraudio-pcm-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let plane = |offset: i32| -> Vec<i32> {
+            (0..4097)
+                .map(|i| ((i * 7919 + offset) % 16_777_216 - 8_388_608) << 8)
+                .collect()
+        };
+        let source = RawAudioData::new(
+            HashMap::from([
+                (AudioChannels::FrontLeft, plane(0)),
+                (AudioChannels::FrontRight, plane(1)),
+                (AudioChannels::FrontCentre, plane(2)),
+            ]),
+            48_000,
+            24,
+            SampleFormat::S24,
+            Vec::new(),
+            None,
+            Vec::new(),
+        );
+        let path = dir.join("source.wav");
+        fs::write(&path, source.encode_to_wav().unwrap()).unwrap();
+        path
+    }
+
+    #[test]
+    fn streams_the_same_wav_as_the_encoder_in_memory() {
+        let path = write_source("wav");
+        let in_memory = RawAudioData::new_from_path(&path, &CancellationToken::new())
+            .unwrap()
+            .encode_to_wav()
+            .unwrap();
+        let mut streamed = Cursor::new(Vec::new());
+        AudioStream::new_from_path(&path, CancellationToken::new())
+            .unwrap()
+            .encode_to_wav_writer(&mut streamed)
+            .unwrap();
+        assert_eq!(streamed.into_inner(), in_memory);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn streams_the_same_aiff_as_the_encoder_in_memory() {
+        let path = write_source("aiff");
+        let in_memory = RawAudioData::new_from_path(&path, &CancellationToken::new())
+            .unwrap()
+            .encode_to_aiff()
+            .unwrap();
+        let mut streamed = Cursor::new(Vec::new());
+        AudioStream::new_from_path(&path, CancellationToken::new())
+            .unwrap()
+            .encode_to_aiff_writer(&mut streamed)
+            .unwrap();
+        assert_eq!(streamed.into_inner(), in_memory);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    fn get_source(
+        channels: &[AudioChannels],
+        bits_per_sample: u32,
+        sample_rate: u32,
+    ) -> RawAudioData {
+        // the channel number in the top byte, so the order can be told apart
+        let audio_data = channels
+            .iter()
+            .enumerate()
+            .map(|(i, channel)| (channel.clone(), vec![((i as i32 + 1) << 24) | 0x0002_0300]))
+            .collect();
+        RawAudioData::new(
+            audio_data,
+            sample_rate,
+            bits_per_sample,
+            SampleFormat::S24,
+            Vec::new(),
+            None,
+            Vec::new(),
+        )
+    }
+
+    #[test]
+    fn writes_an_extensible_header_with_the_channel_mask() {
+        let channels = [
+            AudioChannels::SideRight,
+            AudioChannels::FrontLeft,
+            AudioChannels::LFE1,
+            AudioChannels::FrontRight,
+            AudioChannels::SideLeft,
+            AudioChannels::FrontCentre,
+        ];
+        // 20 bit samples are written in 24 bit containers
+        let wav = get_source(&channels, 20, 48_000).encode_to_wav().unwrap();
+
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(&wav[4..8], &(wav.len() as u32 - 8).to_le_bytes());
+        assert_eq!(&wav[8..12], b"WAVE");
+        let mut format = b"fmt \x28\x00\x00\x00".to_vec();
+        // WAVE_FORMAT_EXTENSIBLE, 6 channels, 48kHz, 864000 bytes a second,
+        // 18 bytes a frame and 24 bits a sample
+        format
+            .extend_from_slice(b"\xfe\xff\x06\x00\x80\xbb\x00\x00\x00\x2f\x0d\x00\x12\x00\x18\x00");
+        // 22 extra bytes, 20 valid bits and FL | FR | FC | LFE | SL | SR
+        format.extend_from_slice(b"\x16\x00\x14\x00\x0f\x06\x00\x00");
+        format
+            .extend_from_slice(b"\x01\x00\x00\x00\x00\x00\x10\x00\x80\x00\x00\xaa\x00\x38\x9b\x71");
+        assert_eq!(&wav[12..60], format);
+        assert_eq!(&wav[60..68], b"data\x12\x00\x00\x00");
+        // in WAVE channel order, each the top three bytes little endian
+        assert_eq!(
+            &wav[68..86],
+            b"\x03\x02\x02\x03\x02\x04\x03\x02\x06\x03\x02\x03\x03\x02\x05\x03\x02\x01"
+        );
+        // followed by the INFO list naming the software
+        assert_eq!(&wav[86..90], b"LIST");
+    }
+
+    #[test]
+    fn writes_a_plain_header_for_stereo() {
+        let channels = [AudioChannels::FrontLeft, AudioChannels::FrontRight];
+        let wav = get_source(&channels, 16, 44_100).encode_to_wav().unwrap();
+
+        // WAVE_FORMAT_PCM, 2 channels, 44.1kHz, 176400 bytes a second, 4 bytes
+        // a frame and 16 bits a sample
+        assert_eq!(
+            &wav[12..36],
+            b"fmt \x10\x00\x00\x00\x01\x00\x02\x00\x44\xac\x00\x00\x10\xb1\x02\x00\x04\x00\x10\x00"
+        );
+        assert_eq!(&wav[36..48], b"data\x04\x00\x00\x00\x02\x01\x02\x02");
+    }
+
+    #[test]
+    fn writes_the_sample_rate_of_the_common_chunk_as_extended_float() {
+        let channels = [AudioChannels::FrontLeft, AudioChannels::FrontRight];
+        let aiff = get_source(&channels, 16, 44_100).encode_to_aiff().unwrap();
+
+        assert_eq!(&aiff[..4], b"FORM");
+        assert_eq!(&aiff[4..8], &(aiff.len() as u32 - 8).to_be_bytes());
+        assert_eq!(&aiff[8..12], b"AIFF");
+        // 2 channels, 1 frame, 16 bits and 44.1kHz
+        assert_eq!(
+            &aiff[12..38],
+            b"COMM\x00\x00\x00\x12\x00\x02\x00\x00\x00\x01\x00\x10\x40\x0e\xac\x44\x00\x00\x00\x00\x00\x00"
+        );
+        // no offset, no block alignment and big endian samples
+        assert_eq!(
+            &aiff[38..58],
+            b"SSND\x00\x00\x00\x0c\x00\x00\x00\x00\x00\x00\x00\x00\x01\x02\x02\x02"
+        );
+
+        for (sample_rate, extended) in [
+            (8_000, b"\x40\x0b\xfa\x00"),
+            (48_000, b"\x40\x0e\xbb\x80"),
+            (96_000, b"\x40\x0f\xbb\x80"),
+            (192_000, b"\x40\x10\xbb\x80"),
+        ] {
+            let aiff = get_source(&channels, 16, sample_rate)
+                .encode_to_aiff()
+                .unwrap();
+            assert_eq!(&aiff[28..32], extended, "{}", sample_rate);
+            assert_eq!(&aiff[32..38], [0; 6], "{}", sample_rate);
+        }
+    }
+}
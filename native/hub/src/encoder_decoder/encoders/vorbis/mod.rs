@@ -0,0 +1,255 @@
+use crate::messages::dart_signal::{VorbisBitrateMode, VorbisConfig};
+
+pub mod vorbis {
+    use std::{
+        io::Write,
+        num::{NonZeroU32, NonZeroU8},
+    };
+
+    use symphonia::core::meta::{Tag, Visual};
+    use vorbis_rs::{VorbisEncoder as LibVorbisEncoder, VorbisEncoderBuilder};
+
+    use crate::{
+        encoder_decoder::{
+            audio_stream::audio_stream::AudioStream,
+            encoders::vorbis_comment::vorbis_comment::{get_picture_comment, get_vorbis_comments},
+            raw_audio_data::raw_audio_data::RawAudioData,
+        },
+        messages::dart_signal::VorbisConfig,
+    };
+
+    use super::get_correct_strategy;
+
+    // Number of samples per channel handed to libvorbis at once.
+    const BLOCK_SIZE: usize = 4096;
+
+    pub trait VorbisEncoder {
+        fn encode_to_vorbis(&self, config: &VorbisConfig) -> Result<Vec<u8>, String>;
+    }
+
+    pub trait VorbisStreamEncoder {
+        /// Decodes and encodes the audio block by block, writing the Ogg
+        /// stream to `writer` as it is produced.
+        fn encode_to_vorbis_writer(
+            &mut self,
+            config: &VorbisConfig,
+            writer: &mut dyn Write,
+        ) -> Result<(), String>;
+    }
+
+    impl VorbisEncoder for RawAudioData {
+        fn encode_to_vorbis(&self, config: &VorbisConfig) -> Result<Vec<u8>, String> {
+            let planes = self.get_f32_mono_or_stereo()?;
+            let no_of_samples = planes[0].len();
+            let mut vorbis_encoder = get_vorbis_encoder(
+                planes.len() as u8,
+                self.get_sample_rate(),
+                self.get_tags(),
+                self.get_album_art(),
+                config,
+                Vec::new(),
+            )?;
+
+            // encoding the input data
+            let mut position = 0;
+            while position < no_of_samples {
+                let end = usize::min(position + BLOCK_SIZE, no_of_samples);
+                let block: Vec<&[f32]> = planes.iter().map(|plane| &plane[position..end]).collect();
+                if let Err(err) = vorbis_encoder.encode_audio_block(&block) {
+                    return Err(err.to_string());
+                }
+                position = end;
+            }
+
+            match vorbis_encoder.finish() {
+                Ok(output) => Ok(output),
+                Err(err) => Err(err.to_string()),
+            }
+        }
+    }
+
+    impl VorbisStreamEncoder for AudioStream {
+        fn encode_to_vorbis_writer(
+            &mut self,
+            config: &VorbisConfig,
+            writer: &mut dyn Write,
+        ) -> Result<(), String> {
+            let positions = if self.get_no_of_channels() == 1 {
+                vec![0]
+            } else {
+                let (left, right) = self.get_stereo_pair_positions()?;
+                vec![left, right]
+            };
+            let mut vorbis_encoder = get_vorbis_encoder(
+                positions.len() as u8,
+                self.get_sample_rate(),
+                self.get_tags(),
+                self.get_album_art(),
+                config,
+                writer,
+            )?;
+
+            // libvorbis buffers the samples itself, so every decoded packet
+            // is handed over as it is
+            loop {
+                let block = match self.next_block() {
+                    Ok(Some(block)) => block,
+                    Ok(None) => break,
+                    Err(err) => return Err(err.to_string()),
+                };
+                let planes: Vec<Vec<f32>> = positions
+                    .iter()
+                    .map(|position| to_f32_plane(&block[*position]))
+                    .collect();
+                if let Err(err) = vorbis_encoder.encode_audio_block(&planes) {
+                    return Err(err.to_string());
+                }
+            }
+
+            match vorbis_encoder.finish() {
+                Ok(_) => Ok(()),
+                Err(err) => Err(err.to_string()),
+            }
+        }
+    }
+
+    fn get_vorbis_encoder<W: Write>(
+        no_of_channels: u8,
+        sample_rate: u32,
+        tags: &[Tag],
+        visuals: &[Visual],
+        config: &VorbisConfig,
+        sink: W,
+    ) -> Result<LibVorbisEncoder<W>, String> {
+        let sample_rate = match NonZeroU32::new(sample_rate) {
+            Some(rate) => rate,
+            None => return Err("Invalid sample rate of 0".to_string()),
+        };
+        let channels = match NonZeroU8::new(no_of_channels) {
+            Some(channels) => channels,
+            None => return Err("Could not find any channels in the audio file.".to_string()),
+        };
+        let mut builder = match VorbisEncoderBuilder::new(sample_rate, channels, sink) {
+            Ok(builder) => builder,
+            Err(err) => return Err(err.to_string()),
+        };
+        builder.bitrate_management_strategy(get_correct_strategy(config)?);
+
+        let mut comments = get_vorbis_comments(tags);
+        for visual in visuals {
+            comments.push(get_picture_comment(visual));
+        }
+        for (key, value) in comments {
+            if let Err(err) = builder.comment_tag(key, value) {
+                return Err(err.to_string());
+            }
+        }
+
+        match builder.build() {
+            Ok(encoder) => Ok(encoder),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    fn to_f32_plane(plane: &[i32]) -> Vec<f32> {
+        plane
+            .iter()
+            .map(|sample| *sample as f32 / 2_147_483_648.0)
+            .collect()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::{collections::HashMap, fs, io::Cursor};
+
+        use symphonia::core::sample::SampleFormat;
+
+        use super::{VorbisEncoder, VorbisStreamEncoder};
+        use crate::{
+            cancellation::CancellationToken,
+            encoder_decoder::{
+                audio_stream::audio_stream::AudioStream,
+                encoders::pcm::wav::WavEncoder,
+                raw_audio_data::raw_audio_data::{AudioChannels, RawAudioData},
+            },
+            messages::dart_signal::VorbisConfig,
+        };
+
+        #[test]
+        fn streams_as_many_samples_as_the_encoder_in_memory() {
+            let dir =
+                std::env::temp_dir().join(format!("diraudio-vorbis-stream-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            let plane: Vec<i32> = (0..44_100)
+                .map(|i| ((i * 7919) % 65_536 - 32_768) << 16)
+                .collect();
+            let source = RawAudioData::new(
+                HashMap::from([
+                    (AudioChannels::FrontLeft, plane.clone()),
+                    (AudioChannels::FrontRight, plane),
+                ]),
+                44_100,
+                16,
+                SampleFormat::S16,
+                Vec::new(),
+                None,
+                Vec::new(),
+            );
+            let source_path = dir.join("source.wav");
+            fs::write(&source_path, source.encode_to_wav().unwrap()).unwrap();
+            let config = VorbisConfig::default();
+
+            let mut streamed = Cursor::new(Vec::new());
+            AudioStream::new_from_path(&source_path, CancellationToken::new())
+                .unwrap()
+                .encode_to_vorbis_writer(&config, &mut streamed)
+                .unwrap();
+            let streamed_path = dir.join("streamed.ogg");
+            fs::write(&streamed_path, streamed.into_inner()).unwrap();
+            let in_memory = RawAudioData::new_from_path(&source_path, &CancellationToken::new())
+                .unwrap()
+                .encode_to_vorbis(&config)
+                .unwrap();
+            let in_memory_path = dir.join("in-memory.ogg");
+            fs::write(&in_memory_path, in_memory).unwrap();
+
+            let streamed =
+                RawAudioData::new_from_path(&streamed_path, &CancellationToken::new()).unwrap();
+            let in_memory =
+                RawAudioData::new_from_path(&in_memory_path, &CancellationToken::new()).unwrap();
+            assert_eq!(streamed.get_sample_rate(), 44_100);
+            assert_eq!(streamed.get_no_of_channels(), 2);
+            // the decoder keeps the padding of the last block, so the length
+            // is compared with the output of the other encoder
+            let streamed_len = streamed.get_audio_data()[&AudioChannels::FrontLeft].len();
+            assert!(streamed_len >= 44_100);
+            assert_eq!(
+                streamed_len,
+                in_memory.get_audio_data()[&AudioChannels::FrontLeft].len()
+            );
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+}
+
+fn get_correct_strategy(
+    config: &VorbisConfig,
+) -> Result<vorbis_rs::VorbisBitrateManagementStrategy, String> {
+    match config.mode() {
+        // libvorbis takes the quality from -0.1 to 1.0 instead of -1 to 10
+        VorbisBitrateMode::QualityVbr => {
+            Ok(vorbis_rs::VorbisBitrateManagementStrategy::QualityVbr {
+                target_quality: config.quality.clamp(-1.0, 10.0) / 10.0,
+            })
+        }
+        VorbisBitrateMode::NominalBitrate => {
+            match std::num::NonZeroU32::new(config.bitrate.clamp(0, 500) as u32 * 1000) {
+                Some(target_bitrate) => {
+                    Ok(vorbis_rs::VorbisBitrateManagementStrategy::Vbr { target_bitrate })
+                }
+                None => Err("Vorbis nominal bitrate must be greater than 0".to_string()),
+            }
+        }
+    }
+}
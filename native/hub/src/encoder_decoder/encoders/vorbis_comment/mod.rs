@@ -0,0 +1,321 @@
+pub mod vorbis_comment {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use symphonia::core::meta::{ColorMode, StandardTagKey, Tag, Value, Visual};
+
+    use crate::encoder_decoder::encoders::id3::id3::{get_picture_description, get_picture_type};
+
+    // Vorbis comments (and FLAC pictures) are shared by the Ogg and FLAC
+    // based encoders, this module holds the common serialisation.
+
+    pub const VENDOR_STRING: &str = concat!("Diraudio ", env!("CARGO_PKG_VERSION"));
+
+    /// Returns every text tag as a `(KEY, value)` pair. Tags
+    /// symphonia knows the meaning of get the usual Vorbis field name, so an
+    /// ID3 `TIT2` or an MP4 `©nam` becomes `TITLE`. Other keys are kept,
+    /// upper-cased as recommended by the Vorbis comment specification.
+    pub fn get_vorbis_comments(tags: &[Tag]) -> Vec<(String, String)> {
+        let mut comments = Vec::new();
+        for tag in tags {
+            // binary values can not be represented as a comment
+            if let Value::Binary(_) = tag.value {
+                continue;
+            }
+            let value = tag.value.to_string();
+            let key = match tag.std_key {
+                Some(std_key) => get_field_name(std_key).to_string(),
+                None => get_unknown_field_name(&tag.key),
+            };
+            if key.is_empty() {
+                continue;
+            }
+            // ID3 keeps the total in the same frame, like `3/12`
+            let total_key = match tag.std_key {
+                Some(StandardTagKey::TrackNumber) => Some(StandardTagKey::TrackTotal),
+                Some(StandardTagKey::DiscNumber) => Some(StandardTagKey::DiscTotal),
+                _ => None,
+            };
+            if let (Some(total_key), Some((number, total))) = (total_key, value.split_once('/')) {
+                comments.push((key, number.trim().to_string()));
+                if !has_tag(tags, total_key) && !total.trim().is_empty() {
+                    comments.push((
+                        get_field_name(total_key).to_string(),
+                        total.trim().to_string(),
+                    ));
+                }
+                continue;
+            }
+            comments.push((key, value));
+        }
+        comments
+    }
+
+    fn has_tag(tags: &[Tag], std_key: StandardTagKey) -> bool {
+        tags.iter().any(|tag| tag.std_key == Some(std_key))
+    }
+
+    // Names as written by MusicBrainz Picard and read back by symphonia.
+    fn get_field_name(std_key: StandardTagKey) -> &'static str {
+        match std_key {
+            StandardTagKey::AcoustidFingerprint => "ACOUSTID_FINGERPRINT",
+            StandardTagKey::AcoustidId => "ACOUSTID_ID",
+            StandardTagKey::Album => "ALBUM",
+            StandardTagKey::AlbumArtist => "ALBUMARTIST",
+            StandardTagKey::Arranger => "ARRANGER",
+            StandardTagKey::Artist => "ARTIST",
+            StandardTagKey::Bpm => "BPM",
+            StandardTagKey::Comment => "COMMENT",
+            StandardTagKey::Compilation => "COMPILATION",
+            StandardTagKey::Composer => "COMPOSER",
+            StandardTagKey::Conductor => "CONDUCTOR",
+            StandardTagKey::ContentGroup => "GROUPING",
+            StandardTagKey::Copyright => "COPYRIGHT",
+            StandardTagKey::Date => "DATE",
+            StandardTagKey::Description => "DESCRIPTION",
+            StandardTagKey::DiscNumber => "DISCNUMBER",
+            StandardTagKey::DiscSubtitle => "DISCSUBTITLE",
+            StandardTagKey::DiscTotal => "DISCTOTAL",
+            StandardTagKey::EncodedBy => "ENCODEDBY",
+            StandardTagKey::Encoder => "ENCODER",
+            StandardTagKey::EncoderSettings => "ENCODERSETTINGS",
+            StandardTagKey::EncodingDate => "ENCODINGDATE",
+            StandardTagKey::Engineer => "ENGINEER",
+            StandardTagKey::Ensemble => "ENSEMBLE",
+            StandardTagKey::Genre => "GENRE",
+            StandardTagKey::IdentAsin => "ASIN",
+            StandardTagKey::IdentBarcode => "BARCODE",
+            StandardTagKey::IdentCatalogNumber => "CATALOGNUMBER",
+            StandardTagKey::IdentEanUpn => "EAN/UPN",
+            StandardTagKey::IdentIsrc => "ISRC",
+            StandardTagKey::IdentPn => "PRODUCTNUMBER",
+            StandardTagKey::IdentPodcast => "PODCASTID",
+            StandardTagKey::IdentUpc => "UPC",
+            StandardTagKey::Label => "LABEL",
+            StandardTagKey::Language => "LANGUAGE",
+            StandardTagKey::License => "LICENSE",
+            StandardTagKey::Lyricist => "LYRICIST",
+            StandardTagKey::Lyrics => "LYRICS",
+            StandardTagKey::MediaFormat => "MEDIA",
+            StandardTagKey::MixDj => "DJMIXER",
+            StandardTagKey::MixEngineer => "MIXER",
+            StandardTagKey::Mood => "MOOD",
+            StandardTagKey::MovementName => "MOVEMENTNAME",
+            StandardTagKey::MovementNumber => "MOVEMENT",
+            StandardTagKey::MusicBrainzAlbumArtistId => "MUSICBRAINZ_ALBUMARTISTID",
+            StandardTagKey::MusicBrainzAlbumId => "MUSICBRAINZ_ALBUMID",
+            StandardTagKey::MusicBrainzArtistId => "MUSICBRAINZ_ARTISTID",
+            StandardTagKey::MusicBrainzDiscId => "MUSICBRAINZ_DISCID",
+            StandardTagKey::MusicBrainzGenreId => "MUSICBRAINZ_GENREID",
+            StandardTagKey::MusicBrainzLabelId => "MUSICBRAINZ_LABELID",
+            StandardTagKey::MusicBrainzOriginalAlbumId => "MUSICBRAINZ_ORIGINALALBUMID",
+            StandardTagKey::MusicBrainzOriginalArtistId => "MUSICBRAINZ_ORIGINALARTISTID",
+            StandardTagKey::MusicBrainzRecordingId => "MUSICBRAINZ_TRACKID",
+            StandardTagKey::MusicBrainzReleaseGroupId => "MUSICBRAINZ_RELEASEGROUPID",
+            StandardTagKey::MusicBrainzReleaseStatus => "RELEASESTATUS",
+            StandardTagKey::MusicBrainzReleaseTrackId => "MUSICBRAINZ_RELEASETRACKID",
+            StandardTagKey::MusicBrainzReleaseType => "RELEASETYPE",
+            StandardTagKey::MusicBrainzTrackId => "MUSICBRAINZ_TRACKID",
+            StandardTagKey::MusicBrainzWorkId => "MUSICBRAINZ_WORKID",
+            StandardTagKey::Opus => "OPUS",
+            StandardTagKey::OriginalAlbum => "ORIGINALALBUM",
+            StandardTagKey::OriginalArtist => "ORIGINALARTIST",
+            StandardTagKey::OriginalDate => "ORIGINALDATE",
+            StandardTagKey::OriginalFile => "ORIGINALFILENAME",
+            StandardTagKey::OriginalWriter => "ORIGINALLYRICIST",
+            StandardTagKey::Owner => "OWNER",
+            StandardTagKey::Part => "PART",
+            StandardTagKey::PartTotal => "PARTTOTAL",
+            StandardTagKey::Performer => "PERFORMER",
+            StandardTagKey::Podcast => "PODCAST",
+            StandardTagKey::PodcastCategory => "PODCASTCATEGORY",
+            StandardTagKey::PodcastDescription => "PODCASTDESC",
+            StandardTagKey::PodcastKeywords => "PODCASTKEYWORDS",
+            StandardTagKey::Producer => "PRODUCER",
+            StandardTagKey::PurchaseDate => "PURCHASEDATE",
+            StandardTagKey::Rating => "RATING",
+            StandardTagKey::ReleaseCountry => "RELEASECOUNTRY",
+            StandardTagKey::ReleaseDate => "RELEASEDATE",
+            StandardTagKey::Remixer => "REMIXER",
+            StandardTagKey::ReplayGainAlbumGain => "REPLAYGAIN_ALBUM_GAIN",
+            StandardTagKey::ReplayGainAlbumPeak => "REPLAYGAIN_ALBUM_PEAK",
+            StandardTagKey::ReplayGainTrackGain => "REPLAYGAIN_TRACK_GAIN",
+            StandardTagKey::ReplayGainTrackPeak => "REPLAYGAIN_TRACK_PEAK",
+            StandardTagKey::Script => "SCRIPT",
+            StandardTagKey::SortAlbum => "ALBUMSORT",
+            StandardTagKey::SortAlbumArtist => "ALBUMARTISTSORT",
+            StandardTagKey::SortArtist => "ARTISTSORT",
+            StandardTagKey::SortComposer => "COMPOSERSORT",
+            StandardTagKey::SortTrackTitle => "TITLESORT",
+            StandardTagKey::TaggingDate => "TAGGINGDATE",
+            StandardTagKey::TrackNumber => "TRACKNUMBER",
+            StandardTagKey::TrackSubtitle => "SUBTITLE",
+            StandardTagKey::TrackTitle => "TITLE",
+            StandardTagKey::TrackTotal => "TRACKTOTAL",
+            StandardTagKey::TvEpisode => "TVEPISODE",
+            StandardTagKey::TvEpisodeTitle => "TVEPISODETITLE",
+            StandardTagKey::TvNetwork => "TVNETWORK",
+            StandardTagKey::TvSeason => "TVSEASON",
+            StandardTagKey::TvShowTitle => "TVSHOW",
+            StandardTagKey::Url => "URL",
+            StandardTagKey::UrlArtist => "WEBSITE",
+            StandardTagKey::UrlCopyright => "URL_COPYRIGHT",
+            StandardTagKey::UrlInternetRadio => "URL_RADIO",
+            StandardTagKey::UrlLabel => "URL_LABEL",
+            StandardTagKey::UrlOfficial => "URL_OFFICIAL",
+            StandardTagKey::UrlPayment => "URL_PAYMENT",
+            StandardTagKey::UrlPodcast => "URL_PODCAST",
+            StandardTagKey::UrlPurchase => "URL_PURCHASE",
+            StandardTagKey::UrlSource => "URL_SOURCE",
+            StandardTagKey::Version => "VERSION",
+            StandardTagKey::Writer => "WRITER",
+        }
+    }
+
+    // User defined fields are stored under their own name, without the
+    // `TXXX:` of ID3 or the `----:com.apple.iTunes:` of MP4 in front of it.
+    fn get_unknown_field_name(key: &str) -> String {
+        let name = match key.starts_with("TXXX:") || key.starts_with("----:") {
+            true => key.rsplit(':').next().unwrap_or_default(),
+            false => key,
+        };
+        // field names are printable ASCII without `=`
+        name.chars()
+            .filter(|c| (' '..='}').contains(c) && *c != '=')
+            .collect::<String>()
+            .to_uppercase()
+    }
+
+    /// Serialises a complete comment header body, without any of the
+    /// codec specific magic in front of it.
+    pub fn get_comment_header(comments: &[(String, String)]) -> Vec<u8> {
+        let mut header = Vec::new();
+        header.extend_from_slice(&(VENDOR_STRING.len() as u32).to_le_bytes());
+        header.extend_from_slice(VENDOR_STRING.as_bytes());
+        header.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+        for (key, value) in comments {
+            let comment = format!("{}={}", key, value);
+            header.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            header.extend_from_slice(comment.as_bytes());
+        }
+        header
+    }
+
+    /// Serialises the visual as the body of a FLAC `METADATA_BLOCK_PICTURE`.
+    pub fn get_picture_block(visual: &Visual) -> Vec<u8> {
+        let description = get_picture_description(visual);
+        let (width, height) = match visual.dimensions {
+            Some(size) => (size.width, size.height),
+            None => (0, 0),
+        };
+        let depth = visual.bits_per_pixel.map(|bits| bits.get()).unwrap_or(0);
+        let colors = match visual.color_mode {
+            Some(ColorMode::Indexed(colors)) => colors.get(),
+            _ => 0,
+        };
+
+        let mut block = Vec::new();
+        block.extend_from_slice(&(get_picture_type(visual.usage) as u32).to_be_bytes());
+        block.extend_from_slice(&(visual.media_type.len() as u32).to_be_bytes());
+        block.extend_from_slice(visual.media_type.as_bytes());
+        block.extend_from_slice(&(description.len() as u32).to_be_bytes());
+        block.extend_from_slice(description.as_bytes());
+        block.extend_from_slice(&width.to_be_bytes());
+        block.extend_from_slice(&height.to_be_bytes());
+        block.extend_from_slice(&depth.to_be_bytes());
+        block.extend_from_slice(&colors.to_be_bytes());
+        block.extend_from_slice(&(visual.data.len() as u32).to_be_bytes());
+        block.extend_from_slice(&visual.data);
+        block
+    }
+
+    /// Returns the `METADATA_BLOCK_PICTURE` comment used to embed the
+    /// visual in Ogg streams.
+    pub fn get_picture_comment(visual: &Visual) -> (String, String) {
+        (
+            "METADATA_BLOCK_PICTURE".to_string(),
+            STANDARD.encode(get_picture_block(visual)),
+        )
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::{fs, path::Path};
+
+        use symphonia::core::meta::{StandardTagKey, Tag, Value};
+
+        use super::get_vorbis_comments;
+        use crate::{
+            cancellation::CancellationToken,
+            encoder_decoder::{
+                encoders::id3::id3::get_id3v2_tag, raw_audio_data::raw_audio_data::RawAudioData,
+            },
+            messages::dart_signal::Id3Version,
+        };
+
+        // A few silent samples of 16 bit mono PCM in a WAV, behind an ID3v2
+        // tag.
+        fn write_id3_tagged_file(path: &Path, tags: &[Tag]) {
+            let samples = [0u8; 32];
+            let mut wav = Vec::new();
+            wav.extend_from_slice(b"RIFF");
+            wav.extend_from_slice(&(36 + samples.len() as u32).to_le_bytes());
+            wav.extend_from_slice(b"WAVEfmt ");
+            wav.extend_from_slice(&16u32.to_le_bytes());
+            wav.extend_from_slice(&1u16.to_le_bytes());
+            wav.extend_from_slice(&1u16.to_le_bytes());
+            wav.extend_from_slice(&8000u32.to_le_bytes());
+            wav.extend_from_slice(&16000u32.to_le_bytes());
+            wav.extend_from_slice(&2u16.to_le_bytes());
+            wav.extend_from_slice(&16u16.to_le_bytes());
+            wav.extend_from_slice(b"data");
+            wav.extend_from_slice(&(samples.len() as u32).to_le_bytes());
+            wav.extend_from_slice(&samples);
+
+            let mut data = get_id3v2_tag(tags, &[], Id3Version::Id3v24);
+            data.append(&mut wav);
+            fs::write(path, data).unwrap();
+        }
+
+        #[test]
+        fn names_id3_frames_like_vorbis_fields() {
+            let dir = std::env::temp_dir().join(format!(
+                "diraudio-vorbis-comment-id3-{}",
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("tagged.wav");
+            let tags = vec![
+                Tag::new(Some(StandardTagKey::TrackTitle), "", Value::from("Song")),
+                Tag::new(Some(StandardTagKey::Artist), "", Value::from("Band")),
+                Tag::new(Some(StandardTagKey::Album), "", Value::from("Record")),
+                Tag::new(Some(StandardTagKey::TrackNumber), "", Value::from("3")),
+                Tag::new(Some(StandardTagKey::TrackTotal), "", Value::from("12")),
+                Tag::new(None, "CUSTOM FIELD", Value::from("custom")),
+            ];
+            write_id3_tagged_file(&path, &tags);
+
+            let audio = RawAudioData::new_from_path(&path, &CancellationToken::new()).unwrap();
+            let comments = get_vorbis_comments(audio.get_tags());
+            fs::remove_dir_all(&dir).unwrap();
+
+            let get = |key: &str| {
+                comments
+                    .iter()
+                    .filter(|(comment_key, _)| comment_key == key)
+                    .map(|(_, value)| value.as_str())
+                    .collect::<Vec<&str>>()
+            };
+            assert_eq!(get("TITLE"), vec!["Song"]);
+            assert_eq!(get("ARTIST"), vec!["Band"]);
+            assert_eq!(get("ALBUM"), vec!["Record"]);
+            assert_eq!(get("TRACKNUMBER"), vec!["3"]);
+            assert_eq!(get("TRACKTOTAL"), vec!["12"]);
+            assert_eq!(get("CUSTOM FIELD"), vec!["custom"]);
+            // none of the frame ids are left over
+            for (key, _) in &comments {
+                assert!(!key.starts_with("TIT2") && !key.starts_with("TPE1"));
+                assert!(!key.starts_with("TXXX") && !key.starts_with("TRCK"));
+            }
+        }
+    }
+}
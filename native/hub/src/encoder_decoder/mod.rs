@@ -1,2 +1,5 @@
+pub mod audio_stream;
+pub mod decode_error;
 pub mod encoders;
 pub mod raw_audio_data;
+pub mod source_format;
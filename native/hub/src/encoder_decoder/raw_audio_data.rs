@@ -1,10 +1,16 @@
 pub mod raw_audio_data {
-    use std::{collections::HashMap, fs::File, path::Path};
+    use std::{collections::HashMap, path::Path};
 
     use symphonia::core::{
-        audio::{AudioBufferRef, Channels},
-        io::MediaSourceStream,
         meta::{Tag, VendorData, Visual},
+        sample::SampleFormat,
+    };
+
+    use crate::{
+        cancellation::CancellationToken,
+        encoder_decoder::{
+            audio_stream::audio_stream::AudioStream, decode_error::decode_error::DecodeError,
+        },
     };
 
     #[derive(Clone, Eq, Hash, PartialEq)]
@@ -68,9 +74,11 @@ pub mod raw_audio_data {
         audio_data: HashMap<AudioChannels, Vec<i32>>,
         audio_sample_rate: u32,
         audio_bits_per_sample: u32,
-        image_data: Option<Visual>,
+        audio_sample_format: SampleFormat,
+        image_data: Vec<Visual>,
         vendor_data: Option<VendorData>,
         tag_data: Vec<Tag>,
+        skipped_packets: usize,
     }
 
     impl RawAudioData {
@@ -78,232 +86,71 @@ pub mod raw_audio_data {
             audio: HashMap<AudioChannels, Vec<i32>>,
             sample_rate: u32,
             bits_per_sample: u32,
-            image: Option<Visual>,
+            sample_format: SampleFormat,
+            images: Vec<Visual>,
             vendor: Option<VendorData>,
             tags: Vec<Tag>,
         ) -> Self {
             RawAudioData {
                 audio_data: audio,
                 audio_bits_per_sample: bits_per_sample,
+                audio_sample_format: sample_format,
                 audio_sample_rate: sample_rate,
-                image_data: image,
+                image_data: images,
                 vendor_data: vendor,
                 tag_data: tags,
+                skipped_packets: 0,
             }
         }
 
-        pub fn new_from_path(path: &Path) -> Result<RawAudioData, String> {
-            let codecs = symphonia::default::get_codecs();
-            let probe = symphonia::default::get_probe();
-            let mss = MediaSourceStream::new(
-                Box::new(File::open(path).expect("Setting MediaSource from file path")),
-                Default::default(),
-            );
-            let mut reader = probe
-                .format(
-                    &Default::default(),
-                    mss,
-                    &Default::default(),
-                    &Default::default(),
-                )
-                .unwrap()
-                .format;
-            let tracks = reader.tracks();
-
-            // audio extraction part
-            let mut decoder = codecs
-                .make(&tracks[0].codec_params, &Default::default())
-                .unwrap();
-            //println!("{:?}", decoder.codec_params());
-            let sample_rate = decoder.codec_params().sample_rate.unwrap();
-            let bits_per_sample = decoder.codec_params().bits_per_sample.unwrap();
-            let mut channel_data: HashMap<u8, Vec<i32>> = HashMap::new();
-            let channel_count = tracks[0].codec_params.channels.unwrap();
-            for i in 0..channel_count.count() {
-                channel_data.insert(i.try_into().unwrap(), Vec::new());
-            }
-
-            loop {
-                match reader.next_packet() {
-                    Ok(packet) => {
-                        let decoded = decoder.decode(&packet).unwrap();
-                        match decoded {
-                            AudioBufferRef::S32(buf) => {
-                                let channels = buf.planes();
-                                let channels = channels.planes();
-                                let channel_count = channels.len();
-                                for i in 0..channel_count {
-                                    let mut plane_vec: Vec<i32> = channels[i].to_vec();
-                                    channel_data
-                                        .entry(i.try_into().unwrap())
-                                        .or_insert(Vec::new())
-                                        .append(&mut plane_vec);
-                                }
-                            }
-                            _ => unimplemented!(),
-                        }
-                    }
-                    Err(symphonia::core::errors::Error::IoError(_err)) => {
-                        break;
-                    }
-                    Err(e) => panic!("Failed to decode, error: {}", e),
-                };
-            }
-
-            let channel_mappings = HashMap::from([
-                (
-                    Channels::from_bits_truncate(0x0000_0001),
-                    AudioChannels::FrontLeft,
-                ),
-                (
-                    Channels::from_bits_truncate(0x0000_0002),
-                    AudioChannels::FrontRight,
-                ),
-                (
-                    Channels::from_bits_truncate(0x0000_0004),
-                    AudioChannels::FrontCentre,
-                ),
-                (
-                    Channels::from_bits_truncate(0x0000_0008),
-                    AudioChannels::LFE1,
-                ),
-                (
-                    Channels::from_bits_truncate(0x0000_0010),
-                    AudioChannels::RearLeft,
-                ),
-                (
-                    Channels::from_bits_truncate(0x0000_0020),
-                    AudioChannels::RearRight,
-                ),
-                (
-                    Channels::from_bits_truncate(0x0000_0040),
-                    AudioChannels::FrontLeftCentre,
-                ),
-                (
-                    Channels::from_bits_truncate(0x0000_0080),
-                    AudioChannels::FrontRightCentre,
-                ),
-                (
-                    Channels::from_bits_truncate(0x0000_0100),
-                    AudioChannels::RearCentre,
-                ),
-                (
-                    Channels::from_bits_truncate(0x0000_0200),
-                    AudioChannels::SideLeft,
-                ),
-                (
-                    Channels::from_bits_truncate(0x0000_0400),
-                    AudioChannels::SideRight,
-                ),
-                (
-                    Channels::from_bits_truncate(0x0000_0800),
-                    AudioChannels::TopCentre,
-                ),
-                (
-                    Channels::from_bits_truncate(0x0000_1000),
-                    AudioChannels::TopFrontLeft,
-                ),
-                (
-                    Channels::from_bits_truncate(0x0000_2000),
-                    AudioChannels::TopFrontCentre,
-                ),
-                (
-                    Channels::from_bits_truncate(0x0000_4000),
-                    AudioChannels::TopFrontRight,
-                ),
-                (
-                    Channels::from_bits_truncate(0x0000_8000),
-                    AudioChannels::TopRearLeft,
-                ),
-                (
-                    Channels::from_bits_truncate(0x0001_0000),
-                    AudioChannels::TopRearCentre,
-                ),
-                (
-                    Channels::from_bits_truncate(0x0002_0000),
-                    AudioChannels::TopRearRight,
-                ),
-                (
-                    Channels::from_bits_truncate(0x0004_0000),
-                    AudioChannels::RearLeftCentre,
-                ),
-                (
-                    Channels::from_bits_truncate(0x0008_0000),
-                    AudioChannels::RearRightCentre,
-                ),
-                (
-                    Channels::from_bits_truncate(0x0010_0000),
-                    AudioChannels::FrontLeftWide,
-                ),
-                (
-                    Channels::from_bits_truncate(0x0020_0000),
-                    AudioChannels::FrontRightWide,
-                ),
-                (
-                    Channels::from_bits_truncate(0x0040_0000),
-                    AudioChannels::FrontLeftHigh,
-                ),
-                (
-                    Channels::from_bits_truncate(0x0080_0000),
-                    AudioChannels::FrontCentreHigh,
-                ),
-                (
-                    Channels::from_bits_truncate(0x0100_0000),
-                    AudioChannels::FrontRightHigh,
-                ),
-                (
-                    Channels::from_bits_truncate(0x0200_0000),
-                    AudioChannels::LFE2,
-                ),
-            ]);
-            let named_channels = decoder.codec_params().channels.unwrap();
-            let mut final_channels: HashMap<AudioChannels, Vec<i32>> = HashMap::new();
-            let mut channel_data_key: u8 = 0;
-            for i in named_channels.iter() {
-                final_channels.insert(
-                    channel_mappings.get(&i).unwrap().clone(),
-                    channel_data.get(&channel_data_key).unwrap().to_vec(),
-                );
-                channel_data_key = channel_data_key + 1;
+        pub fn new_from_path(
+            path: &Path,
+            cancel_token: &CancellationToken,
+        ) -> Result<RawAudioData, DecodeError> {
+            let mut audio_stream = AudioStream::new_from_path(path, cancel_token.clone())?;
+            let mut planes: Vec<Vec<i32>> = vec![Vec::new(); audio_stream.get_channels().len()];
+            while let Some(block) = audio_stream.next_block()? {
+                for (plane, block_plane) in planes.iter_mut().zip(block) {
+                    plane.extend_from_slice(&block_plane);
+                }
             }
-            // working with meta data
-            let latest_meta = reader.metadata().skip_to_latest().unwrap().clone();
-            let visual_data = latest_meta.visuals().first().cloned();
-            let vendor_data = latest_meta.vendor_data().first().cloned();
-            let tag_data: Vec<Tag> = latest_meta.tags().to_vec();
-            //println!("{:?}", tag_data);
-
-            let raw_audio_data = RawAudioData::new(
-                final_channels,
-                sample_rate,
-                bits_per_sample,
-                visual_data,
-                vendor_data,
-                tag_data,
+            let audio_data: HashMap<AudioChannels, Vec<i32>> = audio_stream
+                .get_channels()
+                .iter()
+                .cloned()
+                .zip(planes)
+                .collect();
+
+            let mut raw_audio_data = RawAudioData::new(
+                audio_data,
+                audio_stream.get_sample_rate(),
+                audio_stream.get_bit_depth(),
+                audio_stream.get_sample_format(),
+                audio_stream.get_album_art().clone(),
+                audio_stream.get_vendor_data().clone(),
+                audio_stream.get_tags().clone(),
             );
+            raw_audio_data.skipped_packets = audio_stream.get_skipped_packets();
             return Ok(raw_audio_data);
         }
 
-        pub fn get_album_art(&self) -> &Option<Visual> {
+        pub fn get_album_art(&self) -> &Vec<Visual> {
             &self.image_data
         }
 
+        pub fn set_album_art(&mut self, visuals: Vec<Visual>) {
+            self.image_data = visuals;
+        }
+
         pub fn get_approx_size(&self) -> usize {
             let mut size: usize = 0;
             let iter = self.audio_data.iter();
             for i in iter {
                 size = i.1.len() * 4;
             }
-            let size = match &self.image_data {
-                Some(visual) => {
-                    let bits_per_pixel = visual.bits_per_pixel.unwrap();
-                    let height = visual.dimensions.unwrap().height;
-                    let width = visual.dimensions.unwrap().width;
-                    println!("{}", bits_per_pixel);
-                    (u32::from(bits_per_pixel) * height * width) as usize + size
-                }
-                None => size,
-            };
+            for visual in &self.image_data {
+                size += visual.data.len();
+            }
             size
         }
 
@@ -323,9 +170,70 @@ pub mod raw_audio_data {
             self.audio_bits_per_sample
         }
 
+        pub fn get_sample_format(&self) -> SampleFormat {
+            self.audio_sample_format
+        }
+
+        pub fn get_skipped_packets(&self) -> usize {
+            self.skipped_packets
+        }
+
         pub fn get_audio_data(&self) -> &HashMap<AudioChannels, Vec<i32>> {
             return &self.audio_data;
         }
+
+        pub fn get_stereo_pair(&self) -> Result<(&Vec<i32>, &Vec<i32>), String> {
+            let audio_data = self.get_audio_data();
+            let left = match audio_data.get(&AudioChannels::FrontLeft) {
+                Some(data) => data,
+                None => match audio_data.get(&AudioChannels::SideLeft) {
+                    Some(data) => data,
+                    None => match audio_data.get(&AudioChannels::RearLeft) {
+                        Some(data) => data,
+                        None => return Err("Could not find appropriate left channel".to_string()),
+                    },
+                },
+            };
+
+            let right = match audio_data.get(&AudioChannels::FrontRight) {
+                Some(data) => data,
+                None => match audio_data.get(&AudioChannels::SideRight) {
+                    Some(data) => data,
+                    None => match audio_data.get(&AudioChannels::RearRight) {
+                        Some(data) => data,
+                        None => return Err("Could not find appropriate right channel".to_string()),
+                    },
+                },
+            };
+
+            Ok((left, right))
+        }
+
+        pub fn get_ordered_channels(&self) -> Vec<(&AudioChannels, &Vec<i32>)> {
+            // the discriminants follow the WAVE channel order, which is also
+            // the order expected by FLAC and most other containers
+            let mut channels: Vec<(&AudioChannels, &Vec<i32>)> = self.audio_data.iter().collect();
+            channels.sort_by_key(|(channel, _)| (*channel).clone() as u32);
+            channels
+        }
+
+        pub fn get_f32_mono_or_stereo(&self) -> Result<Vec<Vec<f32>>, String> {
+            let to_f32 = |plane: &Vec<i32>| -> Vec<f32> {
+                plane
+                    .iter()
+                    .map(|sample| *sample as f32 / 2_147_483_648.0)
+                    .collect()
+            };
+            if self.get_no_of_channels() == 1 {
+                match self.get_audio_data().values().next() {
+                    Some(data) => Ok(vec![to_f32(data)]),
+                    None => Err("Could not find any channels in the mono audio file.".to_string()),
+                }
+            } else {
+                let (left, right) = self.get_stereo_pair()?;
+                Ok(vec![to_f32(left), to_f32(right)])
+            }
+        }
     }
 
     impl std::fmt::Display for RawAudioData {
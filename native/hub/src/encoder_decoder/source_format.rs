@@ -0,0 +1,228 @@
+pub mod source_format {
+    use std::{fs::File, path::Path};
+
+    use symphonia::core::{
+        codecs::{self, CodecType},
+        io::MediaSourceStream,
+        probe::Hint,
+    };
+
+    use crate::messages::dart_signal::SourceFormat;
+
+    /// Source formats that are converted when the user did not pick any
+    /// specific ones. Lossy files are copied as they are, so they are not
+    /// transcoded a second time.
+    pub const LOSSLESS_SOURCE_FORMATS: [SourceFormat; 4] = [
+        SourceFormat::SourceFlac,
+        SourceFormat::SourceWav,
+        SourceFormat::SourceAiff,
+        SourceFormat::SourceAlac,
+    ];
+
+    // Extensions that can never hold decodable audio, these are not probed.
+    const NON_AUDIO_EXTENSIONS: [&str; 20] = [
+        "jpg", "jpeg", "png", "gif", "bmp", "webp", "tif", "tiff", "txt", "log", "cue", "nfo",
+        "lrc", "pdf", "m3u", "m3u8", "pls", "db", "ini", "sfv",
+    ];
+
+    /// Finds out the source format of the file at `path`.
+    ///
+    /// Files with a well known extension (in any case, `.FLAC` and `.Flac`
+    /// included) are classified by their extension alone, everything else
+    /// is probed by symphonia. Returns `None` if the file is not audio that
+    /// can be decoded.
+    pub fn get_source_format(path: &Path) -> Option<SourceFormat> {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_lowercase());
+        if let Some(extension) = &extension {
+            match extension.as_str() {
+                "flac" => return Some(SourceFormat::SourceFlac),
+                "mp3" => return Some(SourceFormat::SourceMp3),
+                "aif" | "aiff" | "aifc" => return Some(SourceFormat::SourceAiff),
+                "aac" => return Some(SourceFormat::SourceAac),
+                extension if NON_AUDIO_EXTENSIONS.contains(&extension) => return None,
+                // wav, m4a, ogg and friends can carry more than one codec
+                _ => {}
+            }
+        }
+        probe_source_format(path, extension)
+    }
+
+    fn probe_source_format(path: &Path, extension: Option<String>) -> Option<SourceFormat> {
+        let file = File::open(path).ok()?;
+        let mss = MediaSourceStream::new(Box::new(file), Default::default());
+        let mut hint = Hint::new();
+        if let Some(extension) = &extension {
+            hint.with_extension(extension);
+        }
+        let probed = symphonia::default::get_probe()
+            .format(&hint, mss, &Default::default(), &Default::default())
+            .ok()?;
+        let track = probed
+            .format
+            .tracks()
+            .iter()
+            .find(|track| track.codec_params.codec != codecs::CODEC_TYPE_NULL)?;
+        // make sure there actually is a decoder for the codec
+        symphonia::default::get_codecs().get_codec(track.codec_params.codec)?;
+        get_codec_source_format(track.codec_params.codec)
+    }
+
+    fn get_codec_source_format(codec: CodecType) -> Option<SourceFormat> {
+        match codec {
+            codecs::CODEC_TYPE_FLAC => Some(SourceFormat::SourceFlac),
+            codecs::CODEC_TYPE_ALAC => Some(SourceFormat::SourceAlac),
+            codecs::CODEC_TYPE_AAC => Some(SourceFormat::SourceAac),
+            codecs::CODEC_TYPE_VORBIS => Some(SourceFormat::SourceVorbis),
+            codecs::CODEC_TYPE_MP1 | codecs::CODEC_TYPE_MP2 | codecs::CODEC_TYPE_MP3 => {
+                Some(SourceFormat::SourceMp3)
+            }
+            codecs::CODEC_TYPE_ADPCM_MS | codecs::CODEC_TYPE_ADPCM_IMA_WAV => {
+                Some(SourceFormat::SourceAdpcm)
+            }
+            codecs::CODEC_TYPE_PCM_S32BE
+            | codecs::CODEC_TYPE_PCM_S24BE
+            | codecs::CODEC_TYPE_PCM_S16BE
+            | codecs::CODEC_TYPE_PCM_F32BE
+            | codecs::CODEC_TYPE_PCM_F64BE => Some(SourceFormat::SourceAiff),
+            codec if is_pcm(codec) => Some(SourceFormat::SourceWav),
+            _ => None,
+        }
+    }
+
+    fn is_pcm(codec: CodecType) -> bool {
+        [
+            codecs::CODEC_TYPE_PCM_S32LE,
+            codecs::CODEC_TYPE_PCM_S24LE,
+            codecs::CODEC_TYPE_PCM_S16LE,
+            codecs::CODEC_TYPE_PCM_S8,
+            codecs::CODEC_TYPE_PCM_U32LE,
+            codecs::CODEC_TYPE_PCM_U24LE,
+            codecs::CODEC_TYPE_PCM_U16LE,
+            codecs::CODEC_TYPE_PCM_U8,
+            codecs::CODEC_TYPE_PCM_F32LE,
+            codecs::CODEC_TYPE_PCM_F64LE,
+            codecs::CODEC_TYPE_PCM_ALAW,
+            codecs::CODEC_TYPE_PCM_MULAW,
+        ]
+        .contains(&codec)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::{collections::HashMap, fs, path::PathBuf};
+
+        use symphonia::core::sample::SampleFormat;
+
+        use super::{get_source_format, LOSSLESS_SOURCE_FORMATS};
+        use crate::{
+            encoder_decoder::{
+                encoders::{flac::flac::FlacEncoder, pcm::aiff::AiffEncoder, pcm::wav::WavEncoder},
+                raw_audio_data::raw_audio_data::{AudioChannels, RawAudioData},
+            },
+            messages::dart_signal::SourceFormat,
+        };
+
+        fn get_source() -> RawAudioData {
+            RawAudioData::new(
+                HashMap::from([(AudioChannels::FrontCentre, vec![0, 1 << 16, -1 << 16])]),
+                44_100,
+                16,
+                SampleFormat::S16,
+                Vec::new(),
+                None,
+                Vec::new(),
+            )
+        }
+
+        fn get_test_dir(name: &str) -> PathBuf {
+            let dir = std::env::temp_dir().join(format!(
+                "diraudio-source-format-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            dir
+        }
+
+        #[test]
+        fn classifies_well_known_extensions_without_probing() {
+            let dir = get_test_dir("extension");
+            // none of these hold audio, so only the extension can tell
+            for (name, source_format) in [
+                ("a.flac", Some(SourceFormat::SourceFlac)),
+                ("b.FLAC", Some(SourceFormat::SourceFlac)),
+                ("c.Mp3", Some(SourceFormat::SourceMp3)),
+                ("d.aif", Some(SourceFormat::SourceAiff)),
+                ("e.aac", Some(SourceFormat::SourceAac)),
+            ] {
+                let path = dir.join(name);
+                fs::write(&path, b"not audio").unwrap();
+                assert_eq!(get_source_format(&path), source_format, "{}", name);
+            }
+            // audio behind a non audio extension is not probed either
+            let cover = dir.join("cover.JPG");
+            fs::write(&cover, get_source().encode_to_wav().unwrap()).unwrap();
+            assert_eq!(get_source_format(&cover), None);
+            fs::remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn probes_other_files_by_their_content() {
+            let dir = get_test_dir("probe");
+            let source = get_source();
+            for (name, data, source_format) in [
+                (
+                    "a.wav",
+                    source.encode_to_wav().unwrap(),
+                    Some(SourceFormat::SourceWav),
+                ),
+                (
+                    "b",
+                    source.encode_to_wav().unwrap(),
+                    Some(SourceFormat::SourceWav),
+                ),
+                (
+                    "c.dat",
+                    source.encode_to_aiff().unwrap(),
+                    Some(SourceFormat::SourceAiff),
+                ),
+                (
+                    "d.bin",
+                    source.encode_to_flac(&Default::default()).unwrap(),
+                    Some(SourceFormat::SourceFlac),
+                ),
+                ("e.wav", b"not audio".to_vec(), None),
+            ] {
+                let path = dir.join(name);
+                fs::write(&path, data).unwrap();
+                assert_eq!(get_source_format(&path), source_format, "{}", name);
+            }
+            assert_eq!(get_source_format(&dir.join("missing.wav")), None);
+            fs::remove_dir_all(&dir).unwrap();
+        }
+
+        #[test]
+        fn converts_only_lossless_sources_by_default() {
+            for source_format in [
+                SourceFormat::SourceFlac,
+                SourceFormat::SourceWav,
+                SourceFormat::SourceAiff,
+                SourceFormat::SourceAlac,
+            ] {
+                assert!(LOSSLESS_SOURCE_FORMATS.contains(&source_format));
+            }
+            for source_format in [
+                SourceFormat::SourceAac,
+                SourceFormat::SourceVorbis,
+                SourceFormat::SourceMp3,
+                SourceFormat::SourceAdpcm,
+            ] {
+                assert!(!LOSSLESS_SOURCE_FORMATS.contains(&source_format));
+            }
+        }
+    }
+}
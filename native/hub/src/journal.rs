@@ -0,0 +1,297 @@
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use prost::Message;
+
+use crate::{atomic_file::write_atomically, messages::dart_signal::Convert};
+
+/// Name of the journal, kept in the root of the destination while a job is
+/// unfinished, that lets a killed or cancelled job be resumed.
+pub const JOURNAL_FILE_NAME: &str = ".diraudio-journal";
+
+const JOURNAL_HEADER: &str = "diraudio-journal 1";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum FileState {
+    Pending,
+    InProgress,
+    Done,
+    Failed,
+}
+
+impl FileState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            FileState::Pending => "pending",
+            FileState::InProgress => "in-progress",
+            FileState::Done => "done",
+            FileState::Failed => "failed",
+        }
+    }
+
+    fn from_str(state: &str) -> Option<FileState> {
+        match state {
+            "pending" => Some(FileState::Pending),
+            "in-progress" => Some(FileState::InProgress),
+            "done" => Some(FileState::Done),
+            "failed" => Some(FileState::Failed),
+            _ => None,
+        }
+    }
+}
+
+pub struct JournalEntry {
+    pub file_path: PathBuf,
+    pub state: FileState,
+    pub checksum: Option<String>,
+}
+
+/// Append only record of a job. The first lines hold the instructions the
+/// job was started with, every following line is a state change of a file,
+/// the last one of a file being its current state.
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+}
+
+impl Journal {
+    /// Starts a new journal in the destination with the given file states.
+    pub fn create(convert: &Convert, entries: &[JournalEntry]) -> Result<Journal, String> {
+        let path = Path::new(&convert.dest_path).join(JOURNAL_FILE_NAME);
+        let mut contents = format!(
+            "{}\n{}\n",
+            JOURNAL_HEADER,
+            STANDARD.encode(convert.encode_to_vec())
+        );
+        for entry in entries {
+            contents.push_str(&get_entry_line(
+                &entry.file_path,
+                entry.state,
+                &entry.checksum,
+            ));
+        }
+        write_atomically(&path, contents.as_bytes())?;
+        let file = match OpenOptions::new().append(true).open(&path) {
+            Ok(file) => file,
+            Err(err) => return Err(err.to_string()),
+        };
+        Ok(Journal { path, file })
+    }
+
+    /// Reads the journal of the destination, returning the instructions of
+    /// the job and the current state of each of its files.
+    pub fn load(dest_path: &str) -> Result<(Convert, Vec<JournalEntry>), String> {
+        let path = Path::new(dest_path).join(JOURNAL_FILE_NAME);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) => return Err(err.to_string()),
+        };
+        let mut lines = contents.lines();
+        if lines.next() != Some(JOURNAL_HEADER) {
+            return Err("Not a conversion journal".to_string());
+        }
+        let convert = match lines.next().map(|line| STANDARD.decode(line)) {
+            Some(Ok(bytes)) => match Convert::decode(bytes.as_slice()) {
+                Ok(convert) => convert,
+                Err(err) => return Err(err.to_string()),
+            },
+            _ => return Err("The journal has no instructions".to_string()),
+        };
+
+        // a line cut short by a crash is simply ignored
+        let mut order: Vec<PathBuf> = Vec::new();
+        let mut states: HashMap<PathBuf, (FileState, Option<String>)> = HashMap::new();
+        for line in lines {
+            let mut fields = line.splitn(3, '\t');
+            let (state, checksum, file_path) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(state), Some(checksum), Some(file_path)) => (state, checksum, file_path),
+                _ => continue,
+            };
+            let state = match FileState::from_str(state) {
+                Some(state) => state,
+                None => continue,
+            };
+            let checksum = match checksum {
+                "-" => None,
+                checksum => Some(checksum.to_string()),
+            };
+            let file_path = match decode_path(file_path) {
+                Some(file_path) => file_path,
+                None => continue,
+            };
+            if states
+                .insert(file_path.clone(), (state, checksum))
+                .is_none()
+            {
+                order.push(file_path);
+            }
+        }
+        let entries = order
+            .into_iter()
+            .filter_map(|file_path| {
+                let (state, checksum) = states.remove(&file_path)?;
+                Some(JournalEntry {
+                    file_path,
+                    state,
+                    checksum,
+                })
+            })
+            .collect();
+        Ok((convert, entries))
+    }
+
+    pub fn record(
+        &mut self,
+        file_path: &Path,
+        state: FileState,
+        checksum: &Option<String>,
+    ) -> Result<(), String> {
+        let line = get_entry_line(file_path, state, checksum);
+        match self.file.write_all(line.as_bytes()) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    /// Removes the journal once there is nothing left to resume.
+    pub fn remove(self) -> Result<(), String> {
+        drop(self.file);
+        match fs::remove_file(&self.path) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+}
+
+// Every record is one line of text. The path is kept as base64 of its raw
+// bytes, so names that are not valid UTF-8 or have a line break in them are
+// recorded as well.
+fn get_entry_line(file_path: &Path, state: FileState, checksum: &Option<String>) -> String {
+    format!(
+        "{}\t{}\t{}\n",
+        state.as_str(),
+        checksum.as_deref().unwrap_or("-"),
+        encode_path(file_path)
+    )
+}
+
+#[cfg(not(windows))]
+fn encode_path(path: &Path) -> String {
+    use std::os::unix::ffi::OsStrExt;
+    STANDARD.encode(path.as_os_str().as_bytes())
+}
+
+#[cfg(not(windows))]
+fn decode_path(encoded_path: &str) -> Option<PathBuf> {
+    use std::os::unix::ffi::OsStringExt;
+    let bytes = STANDARD.decode(encoded_path).ok()?;
+    Some(PathBuf::from(OsString::from_vec(bytes)))
+}
+
+// Windows paths are UTF-16, unpaired surrogates included.
+#[cfg(windows)]
+fn encode_path(path: &Path) -> String {
+    use std::os::windows::ffi::OsStrExt;
+    let bytes: Vec<u8> = path
+        .as_os_str()
+        .encode_wide()
+        .flat_map(|unit| unit.to_le_bytes())
+        .collect();
+    STANDARD.encode(bytes)
+}
+
+#[cfg(windows)]
+fn decode_path(encoded_path: &str) -> Option<PathBuf> {
+    use std::os::windows::ffi::OsStringExt;
+    let bytes = STANDARD.decode(encoded_path).ok()?;
+    if bytes.len() % 2 != 0 {
+        return None;
+    }
+    let units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .collect();
+    Some(PathBuf::from(OsString::from_wide(&units)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_entry(file_path: PathBuf, state: FileState, checksum: Option<&str>) -> JournalEntry {
+        JournalEntry {
+            file_path,
+            state,
+            checksum: checksum.map(|checksum| checksum.to_string()),
+        }
+    }
+
+    #[test]
+    fn keeps_every_entry_across_a_save_and_load() {
+        let dest_path = std::env::temp_dir().join("diraudio-journal-round-trip");
+        let _ = fs::remove_dir_all(&dest_path);
+        fs::create_dir_all(&dest_path).unwrap();
+        let convert = Convert {
+            src_path: "/music".to_string(),
+            dest_path: dest_path.to_string_lossy().to_string(),
+            no_of_threads: 4,
+            naming_template: "{artist}/{title}".to_string(),
+            ..Default::default()
+        };
+        let mut entries = vec![
+            get_entry(
+                PathBuf::from("Album/01 Intro.flac"),
+                FileState::Pending,
+                None,
+            ),
+            get_entry(
+                PathBuf::from("Album/02 Tab\tand\nline break.flac"),
+                FileState::Pending,
+                None,
+            ),
+            get_entry(
+                PathBuf::from("cover.jpg"),
+                FileState::Done,
+                Some("d41d8cd9"),
+            ),
+        ];
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStringExt;
+            // Latin-1 `café.flac`, which is not valid UTF-8
+            let file_path = OsString::from_vec(b"caf\xe9.flac".to_vec());
+            entries.push(get_entry(PathBuf::from(file_path), FileState::Failed, None));
+        }
+
+        let mut journal = Journal::create(&convert, &entries).unwrap();
+        journal
+            .record(
+                Path::new("Album/02 Tab\tand\nline break.flac"),
+                FileState::Done,
+                &Some("0cc175b9".to_string()),
+            )
+            .unwrap();
+        let (loaded_convert, loaded_entries) = Journal::load(&convert.dest_path).unwrap();
+
+        assert_eq!(loaded_convert, convert);
+        assert_eq!(loaded_entries.len(), entries.len());
+        for (loaded_entry, entry) in loaded_entries.iter().zip(entries.iter()) {
+            assert!(loaded_entry.file_path == entry.file_path);
+        }
+        // the last record of a file is its state
+        assert!(loaded_entries[0].state == FileState::Pending);
+        assert!(loaded_entries[1].state == FileState::Done);
+        assert_eq!(loaded_entries[1].checksum.as_deref(), Some("0cc175b9"));
+        assert_eq!(loaded_entries[2].checksum.as_deref(), Some("d41d8cd9"));
+
+        journal.remove().unwrap();
+        assert!(!dest_path.join(JOURNAL_FILE_NAME).exists());
+        fs::remove_dir_all(&dest_path).unwrap();
+    }
+}
@@ -3,14 +3,30 @@
 
 mod messages;
 
+mod artwork;
+mod atomic_file;
+mod cancellation;
+mod conflict;
 mod conversion_handler;
-mod encoder_decoder;
+pub mod encoder_decoder;
+mod journal;
+mod mirror;
+mod naming;
+mod passthrough;
+mod planner;
 pub mod progress_report_buffer;
-use conversion_handler::{handle_conversion, ConversionInstructions};
+mod sync_state;
+mod walk_filter;
+use cancellation::CancellationToken;
+use conversion_handler::{handle_conversion, resume_conversion, ConversionInstructions};
+use encoder_decoder::source_format::source_format::LOSSLESS_SOURCE_FORMATS;
+use journal::Journal;
+use planner::plan_conversion;
+use walk_filter::WalkFilter;
 
-use messages::rust_signal::TotalNumberOfFilesFound;
+use messages::rust_signal::{ConversionPlan, MessageType, ProgressUpdate, TotalNumberOfFilesFound};
 use rinf::debug_print;
-use std::sync::Arc;
+use std::{path::Path, sync::Arc};
 use tokio::{self, sync::Mutex};
 // use tokio_with_wasm::tokio; // Uncomment this line to target the web
 
@@ -24,74 +40,215 @@ rinf::write_interface!();
 
 enum AppState {
     Convert,
+    Paused,
     DoNothing,
 }
 
 async fn main() {
     let app_state = Arc::new(Mutex::new(AppState::DoNothing));
-    tokio::spawn(dart_listen_start(Arc::clone(&app_state)));
-    tokio::spawn(dart_listen_cancel(Arc::clone(&app_state)));
+    // token of the conversion that is running, `None` while nothing runs
+    let running_job = Arc::new(Mutex::new(None));
+    tokio::spawn(dart_listen_start(
+        Arc::clone(&app_state),
+        Arc::clone(&running_job),
+    ));
+    tokio::spawn(dart_listen_cancel(
+        Arc::clone(&app_state),
+        Arc::clone(&running_job),
+    ));
+    tokio::spawn(dart_listen_pause(
+        Arc::clone(&app_state),
+        Arc::clone(&running_job),
+    ));
+    tokio::spawn(dart_listen_resume(
+        Arc::clone(&app_state),
+        Arc::clone(&running_job),
+    ));
+    tokio::spawn(dart_listen_resume_job(
+        Arc::clone(&app_state),
+        Arc::clone(&running_job),
+    ));
     tokio::spawn(dart_listen_check_directory());
+    tokio::spawn(dart_listen_plan_conversion());
 }
 
-async fn dart_listen_cancel(app_state: Arc<Mutex<AppState>>) {
+async fn dart_listen_cancel(
+    app_state: Arc<Mutex<AppState>>,
+    running_job: Arc<Mutex<Option<CancellationToken>>>,
+) {
     use messages::dart_signal::*;
     let mut reciever = Cancel::get_dart_signal_receiver();
     while let Some(_dart_signal) = reciever.recv().await {
         let mut state = app_state.lock().await;
         *state = AppState::DoNothing;
+        if let Some(cancel_token) = running_job.lock().await.as_ref() {
+            cancel_token.cancel();
+        }
         debug_print!("Requested Cancel");
     }
 }
 
-async fn dart_listen_start(app_state: Arc<Mutex<AppState>>) {
+async fn dart_listen_pause(
+    app_state: Arc<Mutex<AppState>>,
+    running_job: Arc<Mutex<Option<CancellationToken>>>,
+) {
     use messages::dart_signal::*;
-    let mut receiver = Convert::get_dart_signal_receiver();
-    while let Some(dart_signal) = receiver.recv().await {
+    let mut reciever = Pause::get_dart_signal_receiver();
+    while let Some(_dart_signal) = reciever.recv().await {
         let mut state = app_state.lock().await;
-        if let AppState::DoNothing = *state {
+        if let AppState::Convert = *state {
+            *state = AppState::Paused;
+            if let Some(cancel_token) = running_job.lock().await.as_ref() {
+                cancel_token.pause();
+            }
+            debug_print!("Requested Pause");
+        }
+    }
+}
+
+async fn dart_listen_resume(
+    app_state: Arc<Mutex<AppState>>,
+    running_job: Arc<Mutex<Option<CancellationToken>>>,
+) {
+    use messages::dart_signal::*;
+    let mut reciever = Resume::get_dart_signal_receiver();
+    while let Some(_dart_signal) = reciever.recv().await {
+        let mut state = app_state.lock().await;
+        if let AppState::Paused = *state {
             *state = AppState::Convert;
+            if let Some(cancel_token) = running_job.lock().await.as_ref() {
+                cancel_token.resume();
+            }
+            debug_print!("Requested Resume");
         }
-        drop(state);
+    }
+}
+
+async fn dart_listen_start(
+    app_state: Arc<Mutex<AppState>>,
+    running_job: Arc<Mutex<Option<CancellationToken>>>,
+) {
+    use messages::dart_signal::*;
+    let mut receiver = Convert::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
         let message = dart_signal.message.clone();
         debug_print!("{}", message.src_path);
         debug_print!("{}", message.dest_path);
         debug_print!("{}", message.copy_unrecognised_files);
-        let instruction = ConversionInstructions {
-            copy_unrecognised_files: message.copy_unrecognised_files,
-            src_path: message.src_path.clone(),
-            dest_path: message.dest_path.clone(),
-            no_of_threads: message.no_of_threads.clone(),
-            target_format: dart_signal.message.target_format(),
-            mp3_config: Mp3Config {
-                quality: message.mp3_config.clone().unwrap().quality,
-                bitrate: message.mp3_config.clone().unwrap().bitrate,
-            },
-        };
+        let instruction = get_conversion_instructions(&message);
+        if !claim_job(&app_state, &running_job, &instruction.cancel_token).await {
+            continue;
+        }
         let transfered_app_state = Arc::clone(&app_state);
+        let transfered_running_job = Arc::clone(&running_job);
 
-        tokio::spawn(async {
+        tokio::spawn(async move {
             debug_print!("Starting handle_conversion");
-            handle_conversion(instruction, transfered_app_state).await;
+            handle_conversion(instruction, Arc::clone(&transfered_app_state)).await;
+            release_job(&transfered_app_state, &transfered_running_job).await;
             debug_print!("Finished handle_conversion");
         });
     }
 }
 
+async fn dart_listen_resume_job(
+    app_state: Arc<Mutex<AppState>>,
+    running_job: Arc<Mutex<Option<CancellationToken>>>,
+) {
+    use messages::dart_signal::*;
+    let mut reciever = ResumeJob::get_dart_signal_receiver();
+    while let Some(dart_signal) = reciever.recv().await {
+        let (message, entries) = match Journal::load(&dart_signal.message.dest_path) {
+            Ok(journal) => journal,
+            Err(err) => {
+                debug_print!("Failed to load the job journal: {}", err);
+                TotalNumberOfFilesFound {
+                    files_found: false,
+                    number: 0,
+                }
+                .send_signal_to_dart();
+                continue;
+            }
+        };
+        let instruction = get_conversion_instructions(&message);
+        if !claim_job(&app_state, &running_job, &instruction.cancel_token).await {
+            continue;
+        }
+        let transfered_app_state = Arc::clone(&app_state);
+        let transfered_running_job = Arc::clone(&running_job);
+
+        tokio::spawn(async move {
+            debug_print!("Resuming handle_conversion");
+            resume_conversion(instruction, entries, Arc::clone(&transfered_app_state)).await;
+            release_job(&transfered_app_state, &transfered_running_job).await;
+            debug_print!("Finished handle_conversion");
+        });
+    }
+}
+
+// Makes the job of `cancel_token` the running one. A job that is still
+// running, even if it is only winding down after a cancel, is left alone and
+// the new one is refused, so every job can always be cancelled and paused.
+async fn claim_job(
+    app_state: &Arc<Mutex<AppState>>,
+    running_job: &Arc<Mutex<Option<CancellationToken>>>,
+    cancel_token: &CancellationToken,
+) -> bool {
+    let mut state = app_state.lock().await;
+    let mut running_job = running_job.lock().await;
+    if running_job.is_some() {
+        debug_print!("Refused to start, a conversion is already running");
+        ProgressUpdate {
+            msg: "A conversion is already running, wait for it to finish or cancel it first"
+                .to_string(),
+            handling_thread: 0,
+            message_type: MessageType::Fail.into(),
+        }
+        .send_signal_to_dart();
+        return false;
+    }
+    // a pause left over from the last job must not hold up this one
+    *state = AppState::Convert;
+    *running_job = Some(cancel_token.clone());
+    true
+}
+
+// Leaves the app idle once the running job is over, finished or cancelled.
+async fn release_job(
+    app_state: &Arc<Mutex<AppState>>,
+    running_job: &Arc<Mutex<Option<CancellationToken>>>,
+) {
+    let mut state = app_state.lock().await;
+    *state = AppState::DoNothing;
+    *running_job.lock().await = None;
+}
+
 async fn dart_listen_check_directory() {
     use crate::conversion_handler::traverse_directory;
     use messages::dart_signal::*;
     let mut reciever = CheckDirectory::get_dart_signal_receiver();
     while let Some(check_dir) = reciever.recv().await {
+        let filter_config = check_dir.message.filter_config.unwrap_or_default();
         let check_dir = check_dir.message.src;
         debug_print!("{}", check_dir);
-        let mut list_of_files: Vec<String> = Vec::new();
-        match traverse_directory(&check_dir, &mut list_of_files, check_dir.len()) {
-            Ok(list_of_files) => {
-                debug_print!("{}", list_of_files.len());
+        match WalkFilter::new_from_config(&filter_config)
+            .and_then(|filter| traverse_directory(Path::new(&check_dir), &filter))
+        {
+            Ok(walk) => {
+                debug_print!("{}", walk.files.len());
+                // entries that could not be walked are left out of the count,
+                // the app lists why
+                for warning in walk.warnings {
+                    ProgressUpdate {
+                        msg: warning,
+                        handling_thread: 0,
+                        message_type: MessageType::Warning.into(),
+                    }
+                    .send_signal_to_dart();
+                }
                 TotalNumberOfFilesFound {
                     files_found: true,
-                    number: list_of_files.len() as i32,
+                    number: walk.files.len() as i32,
                 }
                 .send_signal_to_dart();
             }
@@ -105,3 +262,58 @@ async fn dart_listen_check_directory() {
         };
     }
 }
+
+async fn dart_listen_plan_conversion() {
+    use messages::dart_signal::*;
+    let mut reciever = PlanConversion::get_dart_signal_receiver();
+    while let Some(dart_signal) = reciever.recv().await {
+        let message = dart_signal.message.convert.clone().unwrap_or_default();
+        let instruction = get_conversion_instructions(&message);
+        // probing every file is blocking work
+        match tokio::task::spawn_blocking(move || plan_conversion(&instruction)).await {
+            Ok(plan) => plan.send_signal_to_dart(),
+            // the app still gets an answer, so it does not wait for a plan
+            // forever
+            Err(err) => {
+                debug_print!("Error in planning thread: {:?}", err);
+                ConversionPlan {
+                    planned: false,
+                    ..Default::default()
+                }
+                .send_signal_to_dart();
+            }
+        }
+    }
+}
+
+fn get_conversion_instructions(message: &messages::dart_signal::Convert) -> ConversionInstructions {
+    use messages::dart_signal::*;
+    let mut convert_formats: Vec<SourceFormat> = message.convert_formats().collect();
+    if convert_formats.is_empty() {
+        convert_formats = LOSSLESS_SOURCE_FORMATS.to_vec();
+    }
+    ConversionInstructions {
+        copy_unrecognised_files: message.copy_unrecognised_files,
+        src_path: message.src_path.clone(),
+        dest_path: message.dest_path.clone(),
+        no_of_threads: message.no_of_threads,
+        target_format: message.target_format(),
+        mp3_config: message.mp3_config.clone().unwrap_or_default(),
+        opus_config: message.opus_config.clone().unwrap_or_default(),
+        vorbis_config: message.vorbis_config.clone().unwrap_or_default(),
+        flac_config: message.flac_config.clone().unwrap_or_default(),
+        convert_formats,
+        sync_config: message.sync_config.clone().unwrap_or_default(),
+        mirror_config: message.mirror_config.clone().unwrap_or_default(),
+        conflict_policy: message.conflict_policy(),
+        naming_template: message.naming_template.clone(),
+        filter_config: message.filter_config.clone().unwrap_or_default(),
+        passthrough_mode: message.passthrough_mode(),
+        artwork_config: message.artwork_config.clone().unwrap_or_default(),
+        file_actions: Arc::default(),
+        collisions: Arc::default(),
+        templated_paths: Arc::default(),
+        artwork_cache: Arc::default(),
+        cancel_token: CancellationToken::new(),
+    }
+}
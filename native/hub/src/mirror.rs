@@ -0,0 +1,322 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::sync::Mutex;
+
+use crate::{
+    conversion_handler::{get_expected_output, traverse_directory, ConversionInstructions},
+    journal::JOURNAL_FILE_NAME,
+    messages::{
+        dart_signal::MirrorMode,
+        rust_signal::{MessageType, ProgressUpdate},
+    },
+    progress_report_buffer::ProgressBuffer,
+    sync_state::SYNC_STATE_FILE_NAME,
+    walk_filter::WalkFilter,
+};
+
+/// Folder in the root of the destination that files without a source are
+/// moved to in `MirrorTrash` mode, one sub folder per run.
+pub const TRASH_FOLDER_NAME: &str = ".diraudio-trash";
+
+/// Removes every file of the destination that no file of `source_files`
+/// would have produced and that was not written in `written_outputs`, or
+/// only lists them in a dry run. Nothing is removed if a part of the source
+/// can not be read.
+pub async fn prune_destination(
+    instruction: &ConversionInstructions,
+    source_files: &[PathBuf],
+    written_outputs: &HashSet<PathBuf>,
+    update_buffer: Arc<Mutex<ProgressBuffer>>,
+) {
+    let mode = instruction.mirror_config.mode();
+    if let MirrorMode::MirrorOff = mode {
+        return;
+    }
+    // with overlapping folders the source itself would look like files
+    // without a source, and so would the outputs of a folder of the source
+    // that could not be read
+    if let Err(err) = check_separate_trees(
+        Path::new(&instruction.src_path),
+        Path::new(&instruction.dest_path),
+    )
+    .and_then(|_| check_complete_source(instruction))
+    {
+        let mut update_buffer_lock = update_buffer.lock().await;
+        update_buffer_lock.add(ProgressUpdate {
+            msg: format!("Skipped mirroring: {}", err),
+            handling_thread: 0,
+            message_type: MessageType::Fail.into(),
+        });
+        return;
+    }
+    let expected_outputs: HashSet<PathBuf> = source_files
+        .iter()
+        .filter_map(|file_path| get_expected_output(instruction, file_path))
+        .collect();
+
+    let dest_path = &instruction.dest_path;
+    let dest_files = match traverse_directory(Path::new(dest_path), &WalkFilter::everything()) {
+        Ok(walk) => walk.files,
+        Err(err) => {
+            let mut update_buffer_lock = update_buffer.lock().await;
+            update_buffer_lock.add(ProgressUpdate {
+                msg: format!("Failed to read the destination for mirroring: {}", err),
+                handling_thread: 0,
+                message_type: MessageType::Fail.into(),
+            });
+            return;
+        }
+    };
+    let run_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let trash_path = Path::new(dest_path)
+        .join(TRASH_FOLDER_NAME)
+        .join(run_time.to_string());
+
+    for file_path in dest_files {
+        let output_path = Path::new(dest_path).join(&file_path);
+        if expected_outputs.contains(&output_path)
+            || written_outputs.contains(&output_path)
+            || is_bookkeeping_file(&file_path)
+        {
+            continue;
+        }
+        let update = if instruction.mirror_config.dry_run {
+            ProgressUpdate {
+                msg: format!("Would remove {} as it has no source", file_path.display()),
+                handling_thread: 0,
+                message_type: MessageType::Pruned.into(),
+            }
+        } else {
+            let removal = match mode {
+                MirrorMode::MirrorTrash => move_to_trash(&output_path, &trash_path, &file_path),
+                _ => fs::remove_file(&output_path).map_err(|err| err.to_string()),
+            };
+            match removal {
+                Ok(_) => {
+                    remove_empty_parents(&output_path, Path::new(dest_path));
+                    ProgressUpdate {
+                        msg: format!("Removed {} as it has no source", file_path.display()),
+                        handling_thread: 0,
+                        message_type: MessageType::Pruned.into(),
+                    }
+                }
+                Err(err) => ProgressUpdate {
+                    msg: format!("Failed to remove {}: {}", file_path.display(), err),
+                    handling_thread: 0,
+                    message_type: MessageType::Fail.into(),
+                },
+            }
+        };
+        let mut update_buffer_lock = update_buffer.lock().await;
+        update_buffer_lock.add(update);
+    }
+}
+
+/// Makes sure the source and the destination are different folders and
+/// neither of them is inside the other.
+pub fn check_separate_trees(src_path: &Path, dest_path: &Path) -> Result<(), String> {
+    let src_path = match fs::canonicalize(src_path) {
+        Ok(path) => path,
+        Err(err) => return Err(format!("{}: {}", src_path.display(), err)),
+    };
+    let dest_path = match fs::canonicalize(dest_path) {
+        Ok(path) => path,
+        Err(err) => return Err(format!("{}: {}", dest_path.display(), err)),
+    };
+    if src_path == dest_path {
+        return Err("the source and the destination are the same folder".to_string());
+    }
+    if dest_path.starts_with(&src_path) {
+        return Err("the destination is inside the source".to_string());
+    }
+    if src_path.starts_with(&dest_path) {
+        return Err("the source is inside the destination".to_string());
+    }
+    Ok(())
+}
+
+// The source is walked again rather than trusting the walk of the job, a
+// resumed job only has the files of its journal.
+fn check_complete_source(instruction: &ConversionInstructions) -> Result<(), String> {
+    let walk = WalkFilter::new_from_config(&instruction.filter_config)
+        .and_then(|filter| traverse_directory(Path::new(&instruction.src_path), &filter))?;
+    match walk.warnings.first() {
+        Some(warning) => Err(format!(
+            "the source could not be read completely ({})",
+            warning
+        )),
+        None => Ok(()),
+    }
+}
+
+// The sync state, the journal and the trash are ours, they never have a
+// source.
+fn is_bookkeeping_file(file_path: &Path) -> bool {
+    match file_path
+        .components()
+        .find(|component| matches!(component, Component::Normal(_)))
+    {
+        Some(Component::Normal(name)) => {
+            name == SYNC_STATE_FILE_NAME || name == JOURNAL_FILE_NAME || name == TRASH_FOLDER_NAME
+        }
+        _ => false,
+    }
+}
+
+fn move_to_trash(output_path: &Path, trash_path: &Path, file_path: &Path) -> Result<(), String> {
+    let relative_path: PathBuf = file_path
+        .components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect();
+    let target_path = trash_path.join(relative_path);
+    if let Some(parent) = target_path.parent() {
+        if let Err(err) = fs::create_dir_all(parent) {
+            return Err(err.to_string());
+        }
+    }
+    match fs::rename(output_path, target_path) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+// Folders left empty by the removal are removed as well, up to the root of
+// the destination.
+fn remove_empty_parents(output_path: &Path, dest_root: &Path) {
+    let mut directory = output_path.parent();
+    while let Some(path) = directory {
+        if path == dest_root || !path.starts_with(dest_root) || fs::remove_dir(path).is_err() {
+            return;
+        }
+        directory = path.parent();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_test_directory(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("diraudio-mirror-{}", name));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn refuses_the_same_folder() {
+        let root = make_test_directory("same");
+        // a different spelling of the same folder
+        let other_spelling = root.join("music").join("..");
+        fs::create_dir(root.join("music")).unwrap();
+        assert!(check_separate_trees(&root, &root).is_err());
+        assert!(check_separate_trees(&root, &other_spelling).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn refuses_a_destination_inside_the_source() {
+        let root = make_test_directory("inside");
+        let dest_path = root.join("converted");
+        fs::create_dir(&dest_path).unwrap();
+        assert_eq!(
+            check_separate_trees(&root, &dest_path),
+            Err("the destination is inside the source".to_string())
+        );
+        assert_eq!(
+            check_separate_trees(&dest_path, &root),
+            Err("the source is inside the destination".to_string())
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn accepts_sibling_folders() {
+        let root = make_test_directory("siblings");
+        let src_path = root.join("music");
+        // a shared prefix of the name is not a parent
+        let dest_path = root.join("music-mp3");
+        fs::create_dir(&src_path).unwrap();
+        fs::create_dir(&dest_path).unwrap();
+        assert_eq!(check_separate_trees(&src_path, &dest_path), Ok(()));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_the_outputs_of_an_unreadable_source() {
+        use crate::messages::dart_signal::{Convert, FilterConfig, MirrorConfig};
+        use std::os::unix::fs::{symlink, PermissionsExt};
+
+        let root = make_test_directory(&format!("unreadable-{}", std::process::id()));
+        let src_path = root.join("music");
+        let dest_path = root.join("music-mp3");
+        let locked = src_path.join("locked");
+        fs::create_dir_all(src_path.join("album")).unwrap();
+        fs::create_dir_all(&locked).unwrap();
+        fs::create_dir_all(dest_path.join("album")).unwrap();
+        fs::create_dir_all(dest_path.join("locked")).unwrap();
+        fs::write(src_path.join("album/01.flac"), b"").unwrap();
+        fs::write(locked.join("02.flac"), b"").unwrap();
+        fs::write(dest_path.join("album/01.mp3"), b"").unwrap();
+        fs::write(dest_path.join("locked/02.mp3"), b"").unwrap();
+        fs::write(dest_path.join("stale.mp3"), b"").unwrap();
+        // permissions do not hold back root, the loop is never walked though
+        symlink(&src_path, src_path.join("album/loop")).unwrap();
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+        let instruction = crate::get_conversion_instructions(&Convert {
+            src_path: src_path.to_string_lossy().to_string(),
+            dest_path: dest_path.to_string_lossy().to_string(),
+            mirror_config: Some(MirrorConfig {
+                mode: MirrorMode::MirrorDelete.into(),
+                dry_run: false,
+            }),
+            filter_config: Some(FilterConfig {
+                follow_symlinks: true,
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let prune = |source_files: Vec<PathBuf>| {
+            runtime.block_on(prune_destination(
+                &instruction,
+                &source_files,
+                &HashSet::new(),
+                Arc::new(Mutex::new(ProgressBuffer::new())),
+            ))
+        };
+
+        // the files the walk of the job found with the locked folder left out
+        prune(vec![PathBuf::from("album/01.flac")]);
+        let kept_unread = dest_path.join("locked/02.mp3").exists();
+        let kept_stale = dest_path.join("stale.mp3").exists();
+
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::remove_file(src_path.join("album/loop")).unwrap();
+        prune(vec![
+            PathBuf::from("album/01.flac"),
+            PathBuf::from("locked/02.flac"),
+        ]);
+        let removed_stale = !dest_path.join("stale.mp3").exists();
+        let kept_outputs =
+            dest_path.join("album/01.mp3").exists() && dest_path.join("locked/02.mp3").exists();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert!(kept_unread);
+        assert!(kept_stale);
+        assert!(removed_stale);
+        assert!(kept_outputs);
+    }
+}
@@ -0,0 +1,296 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use symphonia::core::{
+    io::MediaSourceStream,
+    meta::{StandardTagKey, Tag},
+};
+
+use crate::conversion_handler::{get_target_extension, ConversionInstructions, FileAction};
+
+// Longest name of a single file or folder on FAT32 and exFAT, counted in
+// UTF-16 code units.
+const MAX_NAME_LENGTH: usize = 255;
+// Longest rendered path below the destination, leaving room for the
+// destination itself within the limits of most systems.
+const MAX_PATH_LENGTH: usize = 1024;
+// Names Windows, and with it FAT32 and exFAT, keeps for devices.
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Renders the naming template of every file of the job that is converted,
+/// so the tags of each file are only read once.
+pub fn get_templated_paths(
+    instruction: &ConversionInstructions,
+    files: &[PathBuf],
+) -> HashMap<PathBuf, PathBuf> {
+    let mut templated_paths = HashMap::new();
+    if instruction.naming_template.is_empty() {
+        return templated_paths;
+    }
+    for file_path in files {
+        if let FileAction::Copy = instruction.get_file_action(file_path) {
+            continue;
+        }
+        let tags = read_tags(&Path::new(&instruction.src_path).join(file_path));
+        if let Some(templated_path) = render_template(
+            &instruction.naming_template,
+            &tags,
+            get_target_extension(&instruction.target_format),
+        ) {
+            templated_paths.insert(file_path.clone(), templated_path);
+        }
+    }
+    templated_paths
+}
+
+/// Reads the tags of the file at `path` without decoding any audio.
+pub fn read_tags(path: &Path) -> Vec<Tag> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return Vec::new(),
+    };
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut probed = match symphonia::default::get_probe().format(
+        &Default::default(),
+        mss,
+        &Default::default(),
+        &Default::default(),
+    ) {
+        Ok(probed) => probed,
+        Err(_) => return Vec::new(),
+    };
+    // tags in front of the container (like ID3v2) are found by the probe
+    // instead of the reader
+    let latest_meta = match probed.format.metadata().skip_to_latest() {
+        Some(revision) => Some(revision.clone()),
+        None => probed
+            .metadata
+            .get()
+            .and_then(|mut metadata| metadata.skip_to_latest().cloned()),
+    };
+    match latest_meta {
+        Some(meta) => meta.tags().to_vec(),
+        None => Vec::new(),
+    }
+}
+
+/// Fills the fields of a template like
+/// `{albumartist}/{year} - {album}/{disc:02}{track:02} {title}` with the
+/// tags, giving a path below the destination. Returns `None` if a field has
+/// no tag or the path gets too long, so the source relative path is used
+/// instead.
+pub fn render_template(template: &str, tags: &[Tag], extension: &str) -> Option<PathBuf> {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let end = start + rest[start..].find('}')?;
+        let (name, width) = match rest[start + 1..end].split_once(':') {
+            Some((name, width)) => (name, Some(width)),
+            None => (&rest[start + 1..end], None),
+        };
+        let value = get_field(name.trim(), tags)?;
+        let value = match width.and_then(|width| width.parse::<usize>().ok()) {
+            // a width like `02` pads numbers with zeros
+            Some(width) if value.chars().all(|c| c.is_ascii_digit()) => {
+                format!("{:0>width$}", value, width = width)
+            }
+            _ => value,
+        };
+        // a value is never allowed to add folders of its own
+        rendered.push_str(&value.replace(['/', '\\'], "_"));
+        rest = &rest[end + 1..];
+    }
+    rendered.push_str(rest);
+
+    let mut names: Vec<String> = rendered
+        .split(['/', '\\'])
+        .map(sanitise_name)
+        .filter(|name| !name.is_empty())
+        .collect();
+    // the extension is added as is, a title like `Vol. 2` has a dot of its own
+    let file_name = names.pop()?;
+    let file_name = truncate_name(&file_name, MAX_NAME_LENGTH - extension.len() - 1);
+    names.push(format!("{}.{}", file_name, extension));
+    let path: PathBuf = names.iter().collect();
+    if path.as_os_str().len() > MAX_PATH_LENGTH {
+        return None;
+    }
+    Some(path)
+}
+
+fn get_field(name: &str, tags: &[Tag]) -> Option<String> {
+    let std_key = match name.to_lowercase().as_str() {
+        "albumartist" => Some(StandardTagKey::AlbumArtist),
+        "artist" => Some(StandardTagKey::Artist),
+        "album" => Some(StandardTagKey::Album),
+        "title" => Some(StandardTagKey::TrackTitle),
+        "track" => Some(StandardTagKey::TrackNumber),
+        "disc" => Some(StandardTagKey::DiscNumber),
+        "year" | "date" => Some(StandardTagKey::Date),
+        "genre" => Some(StandardTagKey::Genre),
+        "composer" => Some(StandardTagKey::Composer),
+        _ => None,
+    };
+    let tag = tags.iter().find(|tag| match std_key {
+        Some(std_key) => tag.std_key == Some(std_key),
+        // any other field is looked up by the key it is stored with
+        None => tag.key.eq_ignore_ascii_case(name),
+    })?;
+    let value = tag.value.to_string();
+    let value = match std_key {
+        // `3/12` is the third of twelve
+        Some(StandardTagKey::TrackNumber) | Some(StandardTagKey::DiscNumber) => value
+            .split('/')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_string(),
+        Some(StandardTagKey::Date) if name.eq_ignore_ascii_case("year") => {
+            value.chars().take(4).collect()
+        }
+        _ => value.trim().to_string(),
+    };
+    match value.is_empty() {
+        true => None,
+        false => Some(value),
+    }
+}
+
+/// Makes a single file or folder name safe for FAT32 and exFAT, the strictest
+/// file systems the output ends up on.
+pub fn sanitise_name(name: &str) -> String {
+    let mut sanitised: String = name
+        .chars()
+        .map(|c| match c {
+            '"' | '*' | ':' | '<' | '>' | '?' | '|' | '/' | '\\' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    // trailing dots and spaces are dropped by Windows
+    sanitised = sanitised
+        .trim_start_matches(' ')
+        .trim_end_matches(['.', ' '])
+        .to_string();
+    let stem = sanitised.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+    {
+        sanitised.insert(0, '_');
+    }
+    truncate_name(&sanitised, MAX_NAME_LENGTH)
+}
+
+/// Cuts a name down to `max_length` UTF-16 code units, without splitting a
+/// character.
+pub fn truncate_name(name: &str, max_length: usize) -> String {
+    let mut length = 0;
+    name.chars()
+        .take_while(|c| {
+            length += c.len_utf16();
+            length <= max_length
+        })
+        .collect::<String>()
+        .trim_end_matches(['.', ' '])
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use symphonia::core::meta::{StandardTagKey, Tag, Value};
+
+    use super::{render_template, sanitise_name, truncate_name};
+
+    fn get_tags() -> Vec<Tag> {
+        vec![
+            Tag::new(
+                Some(StandardTagKey::AlbumArtist),
+                "ALBUMARTIST",
+                Value::from("AC/DC"),
+            ),
+            Tag::new(
+                Some(StandardTagKey::Album),
+                "ALBUM",
+                Value::from("Back in Black"),
+            ),
+            Tag::new(
+                Some(StandardTagKey::Date),
+                "DATE",
+                Value::from("1980-07-25"),
+            ),
+            Tag::new(
+                Some(StandardTagKey::DiscNumber),
+                "DISCNUMBER",
+                Value::from("1"),
+            ),
+            Tag::new(
+                Some(StandardTagKey::TrackNumber),
+                "TRACKNUMBER",
+                Value::from("6/10"),
+            ),
+            Tag::new(
+                Some(StandardTagKey::TrackTitle),
+                "TITLE",
+                Value::from("Back in Black"),
+            ),
+            Tag::new(None, "LABEL", Value::from("Atlantic")),
+        ]
+    }
+
+    #[test]
+    fn renders_a_template() {
+        let path = render_template(
+            "{albumartist}/{year} - {album}/{disc:02}{track:02} {title}",
+            &get_tags(),
+            "mp3",
+        );
+        // the slash of a value does not make a folder
+        assert_eq!(
+            path,
+            Some(PathBuf::from(
+                "AC_DC/1980 - Back in Black/0106 Back in Black.mp3"
+            ))
+        );
+        assert_eq!(
+            render_template("{label}/{TITLE}", &get_tags(), "opus"),
+            Some(PathBuf::from("Atlantic/Back in Black.opus"))
+        );
+    }
+
+    #[test]
+    fn gives_up_on_a_missing_tag() {
+        assert_eq!(
+            render_template("{composer} - {title}", &get_tags(), "mp3"),
+            None
+        );
+        assert_eq!(render_template("{title", &get_tags(), "mp3"), None);
+    }
+
+    #[test]
+    fn sanitises_names_for_fat32() {
+        assert_eq!(sanitise_name("What? Why: \"This\"*"), "What_ Why_ _This__");
+        assert_eq!(sanitise_name("  Trailing dots... "), "Trailing dots");
+        assert_eq!(sanitise_name("con.mp3"), "_con.mp3");
+        assert_eq!(sanitise_name("Console"), "Console");
+        assert_eq!(sanitise_name("tab\there"), "tab_here");
+    }
+
+    #[test]
+    fn truncates_names_by_utf16_length() {
+        assert_eq!(truncate_name("abcdef", 4), "abcd");
+        // a character outside the BMP takes two code units and is kept whole
+        assert_eq!(truncate_name("ab\u{1F3B5}cd", 3), "ab");
+        assert_eq!(truncate_name("ab\u{1F3B5}cd", 4), "ab\u{1F3B5}");
+        assert_eq!(truncate_name("abc. def", 5), "abc");
+    }
+}
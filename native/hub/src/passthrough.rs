@@ -0,0 +1,166 @@
+use std::{fs, io, path::Path};
+
+use crate::{
+    atomic_file::{copy_atomically, get_temp_path, sync_parent_dir},
+    messages::dart_signal::PassthroughMode,
+};
+
+/// Puts a file that is not converted, like lyrics, cue sheets or covers, at
+/// `path` the way `mode` asks for. A link that can not be made, for example
+/// because the destination is on another file system, falls back to a copy.
+/// Returns the way the file was placed and why the link failed, if it did.
+pub fn place_passthrough_file(
+    mode: PassthroughMode,
+    src_path: &Path,
+    path: &Path,
+) -> Result<(PassthroughMode, Option<String>), String> {
+    let link_error = match mode {
+        PassthroughMode::PassthroughCopy => None,
+        mode => match link_atomically(mode, src_path, path) {
+            Ok(_) => return Ok((mode, None)),
+            Err(err) => Some(err),
+        },
+    };
+    copy_atomically(src_path, path)?;
+    Ok((PassthroughMode::PassthroughCopy, link_error))
+}
+
+pub fn get_passthrough_name(mode: PassthroughMode) -> &'static str {
+    match mode {
+        PassthroughMode::PassthroughCopy => "copy",
+        PassthroughMode::PassthroughHardlink => "hardlink",
+        PassthroughMode::PassthroughSymlink => "symlink",
+        PassthroughMode::PassthroughReflink => "reflink",
+    }
+}
+
+// Like the outputs, the link is made next to its final path and renamed into
+// place, which also replaces a file that is already there.
+fn link_atomically(mode: PassthroughMode, src_path: &Path, path: &Path) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        if let Err(err) = fs::create_dir_all(parent) {
+            return Err(err.to_string());
+        }
+    }
+    let temp_path = get_temp_path(path);
+    let _ = fs::remove_file(&temp_path);
+    let linked = match mode {
+        PassthroughMode::PassthroughHardlink => fs::hard_link(src_path, &temp_path),
+        PassthroughMode::PassthroughSymlink => match fs::canonicalize(src_path) {
+            Ok(target) => make_symlink(&target, &temp_path),
+            Err(err) => Err(err),
+        },
+        PassthroughMode::PassthroughReflink => reflink_copy::reflink(src_path, &temp_path),
+        PassthroughMode::PassthroughCopy => return Err("A copy is not a link".to_string()),
+    };
+    if let Err(err) = linked {
+        let _ = fs::remove_file(&temp_path);
+        return Err(err.to_string());
+    }
+    match fs::rename(&temp_path, path) {
+        Ok(_) => {
+            sync_parent_dir(path);
+            Ok(())
+        }
+        Err(err) => {
+            let _ = fs::remove_file(&temp_path);
+            Err(err.to_string())
+        }
+    }
+}
+
+#[cfg(unix)]
+fn make_symlink(target: &Path, path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+// Needs developer mode or administrator rights, without them the file is
+// copied instead.
+#[cfg(windows)]
+fn make_symlink(target: &Path, path: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(target, path)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn make_symlink(_target: &Path, _path: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Symbolic links are not supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(unix)]
+    use std::io::Read;
+    use std::{fs, path::PathBuf};
+
+    use super::place_passthrough_file;
+    use crate::{atomic_file::get_temp_path, messages::dart_signal::PassthroughMode};
+
+    fn get_test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "diraudio-passthrough-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // `/dev` is always a file system of its own, so a hardlink from it fails
+    // like one to a destination on another drive.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn copies_when_the_link_fails() {
+        let dir = get_test_dir("fallback");
+        let path = dir.join("lyrics.lrc");
+
+        let (mode, link_error) = place_passthrough_file(
+            PassthroughMode::PassthroughHardlink,
+            "/dev/null".as_ref(),
+            &path,
+        )
+        .unwrap();
+        assert_eq!(mode, PassthroughMode::PassthroughCopy);
+        assert!(link_error.is_some());
+        assert_eq!(fs::read(&path).unwrap(), b"");
+        assert!(!get_temp_path(&path).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replaces_an_existing_file_atomically() {
+        let dir = get_test_dir("replace");
+        let src_path = dir.join("cover.jpg");
+        fs::write(&src_path, b"new cover").unwrap();
+
+        for mode in [
+            PassthroughMode::PassthroughCopy,
+            PassthroughMode::PassthroughHardlink,
+        ] {
+            let path = dir.join("out").join("cover.jpg");
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, b"old cover").unwrap();
+            // a reader of the old file keeps reading it, as it is renamed
+            // over instead of written into
+            #[cfg(unix)]
+            let mut old_file = fs::File::open(&path).unwrap();
+
+            let (placed_mode, link_error) = place_passthrough_file(mode, &src_path, &path).unwrap();
+            assert_eq!(placed_mode, mode);
+            assert!(link_error.is_none());
+            assert_eq!(fs::read(&path).unwrap(), b"new cover");
+            assert!(!get_temp_path(&path).exists());
+            #[cfg(unix)]
+            {
+                let mut old_contents = Vec::new();
+                old_file.read_to_end(&mut old_contents).unwrap();
+                assert_eq!(old_contents, b"old cover");
+            }
+            fs::remove_dir_all(dir.join("out")).unwrap();
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,393 @@
+use std::{
+    fs::{self, File},
+    path::Path,
+};
+
+use symphonia::core::{codecs::CODEC_TYPE_NULL, io::MediaSourceStream};
+
+use crate::{
+    conflict::{resolve_existing_output, Collision},
+    conversion_handler::{
+        get_output_path, get_sync_settings, prepare_job, traverse_directory,
+        ConversionInstructions, FileAction,
+    },
+    encoder_decoder::encoders::mp3::get_correct_bitrate,
+    messages::{
+        dart_signal::{PassthroughMode, TargetFormat, VorbisBitrateMode},
+        rust_signal::{ConversionPlan, PlannedAction, PlannedFile},
+    },
+    sync_state::{SourceState, SyncState},
+    walk_filter::WalkFilter,
+};
+
+// Seconds of audio a single thread encodes per second, measured roughly on
+// a desktop machine. Only meant to give the order of magnitude of a job.
+const MP3_SPEED: f64 = 80.0;
+const OPUS_SPEED: f64 = 60.0;
+const VORBIS_SPEED: f64 = 40.0;
+const FLAC_SPEED: f64 = 150.0;
+const PCM_SPEED: f64 = 400.0;
+// Bytes per second a single thread copies.
+const COPY_SPEED: f64 = 100.0 * 1024.0 * 1024.0;
+// Compressed size of FLAC relative to the PCM it holds.
+const FLAC_RATIO: f64 = 0.6;
+
+/// Works out what a conversion with `instruction` would do to every file of
+/// the source directory, without writing anything.
+pub fn plan_conversion(instruction: &ConversionInstructions) -> ConversionPlan {
+    let files = match WalkFilter::new_from_config(&instruction.filter_config)
+        .and_then(|filter| traverse_directory(Path::new(&instruction.src_path), &filter))
+    {
+        Ok(walk) => walk.files,
+        Err(_) => {
+            return ConversionPlan {
+                planned: false,
+                ..Default::default()
+            }
+        }
+    };
+    let sync_state = SyncState::load(&instruction.dest_path);
+    let instruction = &prepare_job(instruction.clone(), &files);
+
+    let mut plan = ConversionPlan {
+        planned: true,
+        ..Default::default()
+    };
+    let mut thread_seconds = 0.0;
+    for file_path in files {
+        let src_file_path = Path::new(&instruction.src_path).join(&file_path);
+        let src_path = src_file_path.as_path();
+        let source_size = match fs::metadata(src_path) {
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        };
+        let file_action = instruction.get_file_action(&file_path);
+        let dest_path = get_output_path(instruction, &file_path, &file_action);
+        let mut planned_file = PlannedFile {
+            src_path: file_path.to_string_lossy().to_string(),
+            dest_path: dest_path.to_string_lossy().to_string(),
+            ..Default::default()
+        };
+
+        let left_out = match (&file_action, instruction.collisions.get(&file_path)) {
+            (_, Some(Collision::LeftOut(_))) | (_, Some(Collision::Failed(_))) => true,
+            (FileAction::Copy, _) => !instruction.copy_unrecognised_files,
+            (FileAction::Convert, _) => false,
+        };
+        let up_to_date = instruction.sync_config.incremental
+            && match SourceState::new_from_path(src_path) {
+                Ok(source_state) => sync_state.is_up_to_date(
+                    &file_path,
+                    &source_state,
+                    &get_sync_settings(instruction, &file_action),
+                    &dest_path,
+                ),
+                Err(_) => false,
+            };
+        if left_out || up_to_date {
+            planned_file.set_action(PlannedAction::ActionSkip);
+            plan.files.push(planned_file);
+            continue;
+        }
+        let made_by_us = instruction.sync_config.incremental && sync_state.has_record(&file_path);
+        let dest_path = match made_by_us {
+            true => dest_path,
+            false => {
+                match resolve_existing_output(instruction.conflict_policy, src_path, dest_path) {
+                    Ok(Some(dest_path)) => dest_path,
+                    // the file already in the destination is kept
+                    _ => {
+                        planned_file.set_action(PlannedAction::ActionSkip);
+                        plan.files.push(planned_file);
+                        continue;
+                    }
+                }
+            }
+        };
+        planned_file.dest_path = dest_path.to_string_lossy().to_string();
+
+        match file_action {
+            FileAction::Copy => {
+                planned_file.set_action(PlannedAction::ActionCopy);
+                // a link takes next to no time or space, unless it falls
+                // back to a copy
+                if let PassthroughMode::PassthroughCopy = instruction.passthrough_mode {
+                    planned_file.predicted_bytes = source_size as i64;
+                    thread_seconds += source_size as f64 / COPY_SPEED;
+                }
+            }
+            FileAction::Convert => {
+                planned_file.set_action(PlannedAction::ActionConvert);
+                match get_audio_properties(src_path) {
+                    Some(properties) => {
+                        planned_file.duration_seconds = properties.duration;
+                        planned_file.predicted_bytes =
+                            get_predicted_size(instruction, &properties) as i64;
+                        thread_seconds +=
+                            properties.duration / get_encoding_speed(&instruction.target_format);
+                    }
+                    // without a known length the source size is the best guess
+                    None => planned_file.predicted_bytes = source_size as i64,
+                }
+            }
+        }
+        if let Ok(metadata) = fs::metadata(&dest_path) {
+            planned_file.set_action(PlannedAction::ActionOverwrite);
+            plan.overwritten_bytes += metadata.len() as i64;
+        }
+        plan.total_output_bytes += planned_file.predicted_bytes;
+        plan.files.push(planned_file);
+    }
+    plan.estimated_seconds = thread_seconds / instruction.no_of_threads.max(1) as f64;
+    plan
+}
+
+struct AudioProperties {
+    duration: f64,
+    sample_rate: u32,
+    channels: u32,
+    bits_per_sample: u32,
+}
+
+// Only the container headers are read, nothing is decoded.
+fn get_audio_properties(path: &Path) -> Option<AudioProperties> {
+    let file = File::open(path).ok()?;
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+    let probed = symphonia::default::get_probe()
+        .format(
+            &Default::default(),
+            mss,
+            &Default::default(),
+            &Default::default(),
+        )
+        .ok()?;
+    let track = probed
+        .format
+        .tracks()
+        .iter()
+        .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)?;
+    let codec_params = &track.codec_params;
+    let sample_rate = codec_params.sample_rate?;
+    let no_of_frames = codec_params.n_frames?;
+    Some(AudioProperties {
+        duration: no_of_frames as f64 / sample_rate as f64,
+        sample_rate,
+        channels: codec_params
+            .channels
+            .map(|channels| channels.count() as u32)
+            .unwrap_or(2),
+        bits_per_sample: codec_params.bits_per_sample.unwrap_or(16),
+    })
+}
+
+fn get_predicted_size(instruction: &ConversionInstructions, properties: &AudioProperties) -> f64 {
+    let pcm_size = |bits_per_sample: u32| {
+        properties.duration
+            * properties.sample_rate as f64
+            * properties.channels as f64
+            * bits_per_sample as f64
+            / 8.0
+    };
+    let lossy_size = |kbps: f64| properties.duration * kbps * 1000.0 / 8.0;
+    match instruction.target_format {
+        TargetFormat::Mp3 => {
+            lossy_size(get_correct_bitrate(instruction.mp3_config.bitrate()) as u32 as f64)
+        }
+        TargetFormat::Opus => match instruction.opus_config.bitrate {
+            // the automatic bitrate of libopus for music
+            bitrate if bitrate <= 0 => lossy_size(96.0),
+            bitrate => lossy_size(bitrate.clamp(6, 510) as f64),
+        },
+        TargetFormat::Vorbis => match instruction.vorbis_config.mode() {
+            VorbisBitrateMode::QualityVbr => {
+                lossy_size(get_vorbis_quality_kbps(instruction.vorbis_config.quality))
+            }
+            VorbisBitrateMode::NominalBitrate => {
+                lossy_size(instruction.vorbis_config.bitrate as f64)
+            }
+        },
+        TargetFormat::Flac => pcm_size(properties.bits_per_sample.clamp(8, 24)) * FLAC_RATIO,
+        // written as 16, 24 or 32 bit integer PCM
+        TargetFormat::Wav | TargetFormat::Aiff => pcm_size(match properties.bits_per_sample {
+            0..=16 => 16,
+            17..=24 => 24,
+            _ => 32,
+        }),
+    }
+}
+
+// Nominal bitrates of the libvorbis quality levels for stereo 44.1kHz.
+fn get_vorbis_quality_kbps(quality: f32) -> f64 {
+    const NOMINAL_KBPS: [f64; 12] = [
+        45.0, 64.0, 80.0, 96.0, 112.0, 128.0, 160.0, 192.0, 224.0, 256.0, 320.0, 500.0,
+    ];
+    let position = (quality.clamp(-1.0, 10.0) + 1.0).round() as usize;
+    NOMINAL_KBPS[position]
+}
+
+fn get_encoding_speed(target_format: &TargetFormat) -> f64 {
+    match target_format {
+        TargetFormat::Mp3 => MP3_SPEED,
+        TargetFormat::Opus => OPUS_SPEED,
+        TargetFormat::Vorbis => VORBIS_SPEED,
+        TargetFormat::Flac => FLAC_SPEED,
+        TargetFormat::Wav | TargetFormat::Aiff => PCM_SPEED,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, fs, path::PathBuf};
+
+    use symphonia::core::sample::SampleFormat;
+
+    use super::*;
+    use crate::{
+        encoder_decoder::{
+            encoders::flac::flac::FlacEncoder,
+            raw_audio_data::raw_audio_data::{AudioChannels, RawAudioData},
+        },
+        get_conversion_instructions,
+        messages::dart_signal::{
+            ConflictPolicy, Convert, Mp3Bitrate, Mp3Config, OpusConfig, VorbisConfig,
+        },
+    };
+
+    // Ten seconds of 16 bit stereo at 44.1kHz.
+    const PROPERTIES: AudioProperties = AudioProperties {
+        duration: 10.0,
+        sample_rate: 44_100,
+        channels: 2,
+        bits_per_sample: 16,
+    };
+
+    fn get_test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("diraudio-planner-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn predicts_the_size_of_every_target() {
+        let predicted = |message: Convert, properties: &AudioProperties| {
+            get_predicted_size(&get_conversion_instructions(&message), properties).round() as i64
+        };
+        let target = |target_format: TargetFormat| Convert {
+            target_format: target_format.into(),
+            ..Default::default()
+        };
+
+        let mp3 = Convert {
+            mp3_config: Some(Mp3Config {
+                bitrate: Mp3Bitrate::Kbps320.into(),
+                ..Default::default()
+            }),
+            ..target(TargetFormat::Mp3)
+        };
+        assert_eq!(predicted(mp3, &PROPERTIES), 400_000);
+        // an unset bitrate is left to libopus, too high a one is clamped
+        for (bitrate, bytes) in [(0, 120_000), (64, 80_000), (1000, 637_500)] {
+            let opus = Convert {
+                opus_config: Some(OpusConfig {
+                    bitrate,
+                    ..Default::default()
+                }),
+                ..target(TargetFormat::Opus)
+            };
+            assert_eq!(predicted(opus, &PROPERTIES), bytes);
+        }
+        let vorbis_quality = Convert {
+            vorbis_config: Some(VorbisConfig {
+                mode: VorbisBitrateMode::QualityVbr.into(),
+                quality: 5.0,
+                ..Default::default()
+            }),
+            ..target(TargetFormat::Vorbis)
+        };
+        assert_eq!(predicted(vorbis_quality, &PROPERTIES), 200_000);
+        let vorbis_nominal = Convert {
+            vorbis_config: Some(VorbisConfig {
+                mode: VorbisBitrateMode::NominalBitrate.into(),
+                bitrate: 128,
+                ..Default::default()
+            }),
+            ..target(TargetFormat::Vorbis)
+        };
+        assert_eq!(predicted(vorbis_nominal, &PROPERTIES), 160_000);
+        assert_eq!(
+            predicted(target(TargetFormat::Flac), &PROPERTIES),
+            1_058_400
+        );
+        assert_eq!(predicted(target(TargetFormat::Wav), &PROPERTIES), 1_764_000);
+        // 20 bit sources are written as 24 bit PCM, FLAC keeps the 20 bits
+        let deep = AudioProperties {
+            bits_per_sample: 20,
+            ..PROPERTIES
+        };
+        assert_eq!(predicted(target(TargetFormat::Aiff), &deep), 2_646_000);
+        assert_eq!(predicted(target(TargetFormat::Flac), &deep), 1_323_000);
+    }
+
+    #[test]
+    fn clamps_the_vorbis_quality_to_the_libvorbis_range() {
+        assert_eq!(get_vorbis_quality_kbps(-1.0), 45.0);
+        assert_eq!(get_vorbis_quality_kbps(-5.0), 45.0);
+        assert_eq!(get_vorbis_quality_kbps(10.0), 500.0);
+        assert_eq!(get_vorbis_quality_kbps(15.0), 500.0);
+        assert_eq!(get_vorbis_quality_kbps(4.4), 128.0);
+    }
+
+    #[test]
+    fn plans_skips_and_overwrites() {
+        let root = get_test_dir("actions");
+        let (src_path, dest_path) = (root.join("music"), root.join("converted"));
+        fs::create_dir_all(&src_path).unwrap();
+        fs::create_dir_all(&dest_path).unwrap();
+        let audio = RawAudioData::new(
+            HashMap::from([(AudioChannels::FrontLeft, vec![0; 44_100])]),
+            44_100,
+            16,
+            SampleFormat::S16,
+            Vec::new(),
+            None,
+            Vec::new(),
+        )
+        .encode_to_flac(&Default::default())
+        .unwrap();
+        fs::write(src_path.join("new.flac"), &audio).unwrap();
+        fs::write(src_path.join("existing.flac"), &audio).unwrap();
+        fs::write(src_path.join("notes.txt"), b"notes").unwrap();
+        fs::write(dest_path.join("existing.mp3"), b"older output").unwrap();
+
+        let plan = |conflict_policy: ConflictPolicy| {
+            let plan = plan_conversion(&get_conversion_instructions(&Convert {
+                src_path: src_path.to_string_lossy().to_string(),
+                dest_path: dest_path.to_string_lossy().to_string(),
+                conflict_policy: conflict_policy.into(),
+                ..Default::default()
+            }));
+            assert!(plan.planned);
+            let actions: HashMap<String, PlannedAction> = plan
+                .files
+                .iter()
+                .map(|file| (file.src_path.clone(), file.action()))
+                .collect();
+            (plan, actions)
+        };
+
+        let (overwriting, actions) = plan(ConflictPolicy::ConflictOverwrite);
+        assert_eq!(actions["new.flac"], PlannedAction::ActionConvert);
+        assert_eq!(actions["existing.flac"], PlannedAction::ActionOverwrite);
+        // unrecognised files are not copied unless asked for
+        assert_eq!(actions["notes.txt"], PlannedAction::ActionSkip);
+        assert_eq!(overwriting.overwritten_bytes, b"older output".len() as i64);
+
+        let (skipping, actions) = plan(ConflictPolicy::ConflictSkip);
+        assert_eq!(actions["new.flac"], PlannedAction::ActionConvert);
+        assert_eq!(actions["existing.flac"], PlannedAction::ActionSkip);
+        assert_eq!(skipping.overwritten_bytes, 0);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
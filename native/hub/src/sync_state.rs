@@ -0,0 +1,375 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use md5::{Digest, Md5};
+
+use crate::atomic_file::write_atomically;
+
+/// Name of the sidecar file, kept in the root of the destination, that
+/// remembers what every output was made from.
+pub const SYNC_STATE_FILE_NAME: &str = ".diraudio-sync";
+
+// Start of the line that forgets the record of a file.
+const REMOVED_PREFIX: &str = "removed\t";
+
+/// What a source file looked like when its output was written.
+#[derive(Clone, PartialEq)]
+pub struct SourceState {
+    size: u64,
+    modified_secs: u64,
+    modified_nanos: u32,
+    hash: Option<String>,
+}
+
+impl SourceState {
+    /// Reads the size and modification time of the file at `path`.
+    pub fn new_from_path(path: &Path) -> Result<SourceState, String> {
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(err) => return Err(err.to_string()),
+        };
+        let modified = match metadata.modified() {
+            Ok(modified) => modified.duration_since(UNIX_EPOCH).unwrap_or_default(),
+            Err(err) => return Err(err.to_string()),
+        };
+        Ok(SourceState {
+            size: metadata.len(),
+            modified_secs: modified.as_secs(),
+            modified_nanos: modified.subsec_nanos(),
+            hash: None,
+        })
+    }
+
+    /// Adds a hash of the content of the file at `path`. Reading the whole
+    /// file is slow, so this is only done when the cheap checks disagree.
+    pub fn with_content_hash(mut self, path: &Path) -> Result<SourceState, String> {
+        self.hash = Some(get_content_hash(path)?);
+        Ok(self)
+    }
+
+    fn is_same_version(&self, other: &SourceState) -> bool {
+        if self.size != other.size {
+            return false;
+        }
+        if self.modified_secs == other.modified_secs && self.modified_nanos == other.modified_nanos
+        {
+            return true;
+        }
+        // a touched file with the same content is still the same version
+        match (&self.hash, &other.hash) {
+            (Some(hash), Some(other_hash)) => hash == other_hash,
+            _ => false,
+        }
+    }
+}
+
+struct SyncRecord {
+    source: SourceState,
+    settings: String,
+    output_size: u64,
+}
+
+/// Records are appended to the sidecar file as soon as a file is done, so a
+/// killed job does not lose them, and the file is rewritten without the
+/// stale lines when the job ends.
+pub struct SyncState {
+    path: PathBuf,
+    records: HashMap<PathBuf, SyncRecord>,
+    file: Option<File>,
+    // length of the complete lines when the last one was cut short, new
+    // lines must not be glued to it
+    complete_len: Option<u64>,
+}
+
+impl SyncState {
+    /// Loads the sidecar file of the destination. A missing or unreadable
+    /// file simply means nothing is known to be up to date.
+    pub fn load(dest_path: &str) -> SyncState {
+        let path = Path::new(dest_path).join(SYNC_STATE_FILE_NAME);
+        let mut records = HashMap::new();
+        let mut complete_len = None;
+        if let Ok(contents) = fs::read_to_string(&path) {
+            if !contents.is_empty() && !contents.ends_with('\n') {
+                complete_len = Some(contents.rfind('\n').map_or(0, |end| end + 1) as u64);
+            }
+            // later lines replace earlier ones, a line cut short by a crash
+            // is simply ignored
+            for line in contents
+                .split_inclusive('\n')
+                .filter_map(|line| line.strip_suffix('\n'))
+            {
+                if let Some(file_path) = line.strip_prefix(REMOVED_PREFIX) {
+                    records.remove(Path::new(file_path));
+                } else if let Some((file_path, record)) = parse_record(line) {
+                    records.insert(file_path, record);
+                }
+            }
+        }
+        SyncState {
+            path,
+            records,
+            file: None,
+            complete_len,
+        }
+    }
+
+    /// Checks whether the output of `file_path` was made from the same
+    /// version of the source with the same settings and is still in place.
+    pub fn is_up_to_date(
+        &self,
+        file_path: &Path,
+        source: &SourceState,
+        settings: &str,
+        output_path: &Path,
+    ) -> bool {
+        let record = match self.records.get(file_path) {
+            Some(record) => record,
+            None => return false,
+        };
+        if record.settings != settings || !record.source.is_same_version(source) {
+            return false;
+        }
+        match fs::metadata(output_path) {
+            Ok(metadata) => metadata.is_file() && metadata.len() == record.output_size,
+            Err(_) => false,
+        }
+    }
+
+    /// Remembers that the output of `file_path` has just been written.
+    pub fn update(
+        &mut self,
+        file_path: &Path,
+        source: SourceState,
+        settings: String,
+        output_path: &Path,
+    ) {
+        let output_size = match fs::metadata(output_path) {
+            Ok(metadata) => metadata.len(),
+            Err(_) => return,
+        };
+        let record = SyncRecord {
+            source,
+            settings,
+            output_size,
+        };
+        if let Some(line) = get_record_line(file_path, &record) {
+            self.append(&line);
+        }
+        self.records.insert(file_path.to_path_buf(), record);
+    }
+
+    /// Checks whether an output of `file_path` was written by an earlier run.
+    pub fn has_record(&self, file_path: &Path) -> bool {
+        self.records.contains_key(file_path)
+    }
+
+    pub fn remove(&mut self, file_path: &Path) {
+        if self.records.remove(file_path).is_none() {
+            return;
+        }
+        if let Some(file_path) = file_path.to_str().filter(|path| !path.contains('\n')) {
+            self.append(&format!("{}{}\n", REMOVED_PREFIX, file_path));
+        }
+    }
+
+    /// Rewrites the sidecar file with one line per file.
+    pub fn save(&mut self) -> Result<(), String> {
+        // the old file is replaced, appending to it would go nowhere
+        self.file = None;
+        self.complete_len = None;
+        let contents: String = self
+            .records
+            .iter()
+            .filter_map(|(file_path, record)| get_record_line(file_path, record))
+            .collect();
+        write_atomically(&self.path, contents.as_bytes())
+    }
+
+    // A lost line only means the file is converted again next time.
+    fn append(&mut self, line: &str) {
+        if let Some(complete_len) = self.complete_len {
+            let truncated = OpenOptions::new()
+                .write(true)
+                .open(&self.path)
+                .and_then(|file| file.set_len(complete_len));
+            if truncated.is_err() {
+                return;
+            }
+            self.complete_len = None;
+        }
+        if self.file.is_none() {
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .ok();
+        }
+        if let Some(file) = self.file.as_mut() {
+            let _ = file.write_all(line.as_bytes());
+        }
+    }
+}
+
+// Every record is one line of text, file names can not break it up, a name
+// that is not valid UTF-8 is simply not remembered.
+fn get_record_line(file_path: &Path, record: &SyncRecord) -> Option<String> {
+    let file_path = match file_path.to_str() {
+        Some(file_path) if !file_path.contains('\n') => file_path,
+        _ => return None,
+    };
+    if record.settings.contains(['\t', '\n']) {
+        return None;
+    }
+    Some(format!(
+        "{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+        record.source.size,
+        record.source.modified_secs,
+        record.source.modified_nanos,
+        record.source.hash.as_deref().unwrap_or("-"),
+        record.output_size,
+        record.settings,
+        file_path
+    ))
+}
+
+// The file path comes last, so tabs in file names are kept intact.
+fn parse_record(line: &str) -> Option<(PathBuf, SyncRecord)> {
+    let mut fields = line.splitn(7, '\t');
+    let size = fields.next()?.parse().ok()?;
+    let modified_secs = fields.next()?.parse().ok()?;
+    let modified_nanos = fields.next()?.parse().ok()?;
+    let hash = match fields.next()? {
+        "-" => None,
+        hash => Some(hash.to_string()),
+    };
+    let output_size = fields.next()?.parse().ok()?;
+    let settings = fields.next()?.to_string();
+    let file_path = PathBuf::from(fields.next()?);
+    Some((
+        file_path,
+        SyncRecord {
+            source: SourceState {
+                size,
+                modified_secs,
+                modified_nanos,
+                hash,
+            },
+            settings,
+            output_size,
+        },
+    ))
+}
+
+/// Hex encoded MD5 of the content of the file at `path`.
+pub fn get_content_hash(path: &Path) -> Result<String, String> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) => return Err(err.to_string()),
+    };
+    let mut hasher = Md5::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => hasher.update(&buffer[..read]),
+            Err(err) => return Err(err.to_string()),
+        }
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    use super::{parse_record, SourceState, SyncState, SYNC_STATE_FILE_NAME};
+
+    fn get_dest_path(name: &str) -> PathBuf {
+        let dest_path = std::env::temp_dir().join(format!("diraudio-sync-state-{}", name));
+        let _ = fs::remove_dir_all(&dest_path);
+        fs::create_dir_all(&dest_path).unwrap();
+        dest_path
+    }
+
+    // Records an output of `file_path` that is as big as its name.
+    fn add_output(sync_state: &mut SyncState, dest_path: &Path, file_path: &str) -> PathBuf {
+        let output_path = dest_path.join(file_path.replace(['/', '\t'], "_"));
+        fs::write(&output_path, file_path).unwrap();
+        let source = SourceState::new_from_path(&output_path).unwrap();
+        sync_state.update(
+            Path::new(file_path),
+            source,
+            "Flac".to_string(),
+            &output_path,
+        );
+        output_path
+    }
+
+    #[test]
+    fn reads_a_record_line() {
+        let (file_path, record) =
+            parse_record("120\t1700000000\t5\t-\t80\tMp3 quality=0\ta/b\tc.flac").unwrap();
+        assert_eq!(file_path, PathBuf::from("a/b\tc.flac"));
+        assert_eq!(record.source.size, 120);
+        assert_eq!(record.source.modified_secs, 1700000000);
+        assert_eq!(record.source.modified_nanos, 5);
+        assert_eq!(record.source.hash, None);
+        assert_eq!(record.output_size, 80);
+        assert_eq!(record.settings, "Mp3 quality=0");
+
+        let (_, record) = parse_record("1\t2\t3\tabc\t4\tCopy\tx.mp3").unwrap();
+        assert_eq!(record.source.hash.as_deref(), Some("abc"));
+        assert!(parse_record("1\t2\t3\t-\t4\tCopy").is_none());
+        assert!(parse_record("1\tx\t3\t-\t4\tCopy\tx.mp3").is_none());
+    }
+
+    #[test]
+    fn keeps_records_of_a_job_that_never_saved() {
+        let dest_path = get_dest_path("unsaved");
+        let dest = dest_path.to_str().unwrap();
+        let mut sync_state = SyncState::load(dest);
+        let kept = add_output(&mut sync_state, &dest_path, "a/kept\tone.flac");
+        add_output(&mut sync_state, &dest_path, "removed.flac");
+        sync_state.remove(Path::new("removed.flac"));
+        drop(sync_state);
+
+        let sync_state = SyncState::load(dest);
+        let source = SourceState::new_from_path(&kept).unwrap();
+        assert!(sync_state.is_up_to_date(Path::new("a/kept\tone.flac"), &source, "Flac", &kept));
+        assert!(!sync_state.has_record(Path::new("removed.flac")));
+        fs::remove_dir_all(&dest_path).unwrap();
+    }
+
+    #[test]
+    fn skips_a_line_cut_short() {
+        let dest_path = get_dest_path("cut-short");
+        let dest = dest_path.to_str().unwrap();
+        let sync_state_path = dest_path.join(SYNC_STATE_FILE_NAME);
+        fs::write(&sync_state_path, "1\t2\t3\t-\t4\tCopy\tcut.m").unwrap();
+
+        let mut sync_state = SyncState::load(dest);
+        assert!(!sync_state.has_record(Path::new("cut.m")));
+        add_output(&mut sync_state, &dest_path, "next.flac");
+        drop(sync_state);
+
+        let mut sync_state = SyncState::load(dest);
+        assert!(sync_state.has_record(Path::new("next.flac")));
+        sync_state.save().unwrap();
+        let contents = fs::read_to_string(&sync_state_path).unwrap();
+        assert_eq!(contents.lines().count(), 1);
+        assert!(contents.ends_with("\tFlac\tnext.flac\n"));
+        fs::remove_dir_all(&dest_path).unwrap();
+    }
+}
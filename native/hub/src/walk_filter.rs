@@ -0,0 +1,171 @@
+use std::{fs::Metadata, path::Path};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+use crate::messages::dart_signal::FilterConfig;
+
+/// Decides which files of the source take part in a job, for converting
+/// and copying alike.
+pub struct WalkFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    include_hidden: bool,
+    max_depth: usize,
+    follow_symlinks: bool,
+    list_symlinks: bool,
+}
+
+impl WalkFilter {
+    pub fn new_from_config(config: &FilterConfig) -> Result<WalkFilter, String> {
+        // without include patterns every file is included
+        let include = match config.include_globs.is_empty() {
+            true => None,
+            false => Some(get_glob_set(&config.include_globs)?),
+        };
+        Ok(WalkFilter {
+            include,
+            exclude: get_glob_set(&config.exclude_globs)?,
+            include_hidden: config.include_hidden,
+            max_depth: config.max_depth as usize,
+            follow_symlinks: config.follow_symlinks,
+            list_symlinks: false,
+        })
+    }
+
+    /// Filter that lets every file through, for walking the destination.
+    /// Links are listed like files but never followed, so nothing outside
+    /// the destination is ever touched.
+    pub fn everything() -> WalkFilter {
+        WalkFilter {
+            include: None,
+            exclude: GlobSet::empty(),
+            include_hidden: true,
+            max_depth: 0,
+            follow_symlinks: false,
+            list_symlinks: true,
+        }
+    }
+
+    pub fn follows_symlinks(&self) -> bool {
+        self.follow_symlinks
+    }
+
+    /// Checks whether a link that is not followed is listed as a file.
+    pub fn lists_symlinks(&self) -> bool {
+        self.list_symlinks
+    }
+
+    /// Checks whether the folder at `depth` below the root is walked into.
+    pub fn enters_directory(&self, name: &Path, metadata: &Metadata, depth: usize) -> bool {
+        (self.max_depth == 0 || depth < self.max_depth)
+            && (self.include_hidden || !is_hidden(name, metadata))
+    }
+
+    /// Checks whether the file at `relative_path` below the root is part of
+    /// the job.
+    pub fn includes_file(&self, relative_path: &Path, metadata: &Metadata) -> bool {
+        if !self.include_hidden && is_hidden(relative_path, metadata) {
+            return false;
+        }
+        if self.exclude.is_match(relative_path) {
+            return false;
+        }
+        match &self.include {
+            Some(include) => include.is_match(relative_path),
+            None => true,
+        }
+    }
+}
+
+fn get_glob_set(patterns: &[String]) -> Result<GlobSet, String> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        match Glob::new(pattern) {
+            Ok(glob) => builder.add(glob),
+            Err(err) => return Err(format!("Invalid pattern {}: {}", pattern, err)),
+        };
+    }
+    match builder.build() {
+        Ok(glob_set) => Ok(glob_set),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+// Dot files are hidden everywhere, Windows also has an attribute for it.
+fn is_hidden(path: &Path, metadata: &Metadata) -> bool {
+    let dot_file = path
+        .file_name()
+        .map(|name| name.to_string_lossy().starts_with('.'))
+        .unwrap_or(false);
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::MetadataExt;
+        const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+        dot_file || metadata.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0
+    }
+    #[cfg(not(windows))]
+    {
+        let _ = metadata;
+        dot_file
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn get_filter(include_globs: &[&str], exclude_globs: &[&str]) -> WalkFilter {
+        let config = FilterConfig {
+            include_globs: include_globs.iter().map(|glob| glob.to_string()).collect(),
+            exclude_globs: exclude_globs.iter().map(|glob| glob.to_string()).collect(),
+            max_depth: 2,
+            ..Default::default()
+        };
+        WalkFilter::new_from_config(&config).unwrap()
+    }
+
+    fn includes(filter: &WalkFilter, relative_path: &str) -> bool {
+        let metadata = fs::metadata(env!("CARGO_MANIFEST_DIR")).unwrap();
+        filter.includes_file(Path::new(relative_path), &metadata)
+    }
+
+    #[test]
+    fn matches_the_include_and_exclude_globs() {
+        let filter = get_filter(&["*.flac", "Albums/**/*.wav"], &["**/Live/**"]);
+        assert!(includes(&filter, "song.flac"));
+        assert!(includes(&filter, "Artist/Album/song.flac"));
+        assert!(includes(&filter, "Albums/Album/song.wav"));
+        assert!(!includes(&filter, "Singles/song.wav"));
+        assert!(!includes(&filter, "Artist/Live/song.flac"));
+        assert!(!includes(&filter, "Artist/Album/cover.jpg"));
+    }
+
+    #[test]
+    fn includes_every_file_without_include_globs() {
+        let filter = get_filter(&[], &["*.log"]);
+        assert!(includes(&filter, "Artist/Album/cover.jpg"));
+        assert!(!includes(&filter, "Artist/Album/rip.log"));
+    }
+
+    #[test]
+    fn leaves_out_hidden_files_and_deep_folders() {
+        let filter = get_filter(&[], &[]);
+        assert!(!includes(&filter, "Artist/.song.flac"));
+        assert!(includes(&WalkFilter::everything(), "Artist/.song.flac"));
+
+        let metadata = fs::metadata(env!("CARGO_MANIFEST_DIR")).unwrap();
+        assert!(filter.enters_directory(Path::new("Album"), &metadata, 1));
+        assert!(!filter.enters_directory(Path::new("Disc 1"), &metadata, 2));
+        assert!(!filter.enters_directory(Path::new(".git"), &metadata, 1));
+    }
+
+    #[test]
+    fn rejects_an_invalid_glob() {
+        let config = FilterConfig {
+            include_globs: vec!["Album[".to_string()],
+            ..Default::default()
+        };
+        assert!(WalkFilter::new_from_config(&config).is_err());
+    }
+}